@@ -0,0 +1 @@
+-- deleted duplicates are not restored
@@ -0,0 +1,7 @@
+-- duplicates are deleted first, so the unique index of the next migrations can be built
+delete from data_entries_history_keys a
+    using data_entries_history_keys b
+where a.uid > b.uid
+  and a.address = b.address
+  and a.key = b.key
+  and a.data_entry_uid = b.data_entry_uid;
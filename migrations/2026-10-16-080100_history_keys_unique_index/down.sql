@@ -0,0 +1 @@
+drop index concurrently if exists data_entries_history_keys_address_key_data_entry_uid_key;
@@ -0,0 +1,5 @@
+-- built concurrently, so writes to the table are not blocked meanwhile. It cannot run
+-- in a transaction, so this migration has the one statement. An interrupted build leaves
+-- an invalid index, which has to be dropped before the migration is run again.
+create unique index concurrently if not exists data_entries_history_keys_address_key_data_entry_uid_key
+    on data_entries_history_keys (address, key, data_entry_uid);
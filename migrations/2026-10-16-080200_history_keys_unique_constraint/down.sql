@@ -0,0 +1,2 @@
+alter table data_entries_history_keys
+    drop constraint if exists data_entries_history_keys_address_key_data_entry_uid_key;
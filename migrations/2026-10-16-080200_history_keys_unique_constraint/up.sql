@@ -0,0 +1,4 @@
+-- the constraint takes over the index built by the previous migration
+alter table data_entries_history_keys
+    add constraint data_entries_history_keys_address_key_data_entry_uid_key
+        unique using index data_entries_history_keys_address_key_data_entry_uid_key;
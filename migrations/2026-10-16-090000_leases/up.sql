@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS leases (
+    uid BIGINT GENERATED BY DEFAULT AS IDENTITY
+        CONSTRAINT leases_pkey
+            PRIMARY KEY,
+    block_uid BIGINT NOT NULL
+        CONSTRAINT leases_block_uid_fkey
+            REFERENCES blocks_microblocks (uid)
+                ON DELETE CASCADE,
+    transaction_id VARCHAR NOT NULL,
+    lease_id VARCHAR NOT NULL,
+    sender VARCHAR NOT NULL,
+    recipient VARCHAR NOT NULL,
+    amount BIGINT NOT NULL,
+    status VARCHAR NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS leases_block_uid_idx ON leases (block_uid);
+CREATE INDEX IF NOT EXISTS leases_lease_id_idx ON leases (lease_id);
+CREATE INDEX IF NOT EXISTS leases_sender_idx ON leases (sender);
+CREATE INDEX IF NOT EXISTS leases_recipient_idx ON leases (recipient);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS data_entries_last_writes (
+    address VARCHAR NOT NULL,
+    key VARCHAR NOT NULL,
+    height INTEGER NOT NULL,
+    block_timestamp TIMESTAMP,
+    CONSTRAINT data_entries_last_writes_pkey
+        PRIMARY KEY (address, key)
+);
+
+CREATE INDEX IF NOT EXISTS data_entries_last_writes_height_idx ON data_entries_last_writes (height DESC);
+CREATE INDEX IF NOT EXISTS data_entries_last_writes_block_timestamp_idx ON data_entries_last_writes (block_timestamp DESC);
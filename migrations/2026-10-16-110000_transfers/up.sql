@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS transfers (
+    uid BIGINT GENERATED BY DEFAULT AS IDENTITY
+        CONSTRAINT transfers_pkey
+            PRIMARY KEY,
+    block_uid BIGINT NOT NULL
+        CONSTRAINT transfers_block_uid_fkey
+            REFERENCES blocks_microblocks (uid)
+                ON DELETE CASCADE,
+    height INTEGER NOT NULL,
+    transaction_id VARCHAR NOT NULL,
+    sender VARCHAR NOT NULL,
+    recipient VARCHAR NOT NULL,
+    asset_id VARCHAR,
+    amount BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS transfers_block_uid_idx ON transfers (block_uid);
+CREATE INDEX IF NOT EXISTS transfers_transaction_id_idx ON transfers (transaction_id);
+CREATE INDEX IF NOT EXISTS transfers_sender_idx ON transfers (sender);
+CREATE INDEX IF NOT EXISTS transfers_recipient_idx ON transfers (recipient);
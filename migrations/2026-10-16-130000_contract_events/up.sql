@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS contract_events (
+    uid BIGINT GENERATED BY DEFAULT AS IDENTITY
+        CONSTRAINT contract_events_pkey
+            PRIMARY KEY,
+    block_uid BIGINT NOT NULL
+        CONSTRAINT contract_events_block_uid_fkey
+            REFERENCES blocks_microblocks (uid)
+                ON DELETE CASCADE,
+    height INTEGER NOT NULL,
+    transaction_id VARCHAR NOT NULL,
+    address VARCHAR NOT NULL,
+    event_type VARCHAR NOT NULL,
+    has_script BOOLEAN,
+    asset_id VARCHAR,
+    asset_name VARCHAR,
+    asset_description VARCHAR
+);
+
+CREATE INDEX IF NOT EXISTS contract_events_block_uid_idx ON contract_events (block_uid);
+CREATE INDEX IF NOT EXISTS contract_events_address_idx ON contract_events (address);
+CREATE INDEX IF NOT EXISTS contract_events_asset_id_idx ON contract_events (asset_id);
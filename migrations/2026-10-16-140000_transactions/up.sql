@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS transactions (
+    tx_id VARCHAR NOT NULL
+        CONSTRAINT transactions_pkey
+            PRIMARY KEY,
+    block_uid BIGINT NOT NULL
+        CONSTRAINT transactions_block_uid_fkey
+            REFERENCES blocks_microblocks (uid)
+                ON DELETE CASCADE,
+    height INTEGER NOT NULL,
+    tx_type SMALLINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS transactions_block_uid_idx ON transactions (block_uid);
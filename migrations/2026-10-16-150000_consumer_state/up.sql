@@ -0,0 +1,10 @@
+-- a single row, the resume cursor of the consumer
+CREATE TABLE IF NOT EXISTS consumer_state (
+    id SMALLINT DEFAULT 1 NOT NULL
+        CONSTRAINT consumer_state_pkey
+            PRIMARY KEY
+        CONSTRAINT consumer_state_single_row
+            CHECK (id = 1),
+    last_height INTEGER NOT NULL,
+    last_uid BIGINT
+);
@@ -0,0 +1,2 @@
+ALTER TABLE data_entries DROP CONSTRAINT IF EXISTS data_entries_value_type_not_null;
+ALTER TABLE data_entries DROP COLUMN IF EXISTS value_type;
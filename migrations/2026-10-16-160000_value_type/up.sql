@@ -0,0 +1,9 @@
+-- a nullable column without a computed default only changes the catalog, so the table
+-- is not rewritten under an ACCESS EXCLUSIVE lock, existing rows read the NULL default
+ALTER TABLE data_entries ADD COLUMN IF NOT EXISTS value_type VARCHAR DEFAULT NULL;
+
+-- new rows are checked right away, the existing ones are backfilled in batches
+-- by the consumer, which validates the constraint afterwards
+ALTER TABLE data_entries DROP CONSTRAINT IF EXISTS data_entries_value_type_not_null;
+ALTER TABLE data_entries ADD CONSTRAINT data_entries_value_type_not_null
+    CHECK (value_type IS NOT NULL) NOT VALID;
@@ -0,0 +1 @@
+ALTER TABLE consumer_state DROP COLUMN IF EXISTS applied_reset_to_height;
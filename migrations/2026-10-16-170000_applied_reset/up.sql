@@ -0,0 +1,2 @@
+-- the last RESET_TO_HEIGHT done, so it is not repeated on every start
+ALTER TABLE consumer_state ADD COLUMN IF NOT EXISTS applied_reset_to_height INTEGER;
@@ -0,0 +1,831 @@
+use crate::logging::{error, warn};
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::StreamExt;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::data_entries::{
+    DataEntriesRepo, DataEntriesRepoOperations, DataEntry, DataEntryValue, DataEntryVersion,
+    FragmentFilter, FRAGMENT_COUNT,
+};
+
+/// State of the service the probes and the health endpoints report.
+pub struct Probes {
+    /// False once the consumer is down in degraded mode.
+    pub consuming: Arc<AtomicBool>,
+    /// Last value of the readiness channel, `None` before its first check.
+    pub ready: Arc<Mutex<Option<bool>>>,
+    pub syncing: Arc<AtomicBool>,
+    /// Epoch milliseconds of the last iteration of the consumer loop.
+    pub heartbeat: Arc<AtomicI64>,
+    pub liveness_timeout: Duration,
+    /// Epoch milliseconds of the last message of the stream.
+    pub last_message_at: Arc<AtomicI64>,
+    pub max_stream_idle: Duration,
+}
+
+#[derive(Serialize)]
+struct Status {
+    consuming: &'static str,
+    serving: &'static str,
+}
+
+#[derive(Serialize)]
+struct Health {
+    grpc: &'static str,
+    db: &'static str,
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct HealthDetails {
+    last_block_height: Option<i32>,
+    last_block_timestamp: Option<i64>,
+    seconds_since_last_block: Option<i64>,
+    readiness: &'static str,
+    mode: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+enum Value {
+    Binary(String),
+    Boolean(bool),
+    Integer(i64),
+    String(String),
+}
+
+#[derive(Serialize)]
+struct DataEntryResponse {
+    address: String,
+    key: String,
+    transaction_id: String,
+    value: Option<Value>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BinaryEncoding {
+    #[default]
+    Base58,
+    Base64,
+}
+
+impl BinaryEncoding {
+    /// Base64 values are prefixed as in the node API, base58 ones are not.
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            BinaryEncoding::Base58 => bs58::encode(bytes).into_string(),
+            BinaryEncoding::Base64 => format!(
+                "base64:{}",
+                base64::engine::general_purpose::STANDARD.encode(bytes)
+            ),
+        }
+    }
+}
+
+/// Every endpoint returning data entries takes `binary_encoding`, so they are encoded alike.
+#[derive(Deserialize)]
+struct EncodingQuery {
+    #[serde(default)]
+    binary_encoding: BinaryEncoding,
+}
+
+impl Value {
+    fn new(value: DataEntryValue, encoding: BinaryEncoding) -> Option<Self> {
+        match value {
+            DataEntryValue::Binary(v) => Some(Value::Binary(encoding.encode(&v))),
+            DataEntryValue::Bool(v) => Some(Value::Boolean(v)),
+            DataEntryValue::Integer(v) => Some(Value::Integer(v)),
+            DataEntryValue::String(v) => Some(Value::String(v)),
+            DataEntryValue::Deleted => None,
+        }
+    }
+}
+
+impl DataEntryResponse {
+    fn new(de: DataEntry, encoding: BinaryEncoding) -> Self {
+        DataEntryResponse {
+            address: de.address,
+            key: de.key,
+            transaction_id: de.transaction_id,
+            value: Value::new(de.value, encoding),
+        }
+    }
+}
+
+impl DataEntryVersionResponse {
+    fn new(version: DataEntryVersion, encoding: BinaryEncoding) -> Self {
+        DataEntryVersionResponse {
+            uid: version.uid,
+            height: version.height,
+            block_timestamp: version
+                .block_timestamp
+                .map(|ts| ts.and_utc().timestamp_millis()),
+            transaction_id: version.data_entry.transaction_id,
+            value: Value::new(version.data_entry.value, encoding),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DataEntriesPage {
+    items: Vec<DataEntryResponse>,
+    next: Option<String>,
+}
+
+/// `block_timestamp` is in epoch milliseconds, rounded down to seconds as stored.
+#[derive(Serialize)]
+struct DataEntryVersionResponse {
+    uid: i64,
+    height: Option<i32>,
+    block_timestamp: Option<i64>,
+    transaction_id: String,
+    value: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct DataEntryHistoryPage {
+    items: Vec<DataEntryVersionResponse>,
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TransactionResponse {
+    id: String,
+    height: i32,
+    #[serde(rename = "type")]
+    tx_type: i16,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<u32>,
+    after: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<u32>,
+    after_uid: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HeightQuery {
+    height: Option<u32>,
+}
+
+/// Query of the fragment search, e.g. `fragment1_string=X&fragment2_integer=Y&limit=10`.
+struct SearchQuery {
+    filters: Vec<FragmentFilter>,
+    limit: Option<u32>,
+    after: Option<i64>,
+}
+
+impl SearchQuery {
+    /// Unknown parameters are rejected, so a misspelled filter does not widen the search.
+    fn parse(params: HashMap<String, String>) -> Result<Self, String> {
+        let mut query = SearchQuery {
+            filters: vec![],
+            limit: None,
+            after: None,
+        };
+
+        for (name, value) in params {
+            let invalid = || format!("invalid value of {}: {:?}", name, value);
+            match name.as_str() {
+                "limit" => query.limit = Some(value.parse().map_err(|_| invalid())?),
+                "after" => query.after = Some(value.parse().map_err(|_| invalid())?),
+                // it is parsed by the encoding query
+                "binary_encoding" => (),
+                _ => {
+                    let (index, kind) = name
+                        .strip_prefix("fragment")
+                        .and_then(|rest| rest.split_once('_'))
+                        .and_then(|(index, kind)| Some((index.parse::<usize>().ok()?, kind)))
+                        .ok_or_else(|| format!("unknown parameter {}", name))?;
+                    if index >= FRAGMENT_COUNT {
+                        return Err(format!(
+                            "fragment index of {} has to be below {}",
+                            name, FRAGMENT_COUNT
+                        ));
+                    }
+                    let filter = match kind {
+                        "string" => FragmentFilter::String(index, value),
+                        "integer" => {
+                            FragmentFilter::Integer(index, value.parse().map_err(|_| invalid())?)
+                        }
+                        _ => return Err(format!("unknown parameter {}", name)),
+                    };
+                    query.filters.push(filter);
+                }
+            }
+        }
+
+        // a search without filters would scan all of the live entries
+        if query.filters.is_empty() {
+            return Err("at least one fragment filter is required".to_string());
+        }
+
+        Ok(query)
+    }
+}
+
+const DEFAULT_LIST_LIMIT: u32 = 100;
+const MAX_LIST_LIMIT: u32 = 1000;
+
+fn ok_or_down(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "down"
+    }
+}
+
+fn up_or_down(up: bool) -> &'static str {
+    if up {
+        "up"
+    } else {
+        "down"
+    }
+}
+
+/// Serve the API independently of the consumer, so reads survive a node outage.
+///
+/// `/live` fails when the consumer loop has not iterated for `liveness_timeout`,
+/// unless the consumer is down in degraded mode, and `/ready` follows the readiness channel.
+/// `/health` reports the stream and the database separately, the stream is down
+/// when no message has arrived for `max_stream_idle`.
+/// `/health/details` also tells whether the consumer is syncing or live.
+/// `/data_entries/stream` pushes the entries of every written batch as server-sent events.
+/// `/data_entries/{address}/{key}/history` lists every stored version of the key.
+/// `/transactions/{id}` returns the height and the type of a stored transaction.
+pub async fn start<U>(
+    port: u16,
+    probes: Probes,
+    changes: broadcast::Sender<Vec<DataEntry>>,
+    repo: Arc<U>,
+) where
+    U: DataEntriesRepo + Send + Sync + 'static,
+{
+    warp::serve(routes(probes, changes, repo))
+        .run(([0, 0, 0, 0], port))
+        .await
+}
+
+pub(crate) fn routes<U>(
+    probes: Probes,
+    changes: broadcast::Sender<Vec<DataEntry>>,
+    repo: Arc<U>,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone
+where
+    U: DataEntriesRepo + Send + Sync + 'static,
+{
+    let Probes {
+        consuming,
+        ready,
+        syncing,
+        heartbeat,
+        liveness_timeout,
+        last_message_at,
+        max_stream_idle,
+    } = probes;
+
+    let live = warp::path!("live").and(warp::get()).map({
+        let consuming = consuming.clone();
+        move || {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64);
+            let since_heartbeat = (now - heartbeat.load(Ordering::Relaxed)).max(0) as u128;
+            if !consuming.load(Ordering::Relaxed) || since_heartbeat <= liveness_timeout.as_millis()
+            {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+    });
+
+    let ready_probe = warp::path!("ready").and(warp::get()).map({
+        let ready = ready.clone();
+        move || match *ready.lock().unwrap() {
+            Some(true) => StatusCode::OK,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    });
+
+    let status = warp::path!("status").and(warp::get()).map(move || {
+        warp::reply::json(&Status {
+            consuming: up_or_down(consuming.load(Ordering::Relaxed)),
+            serving: up_or_down(true),
+        })
+    });
+
+    let health = warp::path!("health")
+        .and(warp::get())
+        .and(warp::any().map(move || last_message_at.clone()))
+        .and(warp::any().map(move || max_stream_idle))
+        .and(with_repo(repo.clone()))
+        .and_then(get_health);
+
+    let health_details = warp::path!("health" / "details")
+        .and(warp::get())
+        .and(warp::any().map(move || ready.clone()))
+        .and(warp::any().map(move || syncing.clone()))
+        .and(with_repo(repo.clone()))
+        .and_then(get_health_details);
+
+    let data_entry = warp::path!("data_entries" / String / String)
+        .and(warp::get())
+        .and(warp::query::<HeightQuery>())
+        .and(warp::query::<EncodingQuery>())
+        .and(with_repo(repo.clone()))
+        .and_then(get_data_entry);
+
+    let history = warp::path!("data_entries" / String / String / "history")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and(warp::query::<EncodingQuery>())
+        .and(with_repo(repo.clone()))
+        .and_then(get_data_entry_history);
+
+    let stream = warp::path!("data_entries" / "stream")
+        .and(warp::get())
+        .and(warp::query::<StreamQuery>())
+        .and(warp::query::<EncodingQuery>())
+        .and(warp::any().map(move || changes.subscribe()))
+        .map(stream_data_entries);
+
+    let search = warp::path!("data_entries" / "search")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::query::<EncodingQuery>())
+        .and(with_repo(repo.clone()))
+        .and_then(search_data_entries);
+
+    let data_entries = warp::path!("data_entries" / String)
+        .and(warp::get())
+        .and(warp::query::<ListQuery>())
+        .and(warp::query::<EncodingQuery>())
+        .and(with_repo(repo.clone()))
+        .and_then(list_data_entries);
+
+    let transaction = warp::path!("transactions" / String)
+        .and(warp::get())
+        .and(with_repo(repo))
+        .and_then(get_transaction);
+
+    // the stream and the search go first, otherwise their paths are taken for an address
+    status
+        .or(live)
+        .or(ready_probe)
+        .or(health)
+        .or(health_details)
+        .or(stream)
+        .or(search)
+        .or(data_entry)
+        .or(history)
+        .or(data_entries)
+        .or(transaction)
+}
+
+fn with_repo<U>(repo: Arc<U>) -> impl Filter<Extract = (Arc<U>,), Error = Infallible> + Clone
+where
+    U: DataEntriesRepo + Send + Sync + 'static,
+{
+    warp::any().map(move || repo.clone())
+}
+
+async fn get_data_entry<U: DataEntriesRepo>(
+    address: String,
+    key: String,
+    query: HeightQuery,
+    encoding: EncodingQuery,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    // keys may contain any characters, so they come percent-encoded
+    let key = percent_decode_str(&key).decode_utf8_lossy().to_string();
+
+    match repo.execute(|mut ops| match query.height {
+        Some(height) => ops.get_data_entry_at_height(&address, &key, height as i32),
+        None => ops.get_data_entry(&address, &key),
+    }) {
+        Ok(Some(de)) => Ok(warp::reply::json(&DataEntryResponse::new(
+            de,
+            encoding.binary_encoding,
+        ))
+        .into_response()),
+        Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => {
+            error!("Cannot get data entry {} of {}: {:?}", key, address, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn get_transaction<U: DataEntriesRepo>(
+    tx_id: String,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    match repo.execute(|mut ops| ops.get_transaction(&tx_id)) {
+        Ok(Some(tx)) => Ok(warp::reply::json(&TransactionResponse {
+            id: tx.tx_id,
+            height: tx.height,
+            tx_type: tx.tx_type,
+        })
+        .into_response()),
+        Ok(None) => Ok(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => {
+            error!("Cannot get transaction {}: {:?}", tx_id, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn get_data_entry_history<U: DataEntriesRepo>(
+    address: String,
+    key: String,
+    query: HistoryQuery,
+    encoding: EncodingQuery,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    let key = percent_decode_str(&key).decode_utf8_lossy().to_string();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    match repo.execute(|mut ops| {
+        ops.get_data_entry_history(&address, &key, query.after_uid, limit as i64)
+    }) {
+        Ok((versions, next)) => Ok(warp::reply::json(&DataEntryHistoryPage {
+            items: versions
+                .into_iter()
+                .map(|version| DataEntryVersionResponse::new(version, encoding.binary_encoding))
+                .collect(),
+            next: next.map(|uid| uid.to_string()),
+        })
+        .into_response()),
+        Err(err) => {
+            error!(
+                "Cannot get history of data entry {} of {}: {:?}",
+                key, address, err
+            );
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn list_data_entries<U: DataEntriesRepo>(
+    address: String,
+    query: ListQuery,
+    encoding: EncodingQuery,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    match repo
+        .execute(|mut ops| ops.list_data_entries(&address, query.after.as_deref(), limit as i64))
+    {
+        Ok((entries, next)) => Ok(warp::reply::json(&DataEntriesPage {
+            items: entries
+                .into_iter()
+                .map(|de| DataEntryResponse::new(de, encoding.binary_encoding))
+                .collect(),
+            next,
+        })
+        .into_response()),
+        Err(err) => {
+            error!("Cannot list data entries of {}: {:?}", address, err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+async fn search_data_entries<U: DataEntriesRepo>(
+    params: HashMap<String, String>,
+    encoding: EncodingQuery,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    let query = match SearchQuery::parse(params) {
+        Ok(query) => query,
+        Err(message) => {
+            return Ok(warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+
+    match repo.execute(|mut ops| ops.search_by_fragments(&query.filters, limit as i64, query.after))
+    {
+        Ok((entries, next)) => Ok(warp::reply::json(&DataEntriesPage {
+            items: entries
+                .into_iter()
+                .map(|de| DataEntryResponse::new(de, encoding.binary_encoding))
+                .collect(),
+            next: next.map(|uid| uid.to_string()),
+        })
+        .into_response()),
+        Err(err) => {
+            error!(
+                "Cannot search data entries by {:?}: {:?}",
+                query.filters, err
+            );
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// A client which lags behind the broadcast channel is disconnected, so it can reconnect
+/// and catch up from the database instead of silently missing entries.
+fn stream_data_entries(
+    query: StreamQuery,
+    encoding: EncodingQuery,
+    changes: broadcast::Receiver<Vec<DataEntry>>,
+) -> Response {
+    let events = BroadcastStream::new(changes)
+        .map_while(move |entries| match entries {
+            Ok(entries) => Some(
+                entries
+                    .into_iter()
+                    .filter(|de| query.address.as_ref().is_none_or(|a| *a == de.address))
+                    .map(|de| DataEntryResponse::new(de, encoding.binary_encoding))
+                    .collect::<Vec<_>>(),
+            ),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!(
+                    "Data entries stream client lagged behind by {} batches, disconnecting",
+                    skipped
+                );
+                None
+            }
+        })
+        .filter(|items| !items.is_empty())
+        .map(|items| {
+            warp::sse::Event::default()
+                .event("data_entries")
+                .json_data(items)
+        });
+
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+async fn get_health<U: DataEntriesRepo>(
+    last_message_at: Arc<AtomicI64>,
+    max_stream_idle: Duration,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64);
+    let stream_idle = (now - last_message_at.load(Ordering::Relaxed)).max(0) as u128;
+    let grpc = stream_idle <= max_stream_idle.as_millis();
+
+    let db = match repo.execute(|mut ops| ops.ping()) {
+        Ok(()) => true,
+        Err(err) => {
+            error!("Database health check failed: {:?}", err);
+            false
+        }
+    };
+
+    let status = match (grpc, db) {
+        (true, true) => "ready",
+        (false, false) => "dead",
+        _ => "degraded",
+    };
+
+    Ok(warp::reply::json(&Health {
+        grpc: ok_or_down(grpc),
+        db: ok_or_down(db),
+        status,
+    })
+    .into_response())
+}
+
+async fn get_health_details<U: DataEntriesRepo>(
+    ready: Arc<Mutex<Option<bool>>>,
+    syncing: Arc<AtomicBool>,
+    repo: Arc<U>,
+) -> Result<Response, Infallible> {
+    let readiness = match *ready.lock().unwrap() {
+        Some(true) => "ready",
+        Some(false) => "dead",
+        None => "unknown",
+    };
+    let mode = if syncing.load(Ordering::Relaxed) {
+        "syncing"
+    } else {
+        "live"
+    };
+
+    match repo.execute(|mut ops| Ok((ops.get_last_height()?, ops.get_last_block_timestamp()?))) {
+        Ok((last_block_height, last_block_timestamp)) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64);
+
+            Ok(warp::reply::json(&HealthDetails {
+                last_block_height,
+                last_block_timestamp,
+                seconds_since_last_block: last_block_timestamp.map(|ts| (now - ts) / 1000),
+                readiness,
+                mode,
+            })
+            .into_response())
+        }
+        Err(err) => {
+            error!("Cannot get health details: {:?}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_entries::testing::MockDataEntriesRepo;
+
+    fn now_millis() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64)
+    }
+
+    fn probes() -> Probes {
+        Probes {
+            consuming: Arc::new(AtomicBool::new(true)),
+            ready: Arc::new(Mutex::new(None)),
+            syncing: Arc::new(AtomicBool::new(false)),
+            heartbeat: Arc::new(AtomicI64::new(now_millis())),
+            liveness_timeout: Duration::from_secs(60),
+            last_message_at: Arc::new(AtomicI64::new(now_millis())),
+            max_stream_idle: Duration::from_secs(60),
+        }
+    }
+
+    async fn get(
+        probes: Probes,
+        repo: MockDataEntriesRepo,
+        path: &str,
+    ) -> warp::http::Response<warp::hyper::body::Bytes> {
+        let routes = routes(probes, broadcast::channel(1).0, Arc::new(repo));
+        warp::test::request().path(path).reply(&routes).await
+    }
+
+    #[tokio::test]
+    async fn live_fails_once_the_heartbeat_is_older_than_the_timeout() {
+        let repo = MockDataEntriesRepo::new();
+
+        let live = get(probes(), repo.clone(), "/live").await;
+        assert_eq!(live.status(), StatusCode::OK);
+
+        let probes = Probes {
+            heartbeat: Arc::new(AtomicI64::new(now_millis() - 61_000)),
+            ..probes()
+        };
+        let live = get(probes, repo, "/live").await;
+        assert_eq!(live.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn live_ignores_the_heartbeat_while_the_consumer_is_down() {
+        let probes = Probes {
+            consuming: Arc::new(AtomicBool::new(false)),
+            heartbeat: Arc::new(AtomicI64::new(0)),
+            ..probes()
+        };
+
+        let live = get(probes, MockDataEntriesRepo::new(), "/live").await;
+
+        assert_eq!(live.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn binary_value_round_trips_in_both_encodings() {
+        let bytes = b"hello".to_vec();
+
+        let base58 = BinaryEncoding::Base58.encode(&bytes);
+        let base64 = BinaryEncoding::Base64.encode(&bytes);
+
+        assert_eq!(base58, "Cn8eVZg");
+        assert_eq!(base64, "base64:aGVsbG8=");
+        assert_eq!(bs58::decode(&base58).into_vec().unwrap(), bytes);
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(base64.strip_prefix("base64:").unwrap())
+            .unwrap();
+        assert_eq!(decoded, bytes);
+    }
+
+    #[test]
+    fn binary_value_is_encoded_as_requested() {
+        let value = |encoding| Value::new(DataEntryValue::Binary(b"hello".to_vec()), encoding);
+
+        assert!(matches!(
+            value(BinaryEncoding::default()),
+            Some(Value::Binary(v)) if v == "Cn8eVZg"
+        ));
+        assert!(matches!(
+            value(BinaryEncoding::Base64),
+            Some(Value::Binary(v)) if v == "base64:aGVsbG8="
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_subscriber_receives_the_entries_of_its_address() {
+        let entry = |address: &str| DataEntry {
+            address: address.to_string(),
+            key: "k".to_string(),
+            transaction_id: "tx".to_string(),
+            value: DataEntryValue::Integer(1),
+        };
+        let (tx, rx) = broadcast::channel(16);
+        let response = stream_data_entries(
+            StreamQuery {
+                address: Some("3PAddress".to_string()),
+            },
+            EncodingQuery {
+                binary_encoding: BinaryEncoding::Base58,
+            },
+            rx,
+        );
+
+        tx.send(vec![entry("3PAddress"), entry("3POther")]).unwrap();
+        drop(tx);
+        let body = tokio::time::timeout(
+            Duration::from_secs(10),
+            warp::hyper::body::to_bytes(response.into_body()),
+        )
+        .await
+        .expect("the stream ends with the channel")
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&body),
+            concat!(
+                "event:data_entries\n",
+                r#"data:[{"address":"3PAddress","key":"k","transaction_id":"tx","#,
+                r#""value":{"type":"integer","value":1}}]"#,
+                "\n\n"
+            )
+        );
+    }
+
+    async fn health(last_message_at: i64, db_up: bool) -> String {
+        let repo = MockDataEntriesRepo::new();
+        repo.set_unavailable(!db_up);
+        let probes = Probes {
+            last_message_at: Arc::new(AtomicI64::new(last_message_at)),
+            ..probes()
+        };
+
+        let health = get(probes, repo, "/health").await;
+
+        assert_eq!(health.status(), StatusCode::OK);
+        String::from_utf8_lossy(health.body()).into_owned()
+    }
+
+    #[tokio::test]
+    async fn health_reports_the_stream_and_the_database_separately() {
+        let stale = now_millis() - 61_000;
+
+        assert_eq!(
+            health(now_millis(), true).await,
+            r#"{"grpc":"ok","db":"ok","status":"ready"}"#
+        );
+        assert_eq!(
+            health(stale, true).await,
+            r#"{"grpc":"down","db":"ok","status":"degraded"}"#
+        );
+        assert_eq!(
+            health(now_millis(), false).await,
+            r#"{"grpc":"ok","db":"down","status":"degraded"}"#
+        );
+        assert_eq!(
+            health(stale, false).await,
+            r#"{"grpc":"down","db":"down","status":"dead"}"#
+        );
+    }
+}
@@ -0,0 +1,1907 @@
+//! Non-blocking repo on `tokio-postgres`, its connections are awaited
+//! instead of tying up a worker thread with `block_in_place`.
+//! It runs the same SQL as the diesel repo, which stays the default.
+
+use super::{
+    AsyncDataEntriesRepo, AsyncDataEntriesRepoOperations, BlockMicroblock, BoxFuture,
+    ConsumerState, DataEntry, DataEntryUpdate, DataEntryVersion, DeletedDataEntry, FragmentFilter,
+    InsertableContractEvent, InsertableDataEntry, InsertableLease, InsertableTransaction,
+    InsertableTransfer, PrevHandledHeight, StoredTransaction, FRAGMENT_COUNT, LIVE_SUPERSEDED_BY,
+    VALUE_TYPE_BINARY, VALUE_TYPE_BOOL, VALUE_TYPE_DELETED, VALUE_TYPE_INTEGER, VALUE_TYPE_STRING,
+};
+use crate::data_entries::repo::table;
+use crate::error::AppError;
+use anyhow::{anyhow, Error, Result};
+use async_trait::async_trait;
+use deadpool_postgres::{Object, Pool};
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::pin::pin;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{FromSql, ToSql, Type};
+use tokio_postgres::Row;
+
+/// Key of the advisory lock held by a transaction which reserved uids, the same as of the diesel repo.
+const UID_RESERVATION_LOCK: i64 = 0x5ca1ab1e;
+
+/// Rows copied by one statement, chunks only bound the memory used by it.
+const COPY_CHUNK_SIZE: usize = 10000;
+
+const DATA_ENTRY_COLUMNS: &str =
+    "address, key, transaction_id, value_binary, value_bool, value_integer, value_string";
+
+type Param<'a> = Box<dyn ToSql + Sync + Send + 'a>;
+
+fn db_error(err: tokio_postgres::Error) -> Error {
+    Error::new(AppError::from(err))
+}
+
+fn get<'a, T: FromSql<'a>>(row: &'a Row, idx: usize) -> Result<T> {
+    row.try_get(idx).map_err(db_error)
+}
+
+/// The columns of `DATA_ENTRY_COLUMNS` starting at `offset`.
+/// Binary values are stored in a text column, as the diesel repo writes them.
+fn data_entry(row: &Row, offset: usize) -> Result<DataEntry> {
+    let value_binary: Option<String> = get(row, offset + 3)?;
+    Ok(DataEntry {
+        address: get(row, offset)?,
+        key: get(row, offset + 1)?,
+        transaction_id: get(row, offset + 2)?,
+        value: (
+            value_binary.map(String::into_bytes),
+            get(row, offset + 4)?,
+            get(row, offset + 5)?,
+            get(row, offset + 6)?,
+        )
+            .into(),
+    })
+}
+
+fn deleted_data_entry(row: &Row) -> Result<DeletedDataEntry> {
+    Ok(DeletedDataEntry {
+        address: get(row, 0)?,
+        key: get(row, 1)?,
+        uid: get(row, 2)?,
+    })
+}
+
+/// Postgres rejects a text which is not UTF-8, the diesel repo fails on such a value too.
+fn binary_text(de: &InsertableDataEntry) -> Result<Option<&str>> {
+    de.value_binary
+        .as_deref()
+        .map(|value| {
+            std::str::from_utf8(value)
+                .map_err(|_| anyhow!("binary value of {} {} is not UTF-8", de.address, de.key))
+        })
+        .transpose()
+}
+
+/// Columns of an inserted data entry with their types, the fragments are in the order of `fragments`.
+fn inserted_data_entry_columns() -> Vec<(String, Type)> {
+    let mut columns = [
+        ("block_uid", Type::INT8),
+        ("transaction_id", Type::VARCHAR),
+        ("uid", Type::INT8),
+        ("superseded_by", Type::INT8),
+        ("address", Type::VARCHAR),
+        ("key", Type::VARCHAR),
+        ("value_binary", Type::TEXT),
+        ("value_bool", Type::BOOL),
+        ("value_integer", Type::INT8),
+        ("value_string", Type::VARCHAR),
+        ("value_type", Type::VARCHAR),
+    ]
+    .map(|(name, column_type)| (name.to_string(), column_type))
+    .to_vec();
+    for prefix in ["fragment", "value_fragment"] {
+        for index in 0..FRAGMENT_COUNT {
+            columns.push((format!("{}_{}_integer", prefix, index), Type::INT8));
+            columns.push((format!("{}_{}_string", prefix, index), Type::VARCHAR));
+        }
+    }
+    columns
+}
+
+/// Key fragments followed by value fragments.
+fn fragments(de: &InsertableDataEntry) -> [(Option<i64>, Option<&str>); 2 * FRAGMENT_COUNT] {
+    [
+        (de.fragment_0_integer, de.fragment_0_string.as_deref()),
+        (de.fragment_1_integer, de.fragment_1_string.as_deref()),
+        (de.fragment_2_integer, de.fragment_2_string.as_deref()),
+        (de.fragment_3_integer, de.fragment_3_string.as_deref()),
+        (de.fragment_4_integer, de.fragment_4_string.as_deref()),
+        (de.fragment_5_integer, de.fragment_5_string.as_deref()),
+        (de.fragment_6_integer, de.fragment_6_string.as_deref()),
+        (de.fragment_7_integer, de.fragment_7_string.as_deref()),
+        (de.fragment_8_integer, de.fragment_8_string.as_deref()),
+        (de.fragment_9_integer, de.fragment_9_string.as_deref()),
+        (de.fragment_10_integer, de.fragment_10_string.as_deref()),
+        (
+            de.value_fragment_0_integer,
+            de.value_fragment_0_string.as_deref(),
+        ),
+        (
+            de.value_fragment_1_integer,
+            de.value_fragment_1_string.as_deref(),
+        ),
+        (
+            de.value_fragment_2_integer,
+            de.value_fragment_2_string.as_deref(),
+        ),
+        (
+            de.value_fragment_3_integer,
+            de.value_fragment_3_string.as_deref(),
+        ),
+        (
+            de.value_fragment_4_integer,
+            de.value_fragment_4_string.as_deref(),
+        ),
+        (
+            de.value_fragment_5_integer,
+            de.value_fragment_5_string.as_deref(),
+        ),
+        (
+            de.value_fragment_6_integer,
+            de.value_fragment_6_string.as_deref(),
+        ),
+        (
+            de.value_fragment_7_integer,
+            de.value_fragment_7_string.as_deref(),
+        ),
+        (
+            de.value_fragment_8_integer,
+            de.value_fragment_8_string.as_deref(),
+        ),
+        (
+            de.value_fragment_9_integer,
+            de.value_fragment_9_string.as_deref(),
+        ),
+        (
+            de.value_fragment_10_integer,
+            de.value_fragment_10_string.as_deref(),
+        ),
+    ]
+}
+
+/// An insert of the rows of arrays, one array per column, so a statement has a bind parameter
+/// per column instead of one per value.
+fn unnest_insert_sql(table_name: &str, columns: &[(String, Type)]) -> String {
+    format!(
+        "insert into {} ({}) select * from unnest({})",
+        table(table_name),
+        columns.iter().map(|(name, _)| name).join(", "),
+        columns
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, column_type))| format!("${}::{}[]", idx + 1, column_type.name()))
+            .join(", ")
+    )
+}
+
+fn columns(columns: &[(&str, Type)]) -> Vec<(String, Type)> {
+    columns
+        .iter()
+        .map(|(name, column_type)| (name.to_string(), column_type.clone()))
+        .collect()
+}
+
+fn params<'a>(params: &'a [Param<'_>]) -> Vec<&'a (dyn ToSql + Sync)> {
+    params
+        .iter()
+        .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+        .collect()
+}
+
+/// Pages are fetched with one more row, so it is known whether there is a next one.
+fn page<T, C>(mut rows: Vec<T>, limit: i64, cursor: impl Fn(&T) -> C) -> (Vec<T>, Option<C>) {
+    if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        let cursor = rows.last().map(cursor);
+        (rows, cursor)
+    } else {
+        (rows, None)
+    }
+}
+
+pub struct AsyncPgDataEntriesRepo {
+    pool: Pool,
+}
+
+impl AsyncPgDataEntriesRepo {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get_conn(&self) -> Result<AsyncPgConnection> {
+        let client = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| Error::new(AppError::from(err)))?;
+        Ok(AsyncPgConnection {
+            client: Some(client),
+            in_transaction: false,
+        })
+    }
+}
+
+#[async_trait]
+impl AsyncDataEntriesRepo for AsyncPgDataEntriesRepo {
+    type Operations = AsyncPgConnection;
+
+    async fn execute<F, R>(&self, f: F) -> Result<R>
+    where
+        F: for<'a> FnOnce(&'a mut AsyncPgConnection) -> BoxFuture<'a, Result<R>> + Send,
+        R: Send,
+    {
+        let mut conn = self.get_conn().await?;
+        f(&mut conn).await
+    }
+
+    async fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: for<'a> FnOnce(&'a mut AsyncPgConnection) -> BoxFuture<'a, Result<R>> + Send,
+        R: Send,
+    {
+        let mut conn = self.get_conn().await?;
+        conn.begin().await?;
+        match f(&mut conn).await {
+            Ok(result) => {
+                conn.commit().await?;
+                Ok(result)
+            }
+            Err(err) => {
+                // the error of the operations is the one worth reporting,
+                // a connection which cannot roll back is not returned to the pool
+                let _ = conn.rollback().await;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// A pooled connection. When it is dropped within a transaction, e.g. when the future
+/// of the transaction is cancelled, it is closed instead of being returned to the pool,
+/// so the transaction is rolled back by the server.
+pub struct AsyncPgConnection {
+    client: Option<Object>,
+    in_transaction: bool,
+}
+
+impl Drop for AsyncPgConnection {
+    fn drop(&mut self) {
+        if self.in_transaction {
+            if let Some(client) = self.client.take() {
+                drop(Object::take(client));
+            }
+        }
+    }
+}
+
+impl AsyncPgConnection {
+    fn client(&self) -> &Object {
+        self.client
+            .as_ref()
+            .expect("the client is only taken when the connection is dropped")
+    }
+
+    async fn begin(&mut self) -> Result<()> {
+        self.client()
+            .batch_execute("begin")
+            .await
+            .map_err(db_error)?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.client()
+            .batch_execute("commit")
+            .await
+            .map_err(db_error)?;
+        self.in_transaction = false;
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        self.client()
+            .batch_execute("rollback")
+            .await
+            .map_err(db_error)?;
+        self.in_transaction = false;
+        Ok(())
+    }
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>> {
+        let client = self.client();
+        let statement = client.prepare_cached(sql).await.map_err(db_error)?;
+        client.query(&statement, params).await.map_err(db_error)
+    }
+
+    async fn query_opt(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>> {
+        let client = self.client();
+        let statement = client.prepare_cached(sql).await.map_err(db_error)?;
+        client.query_opt(&statement, params).await.map_err(db_error)
+    }
+
+    async fn query_one(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Row> {
+        let client = self.client();
+        let statement = client.prepare_cached(sql).await.map_err(db_error)?;
+        client.query_one(&statement, params).await.map_err(db_error)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<usize> {
+        let client = self.client();
+        let statement = client.prepare_cached(sql).await.map_err(db_error)?;
+        client
+            .execute(&statement, params)
+            .await
+            .map(|rows| rows as usize)
+            .map_err(db_error)
+    }
+
+    async fn insert_history_keys(&self, rows: Vec<Row>) -> Result<()> {
+        let mut addresses = vec![];
+        let mut keys = vec![];
+        let mut data_entry_uids = vec![];
+        let mut block_uids = vec![];
+        for row in &rows {
+            addresses.push(get::<&str>(row, 0)?);
+            keys.push(get::<&str>(row, 1)?);
+            data_entry_uids.push(get::<i64>(row, 2)?);
+            block_uids.push(get::<i64>(row, 3)?);
+        }
+
+        // history keys are unique per data entry, so a retried insert is a no-op
+        let hist_uids = self
+            .query(
+                &format!(
+                    r#"
+                        insert into {} (address, key, data_entry_uid, block_uid)
+                        select * from unnest($1::text[], $2::text[], $3::int8[], $4::int8[])
+                        on conflict (address, key, data_entry_uid) do nothing
+                        returning uid
+                    "#,
+                    table("data_entries_history_keys")
+                ),
+                &[&addresses, &keys, &data_entry_uids, &block_uids],
+            )
+            .await?
+            .iter()
+            .map(|row| get::<i64>(row, 0))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.execute(
+            &format!(
+                r#"
+                    update {} hk set
+                        height = (select height from {blocks} where uid = hk.block_uid),
+                        block_timestamp = (select to_timestamp(time_stamp / 1000) from {blocks} where uid = hk.block_uid)
+                    where hk.uid = any($1)
+                "#,
+                table("data_entries_history_keys"),
+                blocks = table("blocks_microblocks")
+            ),
+            &[&hist_uids],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn update_block_references(&self, table_name: &str, block_uid: &i64) -> Result<()> {
+        self.execute(
+            &format!(
+                "update {} set block_uid = $1 where block_uid > $1",
+                table(table_name)
+            ),
+            &[block_uid],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+#[async_trait]
+impl AsyncDataEntriesRepoOperations for AsyncPgConnection {
+    async fn get_handled_height(&mut self, depth: u32) -> Result<Option<PrevHandledHeight>> {
+        let row = self
+            .query_opt(
+                &format!(
+                    r#"
+                        select uid, height from {blocks}
+                        where height = (select coalesce((select last_height from {}), max(height)) - {} from {blocks})
+                        order by uid
+                        limit 1
+                    "#,
+                    table("consumer_state"),
+                    depth,
+                    blocks = table("blocks_microblocks")
+                ),
+                &[],
+            )
+            .await?;
+
+        row.map(|row| {
+            Ok(PrevHandledHeight {
+                uid: get(&row, 0)?,
+                height: get(&row, 1)?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn get_consumer_state(&mut self) -> Result<Option<ConsumerState>> {
+        let row = self
+            .query_opt(
+                &format!(
+                    "select last_height, last_uid from {} limit 1",
+                    table("consumer_state")
+                ),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get consumer state."))?;
+
+        row.map(|row| {
+            Ok(ConsumerState {
+                last_height: get(&row, 0)?,
+                last_uid: get(&row, 1)?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn set_consumer_state(&mut self, state: &ConsumerState) -> Result<()> {
+        self.execute(
+            &format!(
+                "insert into {} (id, last_height, last_uid) values (1, $1, $2)
+                    on conflict (id) do update set last_height = excluded.last_height, last_uid = excluded.last_uid",
+                table("consumer_state")
+            ),
+            &[&state.last_height, &state.last_uid],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|err| err.context("Cannot set consumer state."))
+    }
+
+    async fn get_applied_reset_to_height(&mut self) -> Result<Option<i32>> {
+        let row = self
+            .query_opt(
+                &format!(
+                    "select applied_reset_to_height from {} limit 1",
+                    table("consumer_state")
+                ),
+                &[],
+            )
+            .await?;
+
+        Ok(row.map(|row| get(&row, 0)).transpose()?.flatten())
+    }
+
+    async fn set_applied_reset_to_height(&mut self, height: i32) -> Result<()> {
+        self.execute(
+            &format!(
+                "insert into {} (id, last_height, applied_reset_to_height) values (1, 0, $1)
+                    on conflict (id) do update set applied_reset_to_height = excluded.applied_reset_to_height",
+                table("consumer_state")
+            ),
+            &[&height],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn get_block_uid(&mut self, block_id: &str) -> Result<Option<i64>> {
+        let row = self
+            .query_opt(
+                &format!(
+                    "select uid from {} where id = $1",
+                    table("blocks_microblocks")
+                ),
+                &[&block_id],
+            )
+            .await
+            .map_err(|err| {
+                err.context(format!("Cannot get block_uid by block id {}.", block_id))
+            })?;
+
+        row.map(|row| get(&row, 0)).transpose()
+    }
+
+    async fn get_block_height(&mut self, block_uid: &i64) -> Result<i32> {
+        let row = self
+            .query_one(
+                &format!(
+                    "select height from {} where uid = $1",
+                    table("blocks_microblocks")
+                ),
+                &[block_uid],
+            )
+            .await
+            .map_err(|err| err.context(format!("Cannot get height by block uid {}.", block_uid)))?;
+
+        get(&row, 0)
+    }
+
+    async fn get_data_entry(&mut self, address: &str, key: &str) -> Result<Option<DataEntry>> {
+        // a deleted key keeps its deletion as the live version, it is not found
+        let row = self
+            .query_opt(
+                &format!(
+                    r#"
+                        select {} from {}
+                        where address = $1 and key = $2 and superseded_by = $3
+                          and value_type is distinct from $4
+                        limit 1
+                    "#,
+                    DATA_ENTRY_COLUMNS,
+                    table("data_entries")
+                ),
+                &[&address, &key, &LIVE_SUPERSEDED_BY, &VALUE_TYPE_DELETED],
+            )
+            .await?;
+
+        row.map(|row| data_entry(&row, 0)).transpose()
+    }
+
+    async fn get_data_entry_at_height(
+        &mut self,
+        address: &str,
+        key: &str,
+        at_height: i32,
+    ) -> Result<Option<DataEntry>> {
+        // the last version written not above the height is superseded above it or is live
+        let row = self
+            .query_opt(
+                &format!(
+                    r#"
+                        select data_entry_uid from {}
+                        where address = $1 and key = $2 and height <= $3
+                        order by height desc, data_entry_uid desc
+                        limit 1
+                    "#,
+                    table("data_entries_history_keys")
+                ),
+                &[&address, &key, &at_height],
+            )
+            .await?;
+
+        let data_entry_uid: i64 = match row {
+            Some(row) => get(&row, 0)?,
+            None => return Ok(None),
+        };
+
+        // a key deleted at or below the height is not found
+        let row = self
+            .query_opt(
+                &format!(
+                    "select {} from {} where uid = $1 and value_type is distinct from $2 limit 1",
+                    DATA_ENTRY_COLUMNS,
+                    table("data_entries")
+                ),
+                &[&data_entry_uid, &VALUE_TYPE_DELETED],
+            )
+            .await?;
+
+        row.map(|row| data_entry(&row, 0)).transpose()
+    }
+
+    async fn list_data_entries(
+        &mut self,
+        address: &str,
+        after_key: Option<&str>,
+        limit: i64,
+    ) -> Result<(Vec<DataEntry>, Option<String>)> {
+        let rows = self
+            .query(
+                &format!(
+                    r#"
+                        select {} from {}
+                        where address = $1 and superseded_by = $2 and ($3::varchar is null or key > $3)
+                          and value_type is distinct from $5
+                        order by key
+                        limit $4
+                    "#,
+                    DATA_ENTRY_COLUMNS,
+                    table("data_entries")
+                ),
+                &[
+                    &address,
+                    &LIVE_SUPERSEDED_BY,
+                    &after_key,
+                    &(limit + 1),
+                    &VALUE_TYPE_DELETED,
+                ],
+            )
+            .await?;
+
+        let entries = rows
+            .iter()
+            .map(|row| data_entry(row, 0))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(page(entries, limit, |de| de.key.clone()))
+    }
+
+    async fn get_data_entry_history(
+        &mut self,
+        address: &str,
+        key: &str,
+        after_uid: Option<i64>,
+        limit: i64,
+    ) -> Result<(Vec<DataEntryVersion>, Option<i64>)> {
+        let rows = self
+            .query(
+                &format!(
+                    r#"
+                        select d.uid, hk.height, hk.block_timestamp, {}
+                        from {} d
+                            inner join {} hk
+                                on hk.data_entry_uid = d.uid and hk.address = d.address and hk.key = d.key
+                        where d.address = $1 and d.key = $2 and ($3::int8 is null or d.uid > $3)
+                        order by d.uid
+                        limit $4
+                    "#,
+                    DATA_ENTRY_COLUMNS
+                        .split(", ")
+                        .map(|column| format!("d.{}", column))
+                        .join(", "),
+                    table("data_entries"),
+                    table("data_entries_history_keys")
+                ),
+                &[&address, &key, &after_uid, &(limit + 1)],
+            )
+            .await?;
+
+        let versions = rows
+            .iter()
+            .map(|row| {
+                Ok(DataEntryVersion {
+                    uid: get(row, 0)?,
+                    height: get(row, 1)?,
+                    block_timestamp: get(row, 2)?,
+                    data_entry: data_entry(row, 3)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(page(versions, limit, |version| version.uid))
+    }
+
+    async fn search_by_fragments(
+        &mut self,
+        filters: &[FragmentFilter],
+        limit: i64,
+        after: Option<i64>,
+    ) -> Result<(Vec<DataEntry>, Option<i64>)> {
+        let mut conditions = vec!["superseded_by = $1".to_string()];
+        let mut values: Vec<Param> = vec![Box::new(LIVE_SUPERSEDED_BY)];
+
+        // fragment columns are picked by index, which is a number, so it is safe to format
+        for filter in filters {
+            let (column, value): (String, Param) = match filter {
+                FragmentFilter::String(index, value) => (
+                    format!("fragment_{}_string", index),
+                    Box::new(value.as_str()),
+                ),
+                FragmentFilter::Integer(index, value) => {
+                    (format!("fragment_{}_integer", index), Box::new(*value))
+                }
+            };
+            values.push(value);
+            conditions.push(format!("{} = ${}", column, values.len()));
+        }
+
+        if let Some(after) = after {
+            values.push(Box::new(after));
+            conditions.push(format!("uid > ${}", values.len()));
+        }
+        values.push(Box::new(limit + 1));
+
+        let rows = self
+            .query(
+                &format!(
+                    "select uid, {} from {} where {} order by uid limit ${}",
+                    DATA_ENTRY_COLUMNS,
+                    table("data_entries"),
+                    conditions.join(" and "),
+                    values.len()
+                ),
+                &params(&values),
+            )
+            .await?;
+
+        let entries = rows
+            .iter()
+            .map(|row| Ok((get::<i64>(row, 0)?, data_entry(row, 1)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let (entries, cursor) = page(entries, limit, |(last_uid, _)| *last_uid);
+        Ok((entries.into_iter().map(|(_, de)| de).collect(), cursor))
+    }
+
+    async fn get_transaction(&mut self, tx_id: &str) -> Result<Option<StoredTransaction>> {
+        let row = self
+            .query_opt(
+                &format!(
+                    "select tx_id, height, tx_type from {} where tx_id = $1 limit 1",
+                    table("transactions")
+                ),
+                &[&tx_id],
+            )
+            .await?;
+
+        row.map(|row| {
+            Ok(StoredTransaction {
+                tx_id: get(&row, 0)?,
+                height: get(&row, 1)?,
+                tx_type: get(&row, 2)?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn get_key_block_uid(&mut self) -> Result<Option<i64>> {
+        let row = self
+            .query_one(
+                &format!(
+                    "select max(uid) from {} where time_stamp is not null",
+                    table("blocks_microblocks")
+                ),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get key block uid."))?;
+
+        get(&row, 0)
+    }
+
+    async fn ping(&mut self) -> Result<()> {
+        self.execute("select 1", &[]).await.map(|_| ())
+    }
+
+    async fn get_last_height(&mut self) -> Result<Option<i32>> {
+        let row = self
+            .query_one(
+                &format!("select max(height) from {}", table("blocks_microblocks")),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get last height."))?;
+
+        get(&row, 0)
+    }
+
+    async fn get_last_block_timestamp(&mut self) -> Result<Option<i64>> {
+        let row = self
+            .query_one(
+                &format!(
+                    "select max(time_stamp) from {}",
+                    table("blocks_microblocks")
+                ),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get last block timestamp."))?;
+
+        get(&row, 0)
+    }
+
+    async fn get_last_block_uid_below(&mut self, height: i32) -> Result<Option<i64>> {
+        let row = self
+            .query_one(
+                &format!(
+                    "select max(uid) from {} where height < $1",
+                    table("blocks_microblocks")
+                ),
+                &[&height],
+            )
+            .await
+            .map_err(|err| {
+                err.context(format!(
+                    "Cannot get last block uid below height {}.",
+                    height
+                ))
+            })?;
+
+        get(&row, 0)
+    }
+
+    async fn get_total_block_id(&mut self) -> Result<Option<String>> {
+        let row = self
+            .query_opt(
+                &format!(
+                    "select id from {} where time_stamp is null order by uid desc limit 1",
+                    table("blocks_microblocks")
+                ),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get total block id."))?;
+
+        row.map(|row| get(&row, 0)).transpose()
+    }
+
+    async fn get_next_update_uid(&mut self) -> Result<i64> {
+        let row = self
+            .query_one(
+                &format!("select last_value from {}", table("data_entries_uid_seq")),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get next update uid."))?;
+
+        get(&row, 0)
+    }
+
+    async fn reserve_update_uids(&mut self, count: i64) -> Result<i64> {
+        self.execute("select pg_advisory_xact_lock($1)", &[&UID_RESERVATION_LOCK])
+            .await
+            .map_err(|err| err.context("Cannot lock update uids."))?;
+
+        // is_called is false, so last_value is the next uid to be taken
+        let row = self
+            .query_one(
+                &format!(
+                    "select setval('{uid_seq}', last_value + $1, false) - $1 as first_uid from {uid_seq}",
+                    uid_seq = table("data_entries_uid_seq")
+                ),
+                &[&count],
+            )
+            .await
+            .map_err(|err| err.context("Cannot reserve update uids."))?;
+
+        get(&row, 0)
+    }
+
+    async fn get_last_data_entry_uid(&mut self) -> Result<Option<i64>> {
+        let row = self
+            .query_one(
+                &format!("select max(uid) from {}", table("data_entries")),
+                &[],
+            )
+            .await
+            .map_err(|err| err.context("Cannot get last data entry uid."))?;
+
+        get(&row, 0)
+    }
+
+    async fn insert_blocks_or_microblocks(
+        &mut self,
+        blocks: &[BlockMicroblock],
+    ) -> Result<Vec<Option<i64>>> {
+        let ids = blocks.iter().map(|block| block.id.as_str()).collect_vec();
+        let time_stamps = blocks.iter().map(|block| block.time_stamp).collect_vec();
+        let heights = blocks.iter().map(|block| block.height).collect_vec();
+
+        // uids are assigned in the order of the blocks
+        let inserted: HashMap<String, i64> = self
+            .query(
+                &format!(
+                    r#"
+                        insert into {} (id, time_stamp, height)
+                        select id, time_stamp, height
+                        from unnest($1::varchar[], $2::int8[], $3::int4[]) with ordinality
+                            as b(id, time_stamp, height, position)
+                        order by position
+                        on conflict (id) do nothing
+                        returning id, uid
+                    "#,
+                    table("blocks_microblocks")
+                ),
+                &[&ids, &time_stamps, &heights],
+            )
+            .await?
+            .iter()
+            .map(|row| Ok((get(row, 0)?, get(row, 1)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(blocks
+            .iter()
+            .map(|block| inserted.get(&block.id).copied())
+            .collect())
+    }
+
+    async fn insert_data_entries<I>(&mut self, entries: I, chunk_size: usize) -> Result<()>
+    where
+        I: IntoIterator<Item = InsertableDataEntry> + Send,
+        I::IntoIter: Send,
+    {
+        let columns = inserted_data_entry_columns();
+        let sql = format!(
+            "{} returning address, key, uid, block_uid",
+            unnest_insert_sql("data_entries", &columns)
+        );
+
+        // chunks are pulled from the iterator lazily,
+        // so only one chunk is materialized at a time
+        let mut entries = entries.into_iter();
+        loop {
+            let chunk = entries.by_ref().take(chunk_size).collect_vec();
+            if chunk.is_empty() {
+                break;
+            }
+
+            let value_binaries = chunk.iter().map(binary_text).collect::<Result<Vec<_>>>()?;
+            let fragments = chunk.iter().map(fragments).collect_vec();
+            let mut values: Vec<Param> = vec![
+                Box::new(chunk.iter().map(|de| de.block_uid).collect_vec()),
+                Box::new(
+                    chunk
+                        .iter()
+                        .map(|de| de.transaction_id.as_str())
+                        .collect_vec(),
+                ),
+                Box::new(chunk.iter().map(|de| de.uid).collect_vec()),
+                Box::new(chunk.iter().map(|de| de.superseded_by).collect_vec()),
+                Box::new(chunk.iter().map(|de| de.address.as_str()).collect_vec()),
+                Box::new(chunk.iter().map(|de| de.key.as_str()).collect_vec()),
+                Box::new(value_binaries),
+                Box::new(chunk.iter().map(|de| de.value_bool).collect_vec()),
+                Box::new(chunk.iter().map(|de| de.value_integer).collect_vec()),
+                Box::new(
+                    chunk
+                        .iter()
+                        .map(|de| de.value_string.as_deref())
+                        .collect_vec(),
+                ),
+                Box::new(chunk.iter().map(|de| de.value_type.as_str()).collect_vec()),
+            ];
+            for position in 0..2 * FRAGMENT_COUNT {
+                values.push(Box::new(
+                    fragments.iter().map(|f| f[position].0).collect_vec(),
+                ));
+                values.push(Box::new(
+                    fragments.iter().map(|f| f[position].1).collect_vec(),
+                ));
+            }
+
+            let rows = self.query(&sql, &params(&values)).await?;
+            self.insert_history_keys(rows).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn copy_data_entries<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = InsertableDataEntry> + Send,
+        I::IntoIter: Send,
+    {
+        let columns = inserted_data_entry_columns();
+        let types = columns
+            .iter()
+            .map(|(_, column_type)| column_type.clone())
+            .collect_vec();
+        let copy_sql = format!(
+            "copy {} ({}) from stdin binary",
+            table("data_entries"),
+            columns.iter().map(|(name, _)| name).join(", ")
+        );
+
+        let mut entries = entries.into_iter();
+        loop {
+            let chunk = entries.by_ref().take(COPY_CHUNK_SIZE).collect_vec();
+            let (first_uid, last_uid) = match chunk.iter().map(|de| de.uid).minmax() {
+                itertools::MinMaxResult::NoElements => break,
+                itertools::MinMaxResult::OneElement(u) => (u, u),
+                itertools::MinMaxResult::MinMax(min, max) => (min, max),
+            };
+
+            let sink = self
+                .client()
+                .copy_in(copy_sql.as_str())
+                .await
+                .map_err(db_error)?;
+            let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+            for de in &chunk {
+                let value_binary = binary_text(de)?;
+                let fragments = fragments(de);
+                let mut row: Vec<&(dyn ToSql + Sync)> = vec![
+                    &de.block_uid,
+                    &de.transaction_id,
+                    &de.uid,
+                    &de.superseded_by,
+                    &de.address,
+                    &de.key,
+                    &value_binary,
+                    &de.value_bool,
+                    &de.value_integer,
+                    &de.value_string,
+                    &de.value_type,
+                ];
+                for (integer, string) in &fragments {
+                    row.push(integer);
+                    row.push(string);
+                }
+                writer.as_mut().write(&row).await.map_err(db_error)?;
+            }
+            writer.finish().await.map_err(db_error)?;
+
+            // copy cannot return rows, but uids are assigned by the consumer,
+            // so the inserted rows are exactly the ones in the uid range of the chunk
+            let rows = self
+                .query(
+                    &format!(
+                        "select address, key, uid, block_uid from {} where uid between $1 and $2",
+                        table("data_entries")
+                    ),
+                    &[&first_uid, &last_uid],
+                )
+                .await?;
+            self.insert_history_keys(rows).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn insert_leases(&mut self, leases: &[InsertableLease]) -> Result<()> {
+        let columns = columns(&[
+            ("block_uid", Type::INT8),
+            ("transaction_id", Type::VARCHAR),
+            ("lease_id", Type::VARCHAR),
+            ("sender", Type::VARCHAR),
+            ("recipient", Type::VARCHAR),
+            ("amount", Type::INT8),
+            ("status", Type::VARCHAR),
+        ]);
+        let values: Vec<Param> = vec![
+            Box::new(leases.iter().map(|l| l.block_uid).collect_vec()),
+            Box::new(
+                leases
+                    .iter()
+                    .map(|l| l.transaction_id.as_str())
+                    .collect_vec(),
+            ),
+            Box::new(leases.iter().map(|l| l.lease_id.as_str()).collect_vec()),
+            Box::new(leases.iter().map(|l| l.sender.as_str()).collect_vec()),
+            Box::new(leases.iter().map(|l| l.recipient.as_str()).collect_vec()),
+            Box::new(leases.iter().map(|l| l.amount).collect_vec()),
+            Box::new(leases.iter().map(|l| l.status.as_str()).collect_vec()),
+        ];
+
+        self.execute(&unnest_insert_sql("leases", &columns), &params(&values))
+            .await
+            .map(|_| ())
+    }
+
+    async fn insert_transfers(&mut self, transfers: &[InsertableTransfer]) -> Result<()> {
+        let columns = columns(&[
+            ("block_uid", Type::INT8),
+            ("height", Type::INT4),
+            ("transaction_id", Type::VARCHAR),
+            ("sender", Type::VARCHAR),
+            ("recipient", Type::VARCHAR),
+            ("asset_id", Type::VARCHAR),
+            ("amount", Type::INT8),
+            ("attachment", Type::VARCHAR),
+        ]);
+        let values: Vec<Param> = vec![
+            Box::new(transfers.iter().map(|t| t.block_uid).collect_vec()),
+            Box::new(transfers.iter().map(|t| t.height).collect_vec()),
+            Box::new(
+                transfers
+                    .iter()
+                    .map(|t| t.transaction_id.as_str())
+                    .collect_vec(),
+            ),
+            Box::new(transfers.iter().map(|t| t.sender.as_str()).collect_vec()),
+            Box::new(transfers.iter().map(|t| t.recipient.as_str()).collect_vec()),
+            Box::new(
+                transfers
+                    .iter()
+                    .map(|t| t.asset_id.as_deref())
+                    .collect_vec(),
+            ),
+            Box::new(transfers.iter().map(|t| t.amount).collect_vec()),
+            Box::new(
+                transfers
+                    .iter()
+                    .map(|t| t.attachment.as_deref())
+                    .collect_vec(),
+            ),
+        ];
+
+        self.execute(&unnest_insert_sql("transfers", &columns), &params(&values))
+            .await
+            .map(|_| ())
+    }
+
+    async fn insert_contract_events(&mut self, events: &[InsertableContractEvent]) -> Result<()> {
+        let columns = columns(&[
+            ("block_uid", Type::INT8),
+            ("height", Type::INT4),
+            ("transaction_id", Type::VARCHAR),
+            ("address", Type::VARCHAR),
+            ("event_type", Type::VARCHAR),
+            ("has_script", Type::BOOL),
+            ("asset_id", Type::VARCHAR),
+            ("asset_name", Type::VARCHAR),
+            ("asset_description", Type::VARCHAR),
+        ]);
+        let values: Vec<Param> = vec![
+            Box::new(events.iter().map(|e| e.block_uid).collect_vec()),
+            Box::new(events.iter().map(|e| e.height).collect_vec()),
+            Box::new(
+                events
+                    .iter()
+                    .map(|e| e.transaction_id.as_str())
+                    .collect_vec(),
+            ),
+            Box::new(events.iter().map(|e| e.address.as_str()).collect_vec()),
+            Box::new(events.iter().map(|e| e.event_type.as_str()).collect_vec()),
+            Box::new(events.iter().map(|e| e.has_script).collect_vec()),
+            Box::new(events.iter().map(|e| e.asset_id.as_deref()).collect_vec()),
+            Box::new(events.iter().map(|e| e.asset_name.as_deref()).collect_vec()),
+            Box::new(
+                events
+                    .iter()
+                    .map(|e| e.asset_description.as_deref())
+                    .collect_vec(),
+            ),
+        ];
+
+        self.execute(
+            &unnest_insert_sql("contract_events", &columns),
+            &params(&values),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn insert_transactions(&mut self, transactions: &[InsertableTransaction]) -> Result<()> {
+        let columns = columns(&[
+            ("tx_id", Type::VARCHAR),
+            ("block_uid", Type::INT8),
+            ("height", Type::INT4),
+            ("tx_type", Type::INT2),
+        ]);
+        let values: Vec<Param> = vec![
+            Box::new(transactions.iter().map(|t| t.tx_id.as_str()).collect_vec()),
+            Box::new(transactions.iter().map(|t| t.block_uid).collect_vec()),
+            Box::new(transactions.iter().map(|t| t.height).collect_vec()),
+            Box::new(transactions.iter().map(|t| t.tx_type).collect_vec()),
+        ];
+
+        self.execute(
+            &unnest_insert_sql("transactions", &columns),
+            &params(&values),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn close_superseded_by(&mut self, updates: &[DataEntryUpdate]) -> Result<()> {
+        let addresses = updates.iter().map(|u| u.address.as_str()).collect_vec();
+        let keys = updates.iter().map(|u| u.key.as_str()).collect_vec();
+        let superseded_bys = updates.iter().map(|u| u.superseded_by).collect_vec();
+
+        self.execute(
+            &format!(
+                r#"
+                    update {data_entries} set superseded_by = updates.superseded_by
+                    from (select unnest($1::varchar[]) as address, unnest($2::varchar[]) as key, unnest($3::int8[]) as superseded_by) as updates
+                    where {data_entries}.address = updates.address and {data_entries}.key = updates.key and {data_entries}.superseded_by = $4
+                "#,
+                data_entries = table("data_entries")
+            ),
+            &[&addresses, &keys, &superseded_bys, &LIVE_SUPERSEDED_BY],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn reopen_superseded_by(&mut self, current_superseded_by: &[i64]) -> Result<()> {
+        self.execute(
+            &format!(
+                r#"
+                    update {data_entries} set superseded_by = $1
+                    from (select unnest($2::int8[]) as superseded_by) as current
+                    where {data_entries}.superseded_by = current.superseded_by
+                "#,
+                data_entries = table("data_entries")
+            ),
+            &[&LIVE_SUPERSEDED_BY, &current_superseded_by],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn set_next_update_uid(&mut self, uid: i64) -> Result<()> {
+        // 3rd param - is called; in case of true, value'll be incremented before returning
+        self.execute(
+            &format!(
+                "select setval('{}', $1, false)",
+                table("data_entries_uid_seq")
+            ),
+            &[&uid],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn change_block_id(&mut self, block_uid: &i64, new_block_id: &str) -> Result<()> {
+        self.execute(
+            &format!(
+                "update {} set id = $1 where uid = $2",
+                table("blocks_microblocks")
+            ),
+            &[&new_block_id, block_uid],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn update_data_entries_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.update_block_references("data_entries", block_uid)
+            .await?;
+        self.update_block_references("data_entries_history_keys", block_uid)
+            .await
+    }
+
+    async fn update_leases_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.update_block_references("leases", block_uid).await
+    }
+
+    async fn update_transfers_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.update_block_references("transfers", block_uid).await
+    }
+
+    async fn update_contract_events_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.update_block_references("contract_events", block_uid)
+            .await
+    }
+
+    async fn update_transactions_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.update_block_references("transactions", block_uid)
+            .await
+    }
+
+    async fn delete_microblocks(&mut self) -> Result<()> {
+        self.execute(
+            &format!(
+                "delete from {} where time_stamp is null",
+                table("blocks_microblocks")
+            ),
+            &[],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn rollback_blocks_microblocks(&mut self, block_uid: &i64) -> Result<()> {
+        self.execute(
+            &format!("delete from {} where uid > $1", table("blocks_microblocks")),
+            &[block_uid],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn rollback_microblocks(&mut self, block_uid: &i64) -> Result<()> {
+        self.execute(
+            &format!(
+                "delete from {} where uid > $1 and time_stamp is null",
+                table("blocks_microblocks")
+            ),
+            &[block_uid],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn rollback_data_entries(&mut self, block_uid: &i64) -> Result<Vec<DeletedDataEntry>> {
+        self.query(
+            &format!(
+                "delete from {} where block_uid > $1 returning address, key, uid",
+                table("data_entries")
+            ),
+            &[block_uid],
+        )
+        .await?
+        .iter()
+        .map(deleted_data_entry)
+        .collect()
+    }
+
+    async fn rollback_microblock_data_entries(
+        &mut self,
+        block_uid: &i64,
+    ) -> Result<Vec<DeletedDataEntry>> {
+        // the backward scan of the primary key stops at the first kept entry
+        self.query(
+            &format!(
+                r#"
+                    delete from {data_entries}
+                    where uid > coalesce(
+                        (select uid from {data_entries} where block_uid <= $1 order by uid desc limit 1),
+                        0
+                    )
+                    returning address, key, uid
+                "#,
+                data_entries = table("data_entries")
+            ),
+            &[block_uid],
+        )
+        .await?
+        .iter()
+        .map(deleted_data_entry)
+        .collect()
+    }
+
+    async fn refresh_last_writes(&mut self, keys: &[(String, String)]) -> Result<()> {
+        let (addresses, keys): (Vec<&str>, Vec<&str>) = keys
+            .iter()
+            .map(|(address, key)| (address.as_str(), key.as_str()))
+            .unzip();
+
+        self.execute(
+            &format!(
+                r#"
+                    delete from {} lw
+                    using (select unnest($1::varchar[]) as address, unnest($2::varchar[]) as key) k
+                    where lw.address = k.address and lw.key = k.key
+                "#,
+                table("data_entries_last_writes")
+            ),
+            &[&addresses, &keys],
+        )
+        .await?;
+
+        self.execute(
+            &format!(
+                r#"
+                    insert into {} (address, key, height, block_timestamp)
+                    select d.address, d.key, b.height, to_timestamp(b.time_stamp / 1000)
+                    from {} d
+                        inner join (select unnest($1::varchar[]) as address, unnest($2::varchar[]) as key) k
+                            on d.address = k.address and d.key = k.key
+                        inner join {} b on b.uid = d.block_uid
+                    where d.superseded_by = $3
+                "#,
+                table("data_entries_last_writes"),
+                table("data_entries"),
+                table("blocks_microblocks")
+            ),
+            &[&addresses, &keys, &LIVE_SUPERSEDED_BY],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn delete_unreferenced_blocks_microblocks(&mut self, below_height: i32) -> Result<usize> {
+        self.execute(
+            &format!(
+                r#"
+                    delete from {} b
+                    where b.height < $1
+                        and not exists (select 1 from {} d where d.block_uid = b.uid)
+                        and not exists (select 1 from {} hk where hk.block_uid = b.uid)
+                        and not exists (select 1 from {} l where l.block_uid = b.uid)
+                        and not exists (select 1 from {} t where t.block_uid = b.uid)
+                        and not exists (select 1 from {} ce where ce.block_uid = b.uid)
+                        and not exists (select 1 from {} tx where tx.block_uid = b.uid)
+                "#,
+                table("blocks_microblocks"),
+                table("data_entries"),
+                table("data_entries_history_keys"),
+                table("leases"),
+                table("transfers"),
+                table("contract_events"),
+                table("transactions")
+            ),
+            &[&below_height],
+        )
+        .await
+    }
+
+    async fn delete_superseded_data_entries(
+        &mut self,
+        block_uid: &i64,
+        limit: i64,
+    ) -> Result<usize> {
+        let row = self
+            .query_one(
+                &format!(
+                    "select max(uid) from {} where block_uid <= $1",
+                    table("data_entries")
+                ),
+                &[block_uid],
+            )
+            .await?;
+
+        // live versions are superseded by LIVE_SUPERSEDED_BY, so they are never below the last uid
+        let last_uid: i64 = match get(&row, 0)? {
+            Some(last_uid) => last_uid,
+            None => return Ok(0),
+        };
+
+        let rows = self
+            .query(
+                &format!(
+                    "select uid, address, key from {} where superseded_by <= $1 limit $2",
+                    table("data_entries")
+                ),
+                &[&last_uid, &limit],
+            )
+            .await?;
+        let mut uids = vec![];
+        let mut addresses = vec![];
+        let mut keys = vec![];
+        for row in &rows {
+            uids.push(get::<i64>(row, 0)?);
+            addresses.push(get::<&str>(row, 1)?);
+            keys.push(get::<&str>(row, 2)?);
+        }
+
+        if uids.is_empty() {
+            return Ok(0);
+        }
+
+        self.execute(
+            &format!(
+                r#"
+                    delete from {} hk
+                    using (select unnest($1::text[]) as address, unnest($2::text[]) as key, unnest($3::int8[]) as data_entry_uid) d
+                    where hk.address = d.address and hk.key = d.key and hk.data_entry_uid = d.data_entry_uid
+                "#,
+                table("data_entries_history_keys")
+            ),
+            &[&addresses, &keys, &uids],
+        )
+        .await?;
+
+        self.execute(
+            &format!("delete from {} where uid = any($1)", table("data_entries")),
+            &[&uids],
+        )
+        .await
+    }
+
+    async fn is_value_type_validated(&mut self) -> Result<bool> {
+        let row = self
+            .query_one(
+                "select coalesce(bool_and(convalidated), false) from pg_constraint where conname = 'data_entries_value_type_not_null' and conrelid = $1::text::regclass",
+                &[&table("data_entries")],
+            )
+            .await?;
+
+        get(&row, 0)
+    }
+
+    async fn backfill_value_types(&mut self, from_uid: i64, to_uid: i64) -> Result<usize> {
+        // the range is read by the primary key, so a batch never scans the whole table
+        self.execute(
+            &format!(
+                r#"
+                    update {}
+                    set value_type = case
+                        when value_binary is not null then '{}'
+                        when value_bool is not null then '{}'
+                        when value_integer is not null then '{}'
+                        when value_string is not null then '{}'
+                        else '{}'
+                    end
+                    where uid >= $1 and uid < $2 and value_type is null
+                "#,
+                table("data_entries"),
+                VALUE_TYPE_BINARY,
+                VALUE_TYPE_BOOL,
+                VALUE_TYPE_INTEGER,
+                VALUE_TYPE_STRING,
+                VALUE_TYPE_DELETED
+            ),
+            &[&from_uid, &to_uid],
+        )
+        .await
+    }
+
+    async fn validate_value_type(&mut self) -> Result<()> {
+        // validation only takes a SHARE UPDATE EXCLUSIVE lock, the writes go on meanwhile
+        self.execute(
+            &format!(
+                "alter table {} validate constraint data_entries_value_type_not_null",
+                table("data_entries")
+            ),
+            &[],
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+/// Against Postgres, see the README on how to run them.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_entries::repo::PgDataEntriesRepo;
+    use crate::data_entries::{
+        DataEntriesRepo, DataEntriesRepoOperations, DataEntryValue, Fragments,
+    };
+    use crate::db::testing::{postgres_config, TestDatabase};
+    use crate::db::{async_pool_with_url, pool_with_url};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const ADDRESS: &str = "3PAddress";
+
+    fn async_repo(db: &TestDatabase, poolsize: u32) -> AsyncPgDataEntriesRepo {
+        let mut config = postgres_config("state-consumer-test");
+        config.poolsize = poolsize;
+        AsyncPgDataEntriesRepo::new(async_pool_with_url(&config, db.url.clone()).unwrap())
+    }
+
+    /// A live version, the key and the uid are its string fragments.
+    fn entry(block_uid: i64, uid: i64, key: &str, value: DataEntryValue) -> InsertableDataEntry {
+        let mut key_fragments = Fragments::default();
+        key_fragments[0] = (None, Some(key.to_string()));
+        key_fragments[1] = (Some(uid), Some(uid.to_string()));
+        let mut entry = InsertableDataEntry::new(
+            block_uid,
+            uid,
+            DataEntry {
+                address: ADDRESS.to_string(),
+                key: key.to_string(),
+                transaction_id: format!("tx-{}", uid),
+                value,
+            },
+            key_fragments,
+            Default::default(),
+        );
+        entry.superseded_by = LIVE_SUPERSEDED_BY;
+        entry
+    }
+
+    /// Append a key block at the height writing the keys, every write supersedes the live version of its key.
+    async fn append_block(repo: &AsyncPgDataEntriesRepo, height: i32, keys: Vec<String>) {
+        repo.transaction(move |ops| {
+            Box::pin(async move {
+                let block_uid = ops
+                    .insert_blocks_or_microblocks(&[BlockMicroblock {
+                        id: format!("b{}", height),
+                        time_stamp: Some(height as i64 * 60_000),
+                        height,
+                    }])
+                    .await?[0]
+                    .unwrap();
+                let first_uid = ops.reserve_update_uids(keys.len() as i64).await?;
+                let updates = keys
+                    .iter()
+                    .zip(first_uid..)
+                    .map(|(key, uid)| DataEntryUpdate {
+                        superseded_by: uid,
+                        address: ADDRESS.to_string(),
+                        key: key.clone(),
+                    })
+                    .collect_vec();
+                ops.close_superseded_by(&updates).await?;
+                let entries = keys
+                    .iter()
+                    .zip(first_uid..)
+                    .map(|(key, uid)| {
+                        entry(block_uid, uid, key, DataEntryValue::Integer(height as i64))
+                    })
+                    .collect_vec();
+                ops.insert_data_entries(entries, 2).await
+            })
+        })
+        .await
+        .unwrap();
+    }
+
+    fn values(entries: &[DataEntry]) -> Vec<(String, DataEntryValue)> {
+        entries
+            .iter()
+            .map(|de| (de.key.clone(), de.value.clone()))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+    async fn writes_are_read_back_as_the_diesel_repo_reads_them() {
+        let db = TestDatabase::create();
+        let repo = async_repo(&db, 2);
+        let diesel_repo = PgDataEntriesRepo::new(db.pool.clone());
+
+        append_block(&repo, 1, vec!["k".to_string(), "j".to_string()]).await;
+        append_block(&repo, 2, vec!["k".to_string()]).await;
+        // the other value types are copied, as on the initial sync
+        repo.transaction(|ops| {
+            Box::pin(async move {
+                let block_uid = ops.get_block_uid("b2").await?.unwrap();
+                let first_uid = ops.reserve_update_uids(4).await?;
+                let entries = [
+                    DataEntryValue::Binary(b"binary".to_vec()),
+                    DataEntryValue::Bool(true),
+                    DataEntryValue::String("string".to_string()),
+                    DataEntryValue::Deleted,
+                ]
+                .into_iter()
+                .zip(first_uid..)
+                .map(|(value, uid)| entry(block_uid, uid, &format!("c{}", uid), value))
+                .collect_vec();
+                ops.copy_data_entries(entries).await
+            })
+        })
+        .await
+        .unwrap();
+
+        let (entries, cursor) = repo
+            .execute(|ops| Box::pin(ops.list_data_entries(ADDRESS, None, 10)))
+            .await
+            .unwrap();
+        let (diesel_entries, diesel_cursor) = diesel_repo
+            .execute(|mut ops| ops.list_data_entries(ADDRESS, None, 10))
+            .unwrap();
+        assert_eq!(values(&entries), values(&diesel_entries));
+        assert_eq!(cursor, diesel_cursor);
+        // the deleted key is left out of the list
+        assert_eq!(
+            values(&entries),
+            vec![
+                ("c4".to_string(), DataEntryValue::Binary(b"binary".to_vec())),
+                ("c5".to_string(), DataEntryValue::Bool(true)),
+                (
+                    "c6".to_string(),
+                    DataEntryValue::String("string".to_string())
+                ),
+                ("j".to_string(), DataEntryValue::Integer(1)),
+                ("k".to_string(), DataEntryValue::Integer(2)),
+            ]
+        );
+
+        let (history, _) = repo
+            .execute(|ops| Box::pin(ops.get_data_entry_history(ADDRESS, "k", None, 10)))
+            .await
+            .unwrap();
+        let (diesel_history, _) = diesel_repo
+            .execute(|mut ops| ops.get_data_entry_history(ADDRESS, "k", None, 10))
+            .unwrap();
+        let versions = |history: &[DataEntryVersion]| {
+            history
+                .iter()
+                .map(|v| {
+                    (
+                        v.uid,
+                        v.height,
+                        v.block_timestamp,
+                        v.data_entry.value.clone(),
+                    )
+                })
+                .collect_vec()
+        };
+        assert_eq!(versions(&history), versions(&diesel_history));
+        assert_eq!(
+            history.iter().map(|v| v.height).collect_vec(),
+            vec![Some(1), Some(2)]
+        );
+
+        let at_height = repo
+            .execute(|ops| Box::pin(ops.get_data_entry_at_height(ADDRESS, "k", 1)))
+            .await
+            .unwrap();
+        assert_eq!(at_height.unwrap().value, DataEntryValue::Integer(1));
+
+        let filters = [FragmentFilter::String(0, "k".to_string())];
+        let (found, _) = repo
+            .execute(|ops| {
+                Box::pin(async move { ops.search_by_fragments(&filters, 10, None).await })
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            values(&found),
+            vec![("k".to_string(), DataEntryValue::Integer(2))]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+    async fn rollback_reopens_the_previous_version() {
+        let db = TestDatabase::create();
+        let repo = async_repo(&db, 2);
+        for height in 1..=3 {
+            append_block(&repo, height, vec!["k".to_string()]).await;
+        }
+
+        let deleted = repo
+            .transaction(|ops| {
+                Box::pin(async move {
+                    let block_uid = ops.get_block_uid("b1").await?.unwrap();
+                    let deleted = ops.rollback_data_entries(&block_uid).await?;
+                    let uids = deleted.iter().map(|de| de.uid).collect_vec();
+                    ops.reopen_superseded_by(&uids).await?;
+                    ops.rollback_blocks_microblocks(&block_uid).await?;
+                    Ok(deleted)
+                })
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            deleted.iter().map(|de| de.uid).sorted().collect_vec(),
+            vec![2, 3]
+        );
+        let diesel_repo = PgDataEntriesRepo::new(db.pool.clone());
+        let live = diesel_repo
+            .execute(|mut ops| ops.get_data_entry(ADDRESS, "k"))
+            .unwrap();
+        assert_eq!(live.unwrap().value, DataEntryValue::Integer(1));
+        assert_eq!(
+            diesel_repo
+                .execute(|mut ops| ops.get_last_height())
+                .unwrap(),
+            Some(1)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+    async fn failed_and_cancelled_transactions_are_rolled_back() {
+        let db = TestDatabase::create();
+        let repo = async_repo(&db, 1);
+
+        let failed = repo
+            .transaction(|ops| {
+                Box::pin(async move {
+                    append_block_uid(ops, "failed").await?;
+                    Err::<(), _>(anyhow!("failed"))
+                })
+            })
+            .await;
+        assert!(failed.is_err());
+
+        // the only connection of the pool is dropped while the transaction waits
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(200),
+            repo.transaction(|ops| {
+                Box::pin(async move {
+                    append_block_uid(ops, "cancelled").await?;
+                    ops.execute("select pg_sleep(10)", &[]).await?;
+                    Ok(())
+                })
+            }),
+        )
+        .await;
+        assert!(cancelled.is_err());
+
+        let block_uids = repo
+            .execute(|ops| {
+                Box::pin(async move {
+                    Ok((
+                        ops.get_block_uid("failed").await?,
+                        ops.get_block_uid("cancelled").await?,
+                    ))
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(block_uids, (None, None));
+    }
+
+    async fn append_block_uid(ops: &mut AsyncPgConnection, id: &str) -> Result<Option<i64>> {
+        Ok(ops
+            .insert_blocks_or_microblocks(&[BlockMicroblock {
+                id: id.to_string(),
+                time_stamp: Some(60_000),
+                height: 1,
+            }])
+            .await?[0])
+    }
+
+    /// Prints the timings of both repos, run it with `--nocapture`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+    async fn benchmark_against_the_diesel_repo() {
+        const BLOCKS: i32 = 200;
+        const KEYS_PER_BLOCK: usize = 20;
+        const READERS: usize = 16;
+        const READS_PER_READER: usize = 200;
+
+        let keys = |height: i32| {
+            (0..KEYS_PER_BLOCK)
+                .map(|key| format!("k{}-{}", height % 10, key))
+                .collect_vec()
+        };
+
+        let db = TestDatabase::create();
+        let repo = Arc::new(async_repo(&db, READERS as u32));
+        let started = Instant::now();
+        for height in 1..=BLOCKS {
+            append_block(&repo, height, keys(height)).await;
+        }
+        let async_appends = started.elapsed();
+
+        let diesel_db = TestDatabase::create();
+        let mut config = postgres_config("state-consumer-test");
+        config.poolsize = READERS as u32;
+        let diesel_repo = Arc::new(PgDataEntriesRepo::new(
+            pool_with_url(&config, diesel_db.url.clone()).unwrap(),
+        ));
+        let started = Instant::now();
+        for height in 1..=BLOCKS {
+            let keys = keys(height);
+            diesel_repo
+                .transaction(|ops| {
+                    let block_uid = ops.insert_blocks_or_microblocks(&[BlockMicroblock {
+                        id: format!("b{}", height),
+                        time_stamp: Some(height as i64 * 60_000),
+                        height,
+                    }])?[0]
+                        .unwrap();
+                    let first_uid = ops.reserve_update_uids(keys.len() as i64)?;
+                    ops.close_superseded_by(
+                        &keys
+                            .iter()
+                            .zip(first_uid..)
+                            .map(|(key, uid)| DataEntryUpdate {
+                                superseded_by: uid,
+                                address: ADDRESS.to_string(),
+                                key: key.clone(),
+                            })
+                            .collect_vec(),
+                    )?;
+                    ops.insert_data_entries(
+                        keys.iter().zip(first_uid..).map(|(key, uid)| {
+                            entry(block_uid, uid, key, DataEntryValue::Integer(height as i64))
+                        }),
+                        2,
+                    )
+                })
+                .unwrap();
+        }
+        let diesel_appends = started.elapsed();
+
+        let started = Instant::now();
+        let readers = (0..READERS)
+            .map(|reader| {
+                let repo = repo.clone();
+                tokio::spawn(async move {
+                    for read in 0..READS_PER_READER {
+                        let key = format!("k{}-{}", read % 10, reader);
+                        repo.execute(move |ops| {
+                            Box::pin(async move { ops.get_data_entry(ADDRESS, &key).await })
+                        })
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    }
+                })
+            })
+            .collect_vec();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        let async_reads = started.elapsed();
+
+        let started = Instant::now();
+        let readers = (0..READERS)
+            .map(|reader| {
+                let repo = diesel_repo.clone();
+                tokio::spawn(async move {
+                    for read in 0..READS_PER_READER {
+                        let key = format!("k{}-{}", read % 10, reader);
+                        repo.execute(|mut ops| ops.get_data_entry(ADDRESS, &key))
+                            .unwrap()
+                            .unwrap();
+                    }
+                })
+            })
+            .collect_vec();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        let diesel_reads = started.elapsed();
+
+        println!(
+            "{} blocks of {} writes: async {:?}, diesel {:?}",
+            BLOCKS, KEYS_PER_BLOCK, async_appends, diesel_appends
+        );
+        println!(
+            "{} readers of {} reads: async {:?}, diesel {:?}",
+            READERS, READS_PER_READER, async_reads, diesel_reads
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::logging::info;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{DataEntriesRepo, DataEntriesRepoOperations};
+
+/// Uids updated in one transaction.
+const VALUE_TYPE_BACKFILL_BATCH_SIZE: i64 = 10000;
+/// Pause between the batches, so the backfill does not compete with the consumer.
+const VALUE_TYPE_BACKFILL_PAUSE: Duration = Duration::from_millis(100);
+/// Batches between two progress logs.
+const VALUE_TYPE_BACKFILL_LOG_BATCHES: i64 = 100;
+
+/// Set `value_type` of the entries written before the column existed, then validate
+/// its constraint. Entries written since then always have it, so only the uids up to
+/// the last entry at the start are visited. An interrupted backfill starts over on the next
+/// start, the batches done before are skipped quickly, since they have nothing to update.
+pub async fn backfill_value_types<U: DataEntriesRepo>(dbw: Arc<U>) -> Result<()> {
+    if dbw.execute(|mut ops| ops.is_value_type_validated())? {
+        return Ok(());
+    }
+
+    let last_uid = dbw
+        .execute(|mut ops| ops.get_last_data_entry_uid())?
+        .unwrap_or(0);
+    info!(
+        "Backfilling value types of data entries up to uid {}",
+        last_uid
+    );
+
+    let mut updated = 0;
+    let mut batches = 0;
+    let mut from_uid = 0;
+    while from_uid <= last_uid {
+        let to_uid = from_uid + VALUE_TYPE_BACKFILL_BATCH_SIZE;
+        updated += dbw.transaction(|ops| ops.backfill_value_types(from_uid, to_uid))?;
+        from_uid = to_uid;
+
+        batches += 1;
+        if batches % VALUE_TYPE_BACKFILL_LOG_BATCHES == 0 {
+            info!(
+                "Value types were backfilled up to uid {} of {}, {} data entries updated",
+                from_uid, last_uid, updated
+            );
+        }
+
+        tokio::time::sleep(VALUE_TYPE_BACKFILL_PAUSE).await;
+    }
+
+    dbw.execute(|mut ops| ops.validate_value_type())?;
+    info!(
+        "Value types were backfilled, {} data entries updated",
+        updated
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_entries::repo::PgDataEntriesRepo;
+    use crate::data_entries::{
+        VALUE_TYPE_BINARY, VALUE_TYPE_BOOL, VALUE_TYPE_DELETED, VALUE_TYPE_INTEGER,
+        VALUE_TYPE_STRING,
+    };
+    use crate::db::testing::TestDatabase;
+    use crate::schema::data_entries;
+    use diesel::connection::SimpleConnection;
+    use diesel::prelude::*;
+
+    /// Entries written before the column existed have no `value_type`,
+    /// the constraint is added back as the migration adds it.
+    const OLD_ENTRIES: &str = r#"
+        alter table data_entries drop constraint data_entries_value_type_not_null;
+        insert into blocks_microblocks (id, height, time_stamp) values ('b1', 1, 60000);
+        insert into data_entries (block_uid, uid, superseded_by, address, key, transaction_id,
+                value_binary, value_bool, value_integer, value_string, value_type)
+            select b.uid, v.uid, 9223372036854775806, 'a', v.key, 'tx', v.value_binary, v.value_bool,
+                v.value_integer, v.value_string, null
+            from blocks_microblocks b,
+                (values (1, 'binary', 'AQ==', null::boolean, null::bigint, null),
+                        (2, 'bool', null, true, null, null),
+                        (3, 'integer', null, null, 1, null),
+                        (15000, 'string', null, null, null, 's'),
+                        (25000, 'deleted', null, null, null, null))
+                    as v(uid, key, value_binary, value_bool, value_integer, value_string);
+        alter table data_entries add constraint data_entries_value_type_not_null
+            check (value_type is not null) not valid;
+    "#;
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+    async fn value_types_of_old_entries_are_set_in_batches_and_validated() {
+        let db = TestDatabase::create();
+        db.pool.get().unwrap().batch_execute(OLD_ENTRIES).unwrap();
+        let repo = Arc::new(PgDataEntriesRepo::new(db.pool.clone()));
+        assert!(!repo
+            .execute(|mut ops| ops.is_value_type_validated())
+            .unwrap());
+
+        backfill_value_types(repo.clone()).await.unwrap();
+
+        let value_types = repo
+            .execute(|mut conn| {
+                Ok(data_entries::table
+                    .select(data_entries::value_type)
+                    .order(data_entries::uid)
+                    .load::<Option<String>>(&mut conn)?)
+            })
+            .unwrap();
+        assert_eq!(
+            value_types,
+            [
+                VALUE_TYPE_BINARY,
+                VALUE_TYPE_BOOL,
+                VALUE_TYPE_INTEGER,
+                VALUE_TYPE_STRING,
+                VALUE_TYPE_DELETED
+            ]
+            .map(|value_type| Some(value_type.to_string()))
+        );
+        assert!(repo
+            .execute(|mut ops| ops.is_value_type_validated())
+            .unwrap());
+    }
+}
@@ -1,22 +1,38 @@
-use anyhow::{Error, Result};
+use crate::logging::{error, info, warn};
+use anyhow::{Context, Error, Result};
 use itertools::Itertools;
-use std::collections::HashMap;
+use prometheus::IntGauge;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use wavesexchange_log::info;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Receiver;
+use tokio_util::sync::CancellationToken;
 
 use super::{
-    BlockMicroblock, BlockMicroblockAppend, BlockchainUpdate, DataEntriesRepo, DataEntriesSource,
-    DataEntry, DataEntryUpdate, DeletedDataEntry, InsertableDataEntry, FRAGMENT_SEPARATOR,
-    INTEGER_DESCRIPTOR, STRING_DESCRIPTOR,
+    BlockMicroblock, BlockMicroblockAppend, BlockchainUpdate, BlockchainUpdatesWithLastHeight,
+    ConsumerState, DataEntriesRepo, DataEntriesSource, DataEntry, DataEntryUpdate, DataEntryValue,
+    DeletedDataEntry, Fragments, InsertableContractEvent, InsertableDataEntry, InsertableLease,
+    InsertableTransaction, InsertableTransfer, BINARY_DESCRIPTOR, FRAGMENT_SEPARATOR,
+    INTEGER_DESCRIPTOR, LIVE_SUPERSEDED_BY, STRING_DESCRIPTOR,
 };
+use crate::data_entries::updates::ParseErrorAction;
 use crate::data_entries::DataEntriesRepoOperations;
-use crate::error::AppError;
+use crate::error::{panic_message, AppError};
+use crate::metrics::{
+    BATCH_PROCESS_SECONDS, BATCH_RECEIVE_SECONDS, BLOCKS_TOTAL, CONSUMER_MODE,
+    DATA_ENTRIES_BY_ADDRESS_TOTAL, DATA_ENTRIES_TOTAL, LAST_HEIGHT, MICROBLOCKS_TOTAL,
+    NEXT_UPDATE_UID, PARSE_ERRORS_TOTAL, ROLLBACKS_TOTAL, SYNC_PROGRESS_RATIO,
+};
 
 enum UpdatesItem {
     Blocks(Vec<BlockMicroblockAppend>),
     Microblock(BlockMicroblockAppend),
-    Rollback(String),
+    Rollback(Vec<(String, u32)>),
 }
 
 #[derive(Debug)]
@@ -25,367 +41,3773 @@ struct BlockUidWithDataEntry {
     data_entry: DataEntry,
 }
 
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub updates_per_request: usize,
+    pub max_wait_time_in_secs: u64,
+    pub start_rollback_depth: u32,
+    /// A rollback to a block below this height is refused, so it is resolved by an operator.
+    pub min_rollback_height: u32,
+    pub track_last_writes: bool,
+    pub implicit_rollbacks: bool,
+    pub use_copy_insert: bool,
+    /// Number of data entries inserted by one statement, unless `COPY` is used.
+    pub insert_chunk_size: usize,
+    /// Number of keys closed by one `close_superseded_by` statement.
+    pub close_superseded_by_chunk_size: usize,
+    /// Received batches are committed together, up to this many of them.
+    /// Nothing of a group is durable until it is committed, so a crash loses the whole group,
+    /// and it is fetched again from the stored cursor on start.
+    pub commit_every_n_batches: usize,
+    /// A group is committed once its first batch has waited this long.
+    pub commit_max_wait_secs: u64,
+    /// The consumer is syncing while it handles more blocks a minute than this, live otherwise.
+    pub live_max_blocks_per_minute: f64,
+    /// Repeated writes of the same value to a key within a block are stored once.
+    pub collapse_identical_writes: bool,
+    /// Only writes of the same transaction are collapsed, so every writing transaction is kept.
+    pub collapse_within_transaction: bool,
+    /// Writes of an empty string are not stored, deletions are stored anyway.
+    pub skip_empty_string_values: bool,
+    /// Binary values which are valid UTF-8 are split into value fragments like strings.
+    pub decode_binary_value_fragments: bool,
+    /// Addresses whose data entries are stored, an empty filter stores all of them.
+    pub address_filter: HashSet<String>,
+    /// Case-sensitive prefixes of stored keys, an empty filter stores all keys.
+    pub key_prefix_filter: Vec<String>,
+    /// Addresses whose inserted data entries are counted separately.
+    pub metrics_address_allowlist: HashSet<String>,
+    /// The consumer stops after this height when it is set.
+    pub sync_to_height: Option<u32>,
+    /// Everything above this height is deleted on start, so it is indexed again.
+    /// The reset is recorded and only done once, a later start resumes from the cursor.
+    pub reset_to_height: Option<u32>,
+    pub on_deep_rollback: DeepRollbackAction,
+    /// Also applies to a batch whose handling panicked, a skipped batch is not written at all.
+    pub on_parse_error: ParseErrorAction,
+    /// Updates are parsed and logged, but nothing is written to the database.
+    pub dry_run: bool,
+    /// The uid sequence is advanced past the stored data entries on start if it is behind.
+    pub reconcile_uid_seq_on_startup: bool,
+    /// A failed write to the secondary database stops the consumer instead of being logged.
+    pub secondary_failures_are_fatal: bool,
+}
+
+/// State the consumer shares with the rest of the service.
+#[derive(Clone)]
+pub struct Signals {
+    /// Epoch milliseconds of the last loop iteration.
+    pub heartbeat: Arc<AtomicI64>,
+    /// Set whenever a batch is handled, cleared by `start_degraded` while the consumer is down.
+    pub consuming: Arc<AtomicBool>,
+    pub syncing: Arc<AtomicBool>,
+    /// Entries of every committed batch are published here.
+    pub changes: broadcast::Sender<Vec<DataEntry>>,
+    pub shutdown: CancellationToken,
+}
+
+/// What to do when a rollback goes below the blocks which are kept in the database.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeepRollbackAction {
+    /// Roll back to the highest kept block below the target and continue from there.
+    Resync,
+    /// Stop the consumer, so the database can be restored by an operator.
+    Fail,
+}
+
+impl Settings {
+    /// When both filters are set, a data entry has to match both of them.
+    fn accepts(&self, data_entry: &DataEntry) -> bool {
+        if self.skip_empty_string_values {
+            if let DataEntryValue::String(value) = &data_entry.value {
+                if value.is_empty() {
+                    return false;
+                }
+            }
+        }
+
+        let address_matches =
+            self.address_filter.is_empty() || self.address_filter.contains(&data_entry.address);
+        let key_matches = self.key_prefix_filter.is_empty()
+            || self
+                .key_prefix_filter
+                .iter()
+                .any(|prefix| data_entry.key.starts_with(prefix.as_str()));
+        address_matches && key_matches
+    }
+}
+
 pub async fn start<T, U>(
     updates_src: T,
     dbw: Arc<U>,
-    updates_per_request: usize,
-    max_wait_time_in_secs: u64,
-    start_rollback_depth: u32,
+    secondary: Option<Arc<U>>,
+    settings: Settings,
+    signals: Signals,
 ) -> Result<()>
 where
     T: DataEntriesSource + Send + Sync + 'static,
     U: DataEntriesRepo,
 {
-    let starting_from_height =
-        dbw.transaction(|ops| match ops.get_handled_height(start_rollback_depth)? {
-            Some(prev_handled_height) => {
-                info!(
-                    "rollback database to height: {}",
-                    prev_handled_height.height
-                );
+    let Signals {
+        heartbeat,
+        consuming,
+        syncing,
+        changes,
+        shutdown,
+    } = signals;
 
-                rollback(ops, prev_handled_height.uid)?;
-                Ok(prev_handled_height.height as u32 + 1)
-            }
-            None => Ok(1u32),
-        })?;
+    let starting_from_height = starting_height(dbw.as_ref(), &settings)?;
+
+    // the secondary is rolled back the same way, so it continues from the same blocks
+    if let Some(secondary) = &secondary {
+        match starting_height(secondary.as_ref(), &settings) {
+            Ok(height) if height != starting_from_height => warn!(
+                "Secondary database starts from height {}, primary from {}",
+                height, starting_from_height
+            ),
+            Ok(_) => (),
+            Err(err) if settings.secondary_failures_are_fatal => return Err(err),
+            Err(err) => error!("Secondary database failed: {:?}", err),
+        }
+    }
+
+    if let Some(sync_to_height) = settings.sync_to_height {
+        if starting_from_height > sync_to_height {
+            info!("Target height {} is already reached", sync_to_height);
+            return Ok(());
+        }
+    }
+
+    if settings.reconcile_uid_seq_on_startup && !settings.dry_run {
+        dbw.transaction(reconcile_uid_seq)?;
+    }
+
+    let next_update_uid = dbw.execute(|mut ops| ops.get_next_update_uid())?;
+    NEXT_UPDATE_UID.set(next_update_uid);
 
     info!(
-        "Fetching block updates from height {}.",
-        starting_from_height
+        "Fetching block updates from height {}. Next data entry uid is {}.",
+        starting_from_height, next_update_uid
     );
-    let max_duration = Duration::from_secs(max_wait_time_in_secs);
+    let max_duration = Duration::from_secs(settings.max_wait_time_in_secs);
+
+    let mut sync_progress = settings
+        .sync_to_height
+        .map(|target_height| SyncProgress::new(starting_from_height, target_height));
+
+    let mut mode = ModeTracker::new(settings.live_max_blocks_per_minute, CONSUMER_MODE.clone());
 
     let mut rx = updates_src
-        .stream(starting_from_height, updates_per_request, max_duration)
+        .stream(
+            starting_from_height,
+            settings.sync_to_height,
+            settings.updates_per_request,
+            max_duration,
+        )
         .await?;
 
     loop {
+        // epoch milliseconds of the last iteration, so a stuck loop can be noticed
+        heartbeat.store(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64),
+            Ordering::Relaxed,
+        );
+
         let mut start = Instant::now();
 
-        let updates_with_height = rx.recv().await.ok_or(Error::new(AppError::StreamClosed(
-            "GRPC Stream was closed by the server".to_string(),
-        )))?;
+        // shutdown is only checked between batches, so a started transaction is always finished
+        let updates_with_height = select! {
+            _ = shutdown.cancelled() => {
+                info!("Shutdown was requested, stopping the consumer");
+                return Ok(());
+            }
+            updates = rx.recv() => updates.ok_or(Error::new(AppError::StreamClosed(
+                "GRPC Stream was closed by the server".to_string(),
+            )))??,
+        };
+
+        let updates_with_height =
+            receive_more_batches(&mut rx, updates_with_height, &settings, &shutdown).await?;
 
+        let batch_size = updates_with_height.updates.len();
         info!(
             "{} block updates were received in {:?}",
-            updates_with_height.updates.len(),
-            start.elapsed()
+            batch_size,
+            start.elapsed();
+            "height" => updates_with_height.last_height,
+            "batch_size" => batch_size
         );
+        BATCH_RECEIVE_SECONDS.observe(start.elapsed().as_secs_f64());
 
         start = Instant::now();
 
-        dbw.transaction(|ops| {
-            updates_with_height
-                .updates
-                .into_iter()
-                .fold::<&mut Vec<UpdatesItem>, _>(&mut vec![], |acc, cur| match cur {
-                    BlockchainUpdate::Block(b) => {
-                        let len = acc.len();
-                        if acc.len() > 0 {
-                            match acc.iter_mut().nth(len as usize - 1).unwrap() {
-                                UpdatesItem::Blocks(v) => {
-                                    v.push(b);
-                                    acc
-                                }
-                                UpdatesItem::Microblock(_) | UpdatesItem::Rollback(_) => {
-                                    acc.push(UpdatesItem::Blocks(vec![b]));
-                                    acc
-                                }
-                            }
-                        } else {
-                            acc.push(UpdatesItem::Blocks(vec![b]));
-                            acc
+        if settings.dry_run {
+            log_dry_run(&updates_with_height);
+        } else {
+            let secondary_updates = secondary
+                .as_ref()
+                .map(|_| updates_with_height.updates.clone());
+
+            // entries are only copied when someone listens to them,
+            // by update, since the skipped updates are not sent
+            let written_entries = (changes.receiver_count() > 0).then(|| {
+                updates_with_height
+                    .updates
+                    .iter()
+                    .map(|update| match update {
+                        BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => {
+                            append
+                                .data_entries
+                                .iter()
+                                .filter(|de| settings.accepts(de))
+                                .cloned()
+                                .collect_vec()
                         }
-                    }
-                    BlockchainUpdate::Microblock(mba) => {
-                        acc.push(UpdatesItem::Microblock(mba));
-                        acc
-                    }
-                    BlockchainUpdate::Rollback(sig) => {
-                        acc.push(UpdatesItem::Rollback(sig));
-                        acc
-                    }
-                })
-                .into_iter()
-                .try_fold((), |_, update_item| match update_item {
-                    UpdatesItem::Blocks(bs) => {
-                        squash_microblocks(ops)?;
-                        append_blocks_or_microblocks(ops, bs.as_ref())
-                    }
-                    UpdatesItem::Microblock(mba) => {
-                        append_blocks_or_microblocks(ops, &vec![mba.to_owned()])
-                    }
-                    UpdatesItem::Rollback(sig) => {
-                        let block_uid = ops.get_block_uid(&sig)?;
-                        rollback(ops, block_uid)
-                    }
-                })?;
+                        BlockchainUpdate::Rollback(..) => vec![],
+                    })
+                    .collect_vec()
+            });
+
+            let written = write_batch(
+                dbw.as_ref(),
+                updates_with_height.updates,
+                updates_with_height.last_height,
+                &settings,
+            )?;
+            // the metrics follow the primary only, once the batch is committed
+            written.counts.report();
+            let last_data_entry_uid = written.last_data_entry_uid;
 
             info!(
                 "Updates were processed in {:?}. Last updated height is {}.",
                 start.elapsed(),
-                updates_with_height.last_height
+                updates_with_height.last_height;
+                "height" => updates_with_height.last_height,
+                "batch_size" => batch_size
             );
+            BATCH_PROCESS_SECONDS.observe(start.elapsed().as_secs_f64());
 
-            Ok(())
-        })?;
-    }
-}
+            // sending never waits, slow subscribers lag behind instead
+            let written_entries = written_entries.map(|entries| {
+                entries
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(position, _)| !written.skipped.contains(position))
+                    .flat_map(|(_, entries)| entries)
+                    .collect_vec()
+            });
+            if let Some(entries) = written_entries.filter(|entries| !entries.is_empty()) {
+                let _ = changes.send(entries);
+            }
 
-fn extract_string_fragment(values: &Vec<(&str, &str)>, position: usize) -> Option<String> {
-    values.get(position).map_or(None, |(t, v)| {
-        if *t == STRING_DESCRIPTOR {
-            Some(v.to_string())
-        } else {
-            None
+            if let (Some(secondary), Some(updates)) = (&secondary, secondary_updates) {
+                let result = write_batch(
+                    secondary.as_ref(),
+                    updates,
+                    updates_with_height.last_height,
+                    &settings,
+                )
+                .map(|written| written.last_data_entry_uid);
+                match result {
+                    Ok(secondary_uid) if secondary_uid != last_data_entry_uid => warn!(
+                        "Secondary database diverged at height {}: last data entry uid is {:?}, primary has {:?}",
+                        updates_with_height.last_height, secondary_uid, last_data_entry_uid
+                    ),
+                    Ok(_) => (),
+                    Err(err) if settings.secondary_failures_are_fatal => return Err(err),
+                    Err(err) => error!("Secondary database failed: {:?}", err),
+                }
+            }
         }
-    })
-}
 
-fn extract_integer_fragment(values: &Vec<(&str, &str)>, position: usize) -> Option<i64> {
-    values.get(position).map_or(None, |(t, v)| {
-        if *t == INTEGER_DESCRIPTOR {
-            v.parse().ok()
-        } else {
-            None
+        LAST_HEIGHT.set(updates_with_height.last_height as i64);
+        consuming.store(true, Ordering::Relaxed);
+
+        if let Some(sync_progress) = sync_progress.as_mut() {
+            sync_progress.update(updates_with_height.last_height);
         }
-    })
+
+        let is_syncing = mode.update(updates_with_height.last_height);
+        if syncing.swap(is_syncing, Ordering::Relaxed) != is_syncing {
+            info!(
+                "Consumer is {} at height {}",
+                if is_syncing { "syncing" } else { "live" },
+                updates_with_height.last_height
+            );
+        }
+
+        if let Some(sync_to_height) = settings.sync_to_height {
+            if updates_with_height.last_height >= sync_to_height {
+                info!("Reached target height {}", sync_to_height);
+                return Ok(());
+            }
+        }
+    }
 }
 
-fn rollback<U: DataEntriesRepoOperations>(dbw: &mut U, block_uid: i64) -> Result<()> {
-    let deletes = dbw.rollback_data_entries(&block_uid)?;
+/// Delays between the restarts of a failed consumer in degraded mode.
+const RESTART_MIN_DELAY: Duration = Duration::from_secs(1);
+const RESTART_MAX_DELAY: Duration = Duration::from_secs(60);
 
-    let mut grouped_deletes: HashMap<DeletedDataEntry, Vec<DeletedDataEntry>> = HashMap::new();
+/// Run the consumer in degraded mode: a failed consumer does not stop the service, so the api
+/// keeps serving reads from the database, and it is restarted with a growing delay instead.
+/// `consuming` is cleared when it fails and set again once a restarted consumer handles a batch.
+pub async fn start_degraded<T, U>(
+    updates_src: T,
+    dbw: Arc<U>,
+    secondary: Option<Arc<U>>,
+    settings: Settings,
+    signals: Signals,
+) -> Result<()>
+where
+    T: DataEntriesSource + Clone + Send + Sync + 'static,
+    U: DataEntriesRepo,
+{
+    let mut delay = RESTART_MIN_DELAY;
+    loop {
+        let result = start(
+            updates_src.clone(),
+            dbw.clone(),
+            secondary.clone(),
+            settings.clone(),
+            signals.clone(),
+        )
+        .await;
 
-    deletes.into_iter().for_each(|item| {
-        let group = grouped_deletes.entry(item.clone()).or_insert(vec![]);
-        group.push(item);
-    });
+        let err = match result {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
 
-    let lowest_deleted_uids: Vec<i64> = grouped_deletes
-        .into_iter()
-        .filter_map(|(_, group)| group.into_iter().min_by_key(|i| i.uid).map(|i| i.uid))
-        .collect();
+        // a consumer which has recovered since the previous failure starts the delays over
+        if signals.consuming.swap(false, Ordering::Relaxed) {
+            delay = RESTART_MIN_DELAY;
+        }
+        error!("{:#}", err);
+        warn!(
+            "Consumer is down, serving in degraded mode, restarting it in {:?}",
+            delay
+        );
 
-    dbw.reopen_superseded_by(&lowest_deleted_uids)?;
+        select! {
+            _ = signals.shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(delay) => (),
+        }
+        delay = (delay * 2).min(RESTART_MAX_DELAY);
+    }
+}
 
-    dbw.rollback_blocks_microblocks(&block_uid)
+struct WrittenBatch {
+    last_data_entry_uid: Option<i64>,
+    counts: BatchCounts,
+    /// Positions of the updates skipped by `ParseErrorAction::Skip`.
+    skipped: Vec<usize>,
 }
 
-fn append_blocks_or_microblocks<U: DataEntriesRepoOperations>(
-    dbw: &mut U,
-    appends: &Vec<BlockMicroblockAppend>,
-) -> Result<()> {
-    let block_uids = dbw.insert_blocks_or_microblocks(
-        &appends
-            .into_iter()
-            .map(|append| BlockMicroblock {
-                id: append.id.clone(),
-                height: append.height as i32,
-                time_stamp: append.time_stamp,
-            })
-            .collect_vec(),
-    )?;
+/// Write the updates in a single transaction.
+///
+/// A panic while handling them, e.g. in the parsing of fragments, is caught, so a single
+/// pathological block is handled by `on_parse_error` instead of taking the process down.
+/// The unwinding drops the pooled connection with its open transaction, and the pool
+/// discards a connection dropped by a panicking thread, so nothing of the batch is written.
+/// `catch_unwind` requires the closure to be unwind safe: the updates are moved into it and
+/// the repo is only shared, so nothing can be observed half-changed, which makes asserting it sound.
+fn write_batch<U: DataEntriesRepo>(
+    dbw: &U,
+    updates: Vec<BlockchainUpdate>,
+    last_height: u32,
+    settings: &Settings,
+) -> Result<WrittenBatch> {
+    // kept to be retried one by one, since a reconnect does not receive the batch again
+    let retried = (settings.on_parse_error == ParseErrorAction::Skip).then(|| updates.clone());
 
-    let data_entries = block_uids
-        .iter()
-        .zip(appends)
-        .filter(|(_, append)| append.data_entries.len() > 0)
-        .flat_map(|(block_uid, append)| {
-            append
-                .data_entries
-                .clone()
-                .into_iter()
-                .map(|de| BlockUidWithDataEntry {
-                    block_uid: block_uid.to_owned(),
-                    data_entry: de,
-                })
-                .collect_vec()
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        dbw.transaction(|ops| {
+            let counts = handle_updates(ops, updates, settings)?;
+            Ok((ops.get_last_data_entry_uid()?, counts))
         })
-        .collect_vec();
+    }));
 
-    if data_entries.len() > 0 {
-        append_data_entries(dbw, data_entries)
-    } else {
-        Ok(())
+    match (result, retried) {
+        (Ok(written), _) => written.map(|(last_data_entry_uid, counts)| WrittenBatch {
+            last_data_entry_uid,
+            counts,
+            skipped: vec![],
+        }),
+        (Err(panic), Some(retried)) => {
+            warn!(
+                "Handling of updates up to height {} panicked, retrying them one by one: {}",
+                last_height,
+                panic_message(panic.as_ref());
+                "height" => last_height
+            );
+            write_one_by_one(dbw, retried, settings)
+        }
+        (Err(panic), None) => {
+            PARSE_ERRORS_TOTAL.inc();
+            Err(Error::new(AppError::InvalidMessage(format!(
+                "handling of updates up to height {} panicked: {}",
+                last_height,
+                panic_message(panic.as_ref())
+            ))))
+        }
     }
 }
 
-fn append_data_entries<U: DataEntriesRepoOperations>(
-    dbw: &mut U,
-    updates: Vec<BlockUidWithDataEntry>,
-) -> Result<()> {
-    let next_uid = dbw.get_next_update_uid()?;
-    let updates_count = updates.len() as i64;
+/// Write each of the updates in its own transaction, so only the updates whose handling
+/// panics are skipped. The cursor is moved past a skipped update, so a restart does not
+/// read it again.
+fn write_one_by_one<U: DataEntriesRepo>(
+    dbw: &U,
+    updates: Vec<BlockchainUpdate>,
+    settings: &Settings,
+) -> Result<WrittenBatch> {
+    let mut written = WrittenBatch {
+        last_data_entry_uid: None,
+        counts: BatchCounts::default(),
+        skipped: vec![],
+    };
 
-    let entries = updates.into_iter().enumerate().map(
-        |(
-            idx,
-            BlockUidWithDataEntry {
-                block_uid,
-                data_entry,
-            },
-        )| {
-            let key_fragments = split_to_fragments(&data_entry.key);
-            let value_fragments = match data_entry.value_string.as_ref() {
-                Some(value) => split_to_fragments(value),
-                _ => vec![],
-            };
-            InsertableDataEntry {
-                block_uid: block_uid,
-                transaction_id: data_entry.transaction_id.clone(),
-                uid: next_uid + idx as i64,
-                superseded_by: -1,
-                address: data_entry.address.clone(),
-                key: data_entry.key.clone(),
-                value_binary: data_entry.value_binary.clone(),
-                value_bool: data_entry.value_bool,
-                value_integer: data_entry.value_integer,
-                value_string: data_entry.value_string.clone(),
-                fragment_0_integer: extract_integer_fragment(&key_fragments, 0),
-                fragment_0_string: extract_string_fragment(&key_fragments, 0),
-                fragment_1_integer: extract_integer_fragment(&key_fragments, 1),
-                fragment_1_string: extract_string_fragment(&key_fragments, 1),
-                fragment_2_integer: extract_integer_fragment(&key_fragments, 2),
-                fragment_2_string: extract_string_fragment(&key_fragments, 2),
-                fragment_3_integer: extract_integer_fragment(&key_fragments, 3),
-                fragment_3_string: extract_string_fragment(&key_fragments, 3),
-                fragment_4_integer: extract_integer_fragment(&key_fragments, 4),
-                fragment_4_string: extract_string_fragment(&key_fragments, 4),
-                fragment_5_integer: extract_integer_fragment(&key_fragments, 5),
-                fragment_5_string: extract_string_fragment(&key_fragments, 5),
-                fragment_6_integer: extract_integer_fragment(&key_fragments, 6),
-                fragment_6_string: extract_string_fragment(&key_fragments, 6),
-                fragment_7_integer: extract_integer_fragment(&key_fragments, 7),
-                fragment_7_string: extract_string_fragment(&key_fragments, 7),
-                fragment_8_integer: extract_integer_fragment(&key_fragments, 8),
-                fragment_8_string: extract_string_fragment(&key_fragments, 8),
-                fragment_9_integer: extract_integer_fragment(&key_fragments, 9),
-                fragment_9_string: extract_string_fragment(&key_fragments, 9),
-                fragment_10_integer: extract_integer_fragment(&key_fragments, 10),
-                fragment_10_string: extract_string_fragment(&key_fragments, 10),
-                value_fragment_0_integer: extract_integer_fragment(&value_fragments, 0),
-                value_fragment_0_string: extract_string_fragment(&value_fragments, 0),
-                value_fragment_1_integer: extract_integer_fragment(&value_fragments, 1),
-                value_fragment_1_string: extract_string_fragment(&value_fragments, 1),
-                value_fragment_2_integer: extract_integer_fragment(&value_fragments, 2),
-                value_fragment_2_string: extract_string_fragment(&value_fragments, 2),
-                value_fragment_3_integer: extract_integer_fragment(&value_fragments, 3),
-                value_fragment_3_string: extract_string_fragment(&value_fragments, 3),
-                value_fragment_4_integer: extract_integer_fragment(&value_fragments, 4),
-                value_fragment_4_string: extract_string_fragment(&value_fragments, 4),
-                value_fragment_5_integer: extract_integer_fragment(&value_fragments, 5),
-                value_fragment_5_string: extract_string_fragment(&value_fragments, 5),
-                value_fragment_6_integer: extract_integer_fragment(&value_fragments, 6),
-                value_fragment_6_string: extract_string_fragment(&value_fragments, 6),
-                value_fragment_7_integer: extract_integer_fragment(&value_fragments, 7),
-                value_fragment_7_string: extract_string_fragment(&value_fragments, 7),
-                value_fragment_8_integer: extract_integer_fragment(&value_fragments, 8),
-                value_fragment_8_string: extract_string_fragment(&value_fragments, 8),
-                value_fragment_9_integer: extract_integer_fragment(&value_fragments, 9),
-                value_fragment_9_string: extract_string_fragment(&value_fragments, 9),
-                value_fragment_10_integer: extract_integer_fragment(&value_fragments, 10),
-                value_fragment_10_string: extract_string_fragment(&value_fragments, 10),
+    for (position, update) in updates.into_iter().enumerate() {
+        let height = update_height(&update);
+        let id = match &update {
+            BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => {
+                append.id.clone()
             }
-        },
-    );
+            BlockchainUpdate::Rollback(id, _) => id.clone(),
+        };
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            dbw.transaction(|ops| {
+                let counts = handle_updates(ops, vec![update], settings)?;
+                Ok((ops.get_last_data_entry_uid()?, counts))
+            })
+        }));
 
-    let mut grouped_updates: HashMap<InsertableDataEntry, Vec<InsertableDataEntry>> =
-        HashMap::new();
+        match result {
+            Ok(result) => {
+                let (last_data_entry_uid, counts) = result?;
+                written.last_data_entry_uid = last_data_entry_uid;
+                written.counts.add(counts);
+            }
+            Err(panic) => {
+                error!(
+                    "Skipping update {} at height {} whose handling panicked: {}",
+                    id,
+                    height,
+                    panic_message(panic.as_ref());
+                    "height" => height
+                );
+                written.counts.parse_errors += 1;
+                written.skipped.push(position);
+                written.last_data_entry_uid = dbw.transaction(|ops| {
+                    let last_uid = last_stored_uid(ops)?;
+                    store_consumer_state(ops, height, last_uid)?;
+                    ops.get_last_data_entry_uid()
+                })?;
+            }
+        }
+    }
 
-    entries.for_each(|item| {
-        let group = grouped_updates.entry(item.clone()).or_insert(vec![]);
-        group.push(item);
-    });
+    Ok(written)
+}
 
-    let grouped_updates = grouped_updates.into_iter().collect_vec();
+/// Blocks handled between two progress logs of a sync.
+const SYNC_PROGRESS_LOG_BLOCKS: u32 = 10000;
 
-    let grouped_updates_with_uids_superseded_by = grouped_updates
-        .into_iter()
-        .map(|(key, group)| {
-            let mut updates = group
-                .into_iter()
-                .sorted_by_key(|item| item.uid)
-                .collect::<Vec<InsertableDataEntry>>();
+/// Progress of a sync up to `sync_to_height`, the ETA is estimated from the throughput
+/// since the previous log, so it follows the block sizes of the recent history.
+struct SyncProgress {
+    start_height: u32,
+    target_height: u32,
+    logged_height: u32,
+    logged_at: Instant,
+}
 
-            let mut last_uid = std::i64::MAX - 1;
-            (
-                key,
-                updates
-                    .as_mut_slice()
-                    .iter_mut()
-                    .rev()
-                    .map(|cur| {
-                        cur.superseded_by = last_uid;
-                        last_uid = cur.uid;
-                        cur.to_owned()
-                    })
-                    .sorted_by_key(|item| item.uid)
-                    .collect(),
-            )
-        })
-        .collect::<Vec<(InsertableDataEntry, Vec<InsertableDataEntry>)>>();
+impl SyncProgress {
+    fn new(start_height: u32, target_height: u32) -> Self {
+        SyncProgress {
+            start_height,
+            target_height,
+            logged_height: start_height,
+            logged_at: Instant::now(),
+        }
+    }
 
-    // First uid for each asset in a new batch. This value closes superseded_by of previous updates.
-    let first_uids: Vec<DataEntryUpdate> = grouped_updates_with_uids_superseded_by
-        .iter()
-        .map(|(_, group)| {
-            let first = group.iter().next().unwrap().clone();
-            DataEntryUpdate {
-                address: first.address,
-                key: first.key,
-                superseded_by: first.uid,
-            }
-        })
-        .collect();
+    fn update(&mut self, height: u32) {
+        let ratio = self.ratio(height);
+        SYNC_PROGRESS_RATIO.set(ratio);
 
-    dbw.close_superseded_by(&first_uids)?;
+        // the height goes down after a rollback, the window starts again from there
+        if height < self.logged_height {
+            self.logged_height = height;
+            self.logged_at = Instant::now();
+            return;
+        }
 
-    let updates_with_uids_superseded_by = &grouped_updates_with_uids_superseded_by
-        .clone()
-        .into_iter()
-        .flat_map(|(_, v)| v)
-        .sorted_by_key(|de| de.uid)
-        .collect_vec();
+        if height - self.logged_height < SYNC_PROGRESS_LOG_BLOCKS && height < self.target_height {
+            return;
+        }
+
+        let eta = eta(
+            height - self.logged_height,
+            self.logged_at.elapsed(),
+            self.target_height.saturating_sub(height),
+        );
+        info!(
+            "Synced {:.2}% up to height {}, at height {}, ETA {}",
+            ratio * 100.0,
+            self.target_height,
+            height,
+            eta.map_or("unknown".to_string(), |eta| format!("{:?}", eta))
+        );
 
-    dbw.insert_data_entries(updates_with_uids_superseded_by)?;
+        self.logged_height = height;
+        self.logged_at = Instant::now();
+    }
 
-    dbw.set_next_update_uid(next_uid + updates_count)
+    fn ratio(&self, height: u32) -> f64 {
+        if self.target_height <= self.start_height {
+            return 1.0;
+        }
+        let handled = height.saturating_sub(self.start_height) as f64;
+        (handled / (self.target_height - self.start_height) as f64).clamp(0.0, 1.0)
+    }
 }
 
-fn split_to_fragments(value: &String) -> Vec<(&str, &str)> {
-    let mut frs = value.split(FRAGMENT_SEPARATOR).into_iter();
+/// Batches the mode of the consumer is judged by.
+const MODE_WINDOW_BATCHES: usize = 10;
 
-    let types = frs
-        .next()
-        .map(|fragment| {
-            fragment
-                .split("%")
-                .into_iter()
-                .skip(1) // first item is empty
-                .collect()
-        })
-        .unwrap_or(vec![]);
+/// Whether the consumer catches up with the chain, judged by the height deltas of the recent
+/// batches. The stream does not report the chain tip, but a live consumer can not handle blocks
+/// faster than the chain produces them. The mode is reported by `gauge`.
+struct ModeTracker {
+    live_max_blocks_per_minute: f64,
+    recent: VecDeque<(Instant, u32)>,
+    gauge: IntGauge,
+}
 
-    types.into_iter().zip(frs).collect()
+impl ModeTracker {
+    fn new(live_max_blocks_per_minute: f64, gauge: IntGauge) -> Self {
+        ModeTracker {
+            live_max_blocks_per_minute,
+            recent: VecDeque::with_capacity(MODE_WINDOW_BATCHES),
+            gauge,
+        }
+    }
+
+    /// Record the height reached by a batch, `true` while syncing.
+    fn update(&mut self, height: u32) -> bool {
+        if self.recent.len() == MODE_WINDOW_BATCHES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((Instant::now(), height));
+
+        // the window is stretched to a minute, so a block followed by its microblocks
+        // within a few seconds does not look like a sync
+        let (since, from_height) = self.recent[0];
+        let minutes = since.elapsed().as_secs_f64().max(60.0) / 60.0;
+        let is_syncing =
+            height.saturating_sub(from_height) as f64 / minutes > self.live_max_blocks_per_minute;
+        self.gauge.set(is_syncing as i64);
+        is_syncing
+    }
 }
 
-fn squash_microblocks<U: DataEntriesRepoOperations>(dbw: &mut U) -> Result<()> {
-    let total_block_id = dbw.get_total_block_id()?;
+/// Time to handle `remaining_blocks` at the rate of `blocks` per `elapsed`,
+/// `None` when nothing has been handled yet.
+fn eta(blocks: u32, elapsed: Duration, remaining_blocks: u32) -> Option<Duration> {
+    if blocks == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(
+        elapsed.as_secs() * remaining_blocks as u64 / blocks as u64,
+    ))
+}
 
-    match total_block_id {
-        Some(total_block_id) => {
-            let key_block_uid = dbw.get_key_block_uid()?;
+/// Append the batches received within `commit_max_wait_secs` to `first`, until there are
+/// `commit_every_n_batches` of them. A rollback is committed right away, and so is
+/// the group when the stream is closed or the shutdown is requested.
+/// An error of the stream fails the group, nothing of it is durable yet.
+async fn receive_more_batches(
+    rx: &mut Receiver<Result<BlockchainUpdatesWithLastHeight>>,
+    mut group: BlockchainUpdatesWithLastHeight,
+    settings: &Settings,
+    shutdown: &CancellationToken,
+) -> Result<BlockchainUpdatesWithLastHeight> {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(settings.commit_max_wait_secs);
+    let has_rollback = |updates: &[BlockchainUpdate]| {
+        updates
+            .iter()
+            .any(|update| matches!(update, BlockchainUpdate::Rollback(..)))
+    };
 
-            dbw.update_data_entries_block_references(&key_block_uid)?;
+    let mut batches = 1;
+    while batches < settings.commit_every_n_batches && !has_rollback(&group.updates) {
+        let batch = select! {
+            _ = shutdown.cancelled() => break,
+            _ = tokio::time::sleep_until(deadline) => break,
+            batch = rx.recv() => match batch {
+                Some(batch) => batch?,
+                None => break,
+            },
+        };
+        group.last_height = batch.last_height;
+        group.updates.extend(batch.updates);
+        batches += 1;
+    }
 
-            dbw.delete_microblocks()?;
+    Ok(group)
+}
 
-            dbw.change_block_id(&key_block_uid, &total_block_id)?;
+/// The sequence may be left behind the stored uids, e.g. by a restored dump,
+/// and the next batch would collide with them.
+fn reconcile_uid_seq<U: DataEntriesRepoOperations>(ops: &mut U) -> Result<()> {
+    let next_update_uid = ops.get_next_update_uid()?;
+    if let Some(last_uid) = ops.get_last_data_entry_uid()? {
+        if last_uid >= next_update_uid {
+            warn!(
+                "Uid sequence is behind the data entries: next uid is {}, last stored uid is {}. Advancing it.",
+                next_update_uid, last_uid
+            );
+            ops.set_next_update_uid(last_uid + 1)?;
         }
-        None => (),
     }
+    Ok(())
+}
+
+fn starting_height<U: DataEntriesRepo>(dbw: &U, settings: &Settings) -> Result<u32> {
+    Ok(match settings.reset_to_height {
+        // nothing is rolled back, the stream continues after the handled blocks
+        _ if settings.dry_run => dbw
+            .execute(|mut ops| resume_height(&mut ops))?
+            .map_or(1, |height| height as u32 + 1),
+        // the uid sequence is reset together with the blocks, so is the cursor
+        Some(height) => dbw.transaction(|ops| {
+            if ops.get_applied_reset_to_height()? == Some(height as i32) {
+                info!(
+                    "Reset to height {} is already done, RESET_TO_HEIGHT can be removed",
+                    height
+                );
+                return resume_from_cursor(ops, settings);
+            }
+            let starting_height = reset_to_height(ops, height, settings.track_last_writes)?;
+            let last_uid = ops.get_last_data_entry_uid()?;
+            store_consumer_state(ops, starting_height as i32 - 1, last_uid)?;
+            ops.set_applied_reset_to_height(height as i32)?;
+            Ok(starting_height)
+        })?,
+        None => dbw.transaction(|ops| resume_from_cursor(ops, settings))?,
+    })
+}
 
+fn resume_from_cursor<U: DataEntriesRepoOperations>(
+    ops: &mut U,
+    settings: &Settings,
+) -> Result<u32> {
+    advance_uid_seq_past_cursor(ops)?;
+    // taken before the rollback, its uids are not handed out again
+    let last_uid = last_stored_uid(ops)?;
+    let starting_height = start_rollback(ops, settings)?;
+    store_consumer_state(ops, starting_height as i32 - 1, last_uid)?;
+    Ok(starting_height)
+}
+
+/// Every uid up to the cursor was handed out already, so the sequence has to be past it,
+/// e.g. when a dump was restored without the sequence. Unlike `reconcile_uid_seq`,
+/// this does not scan the data entries, so it is done on every start.
+fn advance_uid_seq_past_cursor<U: DataEntriesRepoOperations>(ops: &mut U) -> Result<()> {
+    let last_uid = match ops.get_consumer_state()?.and_then(|state| state.last_uid) {
+        Some(last_uid) => last_uid,
+        None => return Ok(()),
+    };
+    let next_update_uid = ops.get_next_update_uid()?;
+    if last_uid >= next_update_uid {
+        warn!(
+            "Uid sequence is behind the stored cursor: next uid is {}, last uid is {}. Advancing it.",
+            next_update_uid, last_uid
+        );
+        ops.set_next_update_uid(last_uid + 1)?;
+    }
     Ok(())
 }
+
+/// Roll back `start_rollback_depth` blocks, so the blocks which might have changed
+/// while the consumer was stopped are handled again. Returns the height to start streaming from.
+fn start_rollback<U: DataEntriesRepoOperations>(ops: &mut U, settings: &Settings) -> Result<u32> {
+    match ops.get_handled_height(settings.start_rollback_depth)? {
+        Some(prev_handled_height) => {
+            info!(
+                "rollback database to height: {}",
+                prev_handled_height.height
+            );
+
+            rollback(ops, prev_handled_height.uid, settings.track_last_writes)?;
+            Ok(prev_handled_height.height as u32 + 1)
+        }
+        None => match resume_height(ops)? {
+            // the block at the rollback depth is missing, e.g. it was pruned
+            Some(last_height) => {
+                let target_height =
+                    (last_height - settings.start_rollback_depth as i32).max(0) as u32;
+                match deep_rollback_block_uid(ops, target_height, settings)? {
+                    Some(block_uid) => {
+                        let height = ops.get_block_height(&block_uid)?;
+                        info!("rollback database to height: {}", height);
+
+                        rollback(ops, block_uid, settings.track_last_writes).with_context(
+                            || {
+                                format!(
+                                    "failed rolling back to block {} at height {}",
+                                    block_uid, height
+                                )
+                            },
+                        )?;
+                        Ok(height as u32 + 1)
+                    }
+                    None => {
+                        info!("no rollback on start, last height is {}", last_height);
+                        Ok(last_height as u32 + 1)
+                    }
+                }
+            }
+            None => Ok(1u32),
+        },
+    }
+}
+
+/// The stored cursor is authoritative, the last block is only looked at
+/// when no batch has stored the cursor yet.
+fn resume_height<U: DataEntriesRepoOperations>(ops: &mut U) -> Result<Option<i32>> {
+    match ops.get_consumer_state()? {
+        Some(state) => Ok(Some(state.last_height)),
+        None => ops.get_last_height(),
+    }
+}
+
+/// Store the resume cursor within the transaction which has written the blocks.
+/// Nothing is stored before the first block.
+fn store_consumer_state<U: DataEntriesRepoOperations>(
+    ops: &mut U,
+    last_height: i32,
+    last_uid: Option<i64>,
+) -> Result<()> {
+    if last_height < 1 {
+        return Ok(());
+    }
+    ops.set_consumer_state(&ConsumerState {
+        last_height,
+        last_uid,
+    })
+}
+
+/// Uid of the cursor, which only falls back to the stored data entries
+/// when no batch has stored the cursor yet.
+fn last_stored_uid<U: DataEntriesRepoOperations>(ops: &mut U) -> Result<Option<i64>> {
+    match ops.get_consumer_state()? {
+        Some(state) => Ok(state.last_uid),
+        None => ops.get_last_data_entry_uid(),
+    }
+}
+
+/// What a batch has written, it is only reported to the metrics after the commit,
+/// so a transaction which fails or is retried is not counted.
+#[derive(Debug, Default)]
+struct BatchCounts {
+    blocks: u64,
+    microblocks: u64,
+    /// By the kind of the rollback.
+    rollbacks: HashMap<&'static str, u64>,
+    data_entries: u64,
+    /// Of the allowlisted addresses only.
+    data_entries_by_address: HashMap<String, u64>,
+    parse_errors: u64,
+}
+
+impl BatchCounts {
+    fn rolled_back(&mut self, rolled_back: &RolledBack) {
+        *self.rollbacks.entry(rolled_back.kind()).or_default() += 1;
+    }
+
+    fn add(&mut self, other: BatchCounts) {
+        self.blocks += other.blocks;
+        self.microblocks += other.microblocks;
+        for (kind, count) in other.rollbacks {
+            *self.rollbacks.entry(kind).or_default() += count;
+        }
+        self.data_entries += other.data_entries;
+        for (address, count) in other.data_entries_by_address {
+            *self.data_entries_by_address.entry(address).or_default() += count;
+        }
+        self.parse_errors += other.parse_errors;
+    }
+
+    fn report(self) {
+        BLOCKS_TOTAL.inc_by(self.blocks);
+        MICROBLOCKS_TOTAL.inc_by(self.microblocks);
+        for (kind, count) in self.rollbacks {
+            ROLLBACKS_TOTAL.with_label_values(&[kind]).inc_by(count);
+        }
+        DATA_ENTRIES_TOTAL.inc_by(self.data_entries);
+        for (address, count) in self.data_entries_by_address {
+            DATA_ENTRIES_BY_ADDRESS_TOTAL
+                .with_label_values(&[&address])
+                .inc_by(count);
+        }
+        PARSE_ERRORS_TOTAL.inc_by(self.parse_errors);
+    }
+}
+
+/// Height of the cursor after the update, a rollback moves it down to its target.
+fn update_height(update: &BlockchainUpdate) -> i32 {
+    match update {
+        BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => {
+            append.height as i32
+        }
+        BlockchainUpdate::Rollback(_, height) => *height as i32,
+    }
+}
+
+fn handle_updates<U: DataEntriesRepoOperations>(
+    ops: &mut U,
+    updates: Vec<BlockchainUpdate>,
+    settings: &Settings,
+) -> Result<BatchCounts> {
+    let mut counts = BatchCounts::default();
+    // the cursor is the last update of the batch
+    let last_height = match updates.last() {
+        Some(update) => update_height(update),
+        None => return Ok(counts),
+    };
+
+    let written_uid = updates
+        .into_iter()
+        .fold::<&mut Vec<UpdatesItem>, _>(&mut vec![], |acc, cur| match cur {
+            BlockchainUpdate::Block(b) => {
+                let len = acc.len();
+                if !acc.is_empty() {
+                    match acc.get_mut(len - 1).unwrap() {
+                        // a block which is not higher than the previous one starts
+                        // a new group, so it is checked for an implicit rollback
+                        UpdatesItem::Blocks(v)
+                            if v.last().is_none_or(|last| last.height < b.height) =>
+                        {
+                            v.push(b);
+                            acc
+                        }
+                        _ => {
+                            acc.push(UpdatesItem::Blocks(vec![b]));
+                            acc
+                        }
+                    }
+                } else {
+                    acc.push(UpdatesItem::Blocks(vec![b]));
+                    acc
+                }
+            }
+            BlockchainUpdate::Microblock(mba) => {
+                acc.push(UpdatesItem::Microblock(mba));
+                acc
+            }
+            BlockchainUpdate::Rollback(sig, height) => {
+                // consecutive rollbacks are handled at once
+                match acc.last_mut() {
+                    Some(UpdatesItem::Rollback(targets)) => targets.push((sig, height)),
+                    _ => acc.push(UpdatesItem::Rollback(vec![(sig, height)])),
+                }
+                acc
+            }
+        })
+        .iter_mut()
+        .try_fold(None, |written_uid, update_item| match update_item {
+            UpdatesItem::Blocks(bs) => {
+                handle_lower_height(
+                    ops,
+                    bs[0].height,
+                    settings.implicit_rollbacks,
+                    settings.track_last_writes,
+                    &mut counts,
+                )?;
+                squash_microblocks(ops)?;
+                let last_uid =
+                    append_blocks_or_microblocks(ops, bs.as_ref(), settings, &mut counts)
+                    .with_context(|| {
+                        let (first, last) = (&bs[0], bs.last().unwrap());
+                        format!(
+                            "failed processing blocks {}..{} at heights {}..{}",
+                            first.id, last.id, first.height, last.height
+                        )
+                    })?;
+                counts.blocks += bs.len() as u64;
+                Ok(last_uid.or(written_uid))
+            }
+            UpdatesItem::Microblock(mba) => {
+                let last_uid =
+                    append_blocks_or_microblocks(ops, &[mba.to_owned()], settings, &mut counts)
+                    .with_context(|| {
+                        format!(
+                            "failed processing microblock {} at height {}",
+                            mba.id, mba.height
+                        )
+                    })?;
+                counts.microblocks += 1;
+                Ok(last_uid.or(written_uid))
+            }
+            UpdatesItem::Rollback(targets) => {
+                // a block may be already deleted by a previous rollback of the group,
+                // so only the lowest of the found blocks is rolled back to
+                let block_uid = match lowest_block_uid(ops, targets)? {
+                    Some(block_uid) => block_uid,
+                    None => {
+                        let target_height =
+                            targets.iter().map(|(_, height)| *height).min().unwrap();
+                        match deep_rollback_block_uid(ops, target_height, settings)? {
+                            Some(block_uid) => block_uid,
+                            None => return Ok(written_uid),
+                        }
+                    }
+                };
+                let height = ops.get_block_height(&block_uid)?;
+                // a rollback to genesis would wipe the whole database,
+                // so it has to be resolved by an operator
+                if height < settings.min_rollback_height as i32 {
+                    return Err(Error::new(AppError::GenesisRollback(format!(
+                        "rollback to block {} at height {} is refused, the lowest allowed height is {}",
+                        block_uid, height, settings.min_rollback_height
+                    ))));
+                }
+                let rolled_back = rollback(ops, block_uid, settings.track_last_writes)
+                    .with_context(|| {
+                        format!(
+                            "failed rolling back to block {} at height {}",
+                            block_uid, height
+                        )
+                    })?;
+                info!(
+                    "Rolled back to block {} at height {} for targets {:?}: {}",
+                    block_uid,
+                    height,
+                    targets,
+                    rolled_back
+                );
+                counts.rolled_back(&rolled_back);
+                Ok(written_uid)
+            }
+        })?;
+
+    // uids are not reused after a rollback, so the cursor keeps the last one handed out
+    let last_uid = match written_uid {
+        Some(uid) => Some(uid),
+        None => last_stored_uid(ops)?,
+    };
+    store_consumer_state(ops, last_height, last_uid)?;
+    Ok(counts)
+}
+
+fn log_dry_run(updates_with_height: &BlockchainUpdatesWithLastHeight) {
+    let mut blocks = 0;
+    let mut microblocks = 0;
+    let mut rollbacks = 0;
+    let mut appends = vec![];
+
+    updates_with_height
+        .updates
+        .iter()
+        .for_each(|update| match update {
+            BlockchainUpdate::Block(append) => {
+                blocks += 1;
+                appends.push(append);
+            }
+            BlockchainUpdate::Microblock(append) => {
+                microblocks += 1;
+                appends.push(append);
+            }
+            BlockchainUpdate::Rollback(..) => rollbacks += 1,
+        });
+
+    info!(
+        "Dry run: {} blocks, {} microblocks, {} rollbacks with {} data entries, {} leases, {} transfers, {} contract events and {} transactions would be written. Last height is {}.",
+        blocks,
+        microblocks,
+        rollbacks,
+        appends.iter().map(|a| a.data_entries.len()).sum::<usize>(),
+        appends.iter().map(|a| a.leases.len()).sum::<usize>(),
+        appends.iter().map(|a| a.transfers.len()).sum::<usize>(),
+        appends.iter().map(|a| a.contract_events.len()).sum::<usize>(),
+        appends.iter().map(|a| a.transactions.len()).sum::<usize>(),
+        updates_with_height.last_height
+    );
+
+    if let Some(data_entry) = appends.iter().flat_map(|a| a.data_entries.iter()).next() {
+        info!("Dry run: sample data entry {:?}", data_entry);
+    }
+    if let Some(transfer) = appends.iter().flat_map(|a| a.transfers.iter()).next() {
+        info!("Dry run: sample transfer {:?}", transfer);
+    }
+}
+
+fn lowest_block_uid<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    targets: &[(String, u32)],
+) -> Result<Option<i64>> {
+    let mut lowest: Option<i64> = None;
+    for (block_id, _) in targets {
+        match dbw.get_block_uid(block_id)? {
+            Some(block_uid) => {
+                lowest = Some(lowest.map_or(block_uid, |l| l.min(block_uid)));
+            }
+            None => warn!("Rollback target {} is already gone, skipping", block_id),
+        }
+    }
+    Ok(lowest)
+}
+
+/// A rollback target which is not found is fine as long as there are no blocks above it.
+/// Otherwise the rollback goes deeper than the kept blocks, so the surviving versions
+/// cannot be reopened exactly.
+fn deep_rollback_block_uid<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    target_height: u32,
+    settings: &Settings,
+) -> Result<Option<i64>> {
+    match dbw.get_last_height()? {
+        Some(last_height) if last_height > target_height as i32 => (),
+        _ => return Ok(None),
+    }
+
+    error!(
+        "Rollback to height {} goes below the blocks kept in the database",
+        target_height
+    );
+
+    match settings.on_deep_rollback {
+        DeepRollbackAction::Fail => Err(Error::new(AppError::DeepRollback(format!(
+            "rollback to height {} cannot be handled",
+            target_height
+        )))),
+        DeepRollbackAction::Resync => {
+            match dbw.get_last_block_uid_below(target_height as i32 + 1)? {
+                Some(block_uid) => Ok(Some(block_uid)),
+                None => Err(Error::new(AppError::GenesisRollback(format!(
+                    "rollback below height {} is refused",
+                    target_height + 1
+                )))),
+            }
+        }
+    }
+}
+
+/// Roll back to `height` and reset the data entries uid sequence to the remaining entries.
+/// Returns the height to start streaming from.
+fn reset_to_height<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    height: u32,
+    track_last_writes: bool,
+) -> Result<u32> {
+    let last_height = match dbw.get_last_height()? {
+        Some(last_height) if last_height > height as i32 => last_height,
+        last_height => {
+            info!("There are no blocks above height {} to reset", height);
+            return Ok(last_height.map_or(1, |h| h as u32 + 1));
+        }
+    };
+
+    let block_uid = match dbw.get_last_block_uid_below(height as i32 + 1)? {
+        Some(block_uid) => block_uid,
+        None => {
+            return Err(Error::new(AppError::GenesisRollback(format!(
+                "reset to height {} is refused",
+                height
+            ))))
+        }
+    };
+
+    let deleted_data_entries = rollback(dbw, block_uid, track_last_writes)?.deleted_data_entries;
+
+    let next_uid = dbw.get_last_data_entry_uid()?.map_or(1, |uid| uid + 1);
+    dbw.set_next_update_uid(next_uid)?;
+
+    warn!(
+        "Reset to height {}: deleted blocks at heights {}..={} with {} data entries, next data entry uid is {}",
+        height,
+        height + 1,
+        last_height,
+        deleted_data_entries,
+        next_uid
+    );
+
+    Ok(height + 1)
+}
+
+/// Some nodes resume the stream from a lower height after their own restart without sending
+/// a rollback, so a block which is not higher than the handled height is treated as one.
+fn handle_lower_height<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    height: u32,
+    implicit_rollbacks: bool,
+    track_last_writes: bool,
+    counts: &mut BatchCounts,
+) -> Result<()> {
+    let last_height = match dbw.get_last_height()? {
+        Some(last_height) if height as i32 <= last_height => last_height,
+        _ => return Ok(()),
+    };
+
+    if !implicit_rollbacks {
+        return Err(Error::new(AppError::InvalidMessage(format!(
+            "Block at height {} was received without a rollback, but height {} is already handled",
+            height, last_height
+        ))));
+    }
+
+    warn!(
+        "Block at height {} was received without a rollback, but height {} is already handled. Rolling back to height {}.",
+        height, last_height, height as i32 - 1
+    );
+
+    match dbw.get_last_block_uid_below(height as i32)? {
+        Some(block_uid) => {
+            let rolled_back = rollback(dbw, block_uid, track_last_writes)?;
+            counts.rolled_back(&rolled_back);
+            Ok(())
+        }
+        None => Err(Error::new(AppError::GenesisRollback(format!(
+            "implicit rollback below height {} is refused",
+            height
+        )))),
+    }
+}
+
+fn extract_fragments(values: &[(&str, &str)]) -> Fragments {
+    std::array::from_fn(|position| {
+        (
+            extract_integer_fragment(values, position),
+            extract_string_fragment(values, position),
+        )
+    })
+}
+
+/// Binary fragments are stored in the string columns in their base58 form,
+/// so only fragments which are valid base58 are kept.
+fn extract_string_fragment(values: &[(&str, &str)], position: usize) -> Option<String> {
+    values.get(position).and_then(|(t, v)| {
+        if *t == STRING_DESCRIPTOR {
+            Some(v.to_string())
+        } else if *t == BINARY_DESCRIPTOR {
+            extract_binary_fragment(values, position).map(|_| v.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn extract_binary_fragment(values: &[(&str, &str)], position: usize) -> Option<Vec<u8>> {
+    values.get(position).and_then(|(t, v)| {
+        if *t == BINARY_DESCRIPTOR {
+            bs58::decode(v).into_vec().ok()
+        } else {
+            None
+        }
+    })
+}
+
+fn extract_integer_fragment(values: &[(&str, &str)], position: usize) -> Option<i64> {
+    values.get(position).and_then(|(t, v)| {
+        if *t == INTEGER_DESCRIPTOR {
+            v.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+struct RolledBack {
+    deleted_data_entries: usize,
+    /// Keys whose previous version became live again, a key written for the first time
+    /// in the deleted blocks has none.
+    reopened_data_entries: usize,
+    microblocks_only: bool,
+}
+
+impl RolledBack {
+    /// Label of the rollbacks counter.
+    fn kind(&self) -> &'static str {
+        if self.microblocks_only {
+            "microblock"
+        } else {
+            "block"
+        }
+    }
+}
+
+impl std::fmt::Display for RolledBack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} data entries deleted, {} reopened, {} rollback",
+            self.deleted_data_entries,
+            self.reopened_data_entries,
+            self.kind()
+        )
+    }
+}
+
+/// Delete everything after `block_uid` and make the surviving versions live again.
+///
+/// Versions of a key are chained by `superseded_by` in uid order, so the lowest deleted uid
+/// of every key is exactly the `superseded_by` of the version that has to be reopened.
+///
+/// A rollback within the current key block only removes microblocks of the last height,
+/// which is the common case. Their entries are the last uids, so they are deleted by a short
+/// scan of the uid index, while a key block rollback deletes by the block reference
+/// and cascades through every block above the target.
+fn rollback<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    block_uid: i64,
+    track_last_writes: bool,
+) -> Result<RolledBack> {
+    let is_microblock_rollback = dbw
+        .get_key_block_uid()?
+        .is_none_or(|key_block_uid| key_block_uid <= block_uid);
+
+    let deletes = if is_microblock_rollback {
+        dbw.rollback_microblock_data_entries(&block_uid)?
+    } else {
+        dbw.rollback_data_entries(&block_uid)?
+    };
+    let deleted_count = deletes.len();
+
+    let mut grouped_deletes: HashMap<DeletedDataEntry, Vec<DeletedDataEntry>> = HashMap::new();
+
+    deletes.into_iter().for_each(|item| {
+        let group = grouped_deletes.entry(item.clone()).or_insert(vec![]);
+        group.push(item);
+    });
+
+    let deleted_keys = grouped_deletes
+        .keys()
+        .map(|de| (de.address.clone(), de.key.clone()))
+        .collect_vec();
+
+    let lowest_deleted_uids: Vec<i64> = grouped_deletes
+        .into_values()
+        .filter_map(|group| group.into_iter().min_by_key(|i| i.uid).map(|i| i.uid))
+        .collect();
+
+    let reopened_count = lowest_deleted_uids.len();
+    if reopened_count > 0 {
+        dbw.reopen_superseded_by(&lowest_deleted_uids)?;
+    }
+
+    if is_microblock_rollback {
+        dbw.rollback_microblocks(&block_uid)?;
+    } else {
+        dbw.rollback_blocks_microblocks(&block_uid)?;
+    }
+
+    if track_last_writes && !deleted_keys.is_empty() {
+        dbw.refresh_last_writes(&deleted_keys)?;
+    }
+
+    Ok(RolledBack {
+        deleted_data_entries: deleted_count,
+        reopened_data_entries: reopened_count,
+        microblocks_only: is_microblock_rollback,
+    })
+}
+
+/// Returns the uid of the last written data entry, if any were written.
+fn append_blocks_or_microblocks<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    appends: &[BlockMicroblockAppend],
+    settings: &Settings,
+    counts: &mut BatchCounts,
+) -> Result<Option<i64>> {
+    let block_uids = dbw.insert_blocks_or_microblocks(
+        &appends
+            .iter()
+            .map(|append| BlockMicroblock {
+                id: append.id.clone(),
+                height: append.height as i32,
+                time_stamp: append.time_stamp,
+            })
+            .collect_vec(),
+    )?;
+
+    // a stored block was already handled, e.g. when the stream was resumed from a wrong height,
+    // so it is skipped instead of being written twice
+    let (block_uids, appends): (Vec<i64>, Vec<&BlockMicroblockAppend>) = block_uids
+        .into_iter()
+        .zip(appends)
+        .filter_map(|(block_uid, append)| match block_uid {
+            Some(block_uid) => Some((block_uid, append)),
+            None => {
+                warn!(
+                    "Block {} at height {} is already stored, skipping",
+                    append.id, append.height
+                );
+                None
+            }
+        })
+        .unzip();
+
+    // blocks are inserted even if none of their data entries are accepted,
+    // so the handled height and rollbacks stay correct
+    let data_entries = block_uids
+        .iter()
+        .zip(&appends)
+        .filter(|(_, append)| !append.data_entries.is_empty())
+        .flat_map(|(block_uid, append)| {
+            append
+                .data_entries
+                .iter()
+                .filter(|de| settings.accepts(de))
+                .cloned()
+                .map(|de| BlockUidWithDataEntry {
+                    block_uid: block_uid.to_owned(),
+                    data_entry: de,
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+
+    let last_uid = if !data_entries.is_empty() {
+        let appended = append_data_entries(dbw, data_entries, settings)?;
+        counts.data_entries += appended.inserted as u64;
+        for (address, count) in appended.by_address {
+            *counts.data_entries_by_address.entry(address).or_default() += count;
+        }
+        appended.last_uid
+    } else {
+        None
+    };
+
+    let leases = block_uids
+        .iter()
+        .zip(&appends)
+        .flat_map(|(block_uid, append)| {
+            append
+                .leases
+                .iter()
+                .map(|lease| InsertableLease {
+                    block_uid: block_uid.to_owned(),
+                    transaction_id: lease.transaction_id.clone(),
+                    lease_id: lease.lease_id.clone(),
+                    sender: lease.sender.clone(),
+                    recipient: lease.recipient.clone(),
+                    amount: lease.amount,
+                    status: lease.status.clone(),
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+
+    if !leases.is_empty() {
+        dbw.insert_leases(&leases)?;
+    }
+
+    let transfers = block_uids
+        .iter()
+        .zip(&appends)
+        .flat_map(|(block_uid, append)| {
+            append
+                .transfers
+                .iter()
+                .map(|transfer| InsertableTransfer {
+                    block_uid: block_uid.to_owned(),
+                    height: append.height as i32,
+                    transaction_id: transfer.transaction_id.clone(),
+                    sender: transfer.sender.clone(),
+                    recipient: transfer.recipient.clone(),
+                    asset_id: transfer.asset_id.clone(),
+                    amount: transfer.amount,
+                    attachment: transfer.attachment.clone(),
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+
+    if !transfers.is_empty() {
+        dbw.insert_transfers(&transfers)?;
+    }
+
+    let contract_events = block_uids
+        .iter()
+        .zip(&appends)
+        .flat_map(|(block_uid, append)| {
+            append
+                .contract_events
+                .iter()
+                .map(|event| InsertableContractEvent {
+                    block_uid: block_uid.to_owned(),
+                    height: append.height as i32,
+                    transaction_id: event.transaction_id.clone(),
+                    address: event.address.clone(),
+                    event_type: event.event_type.clone(),
+                    has_script: event.has_script,
+                    asset_id: event.asset_id.clone(),
+                    asset_name: event.asset_name.clone(),
+                    asset_description: event.asset_description.clone(),
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+
+    if !contract_events.is_empty() {
+        dbw.insert_contract_events(&contract_events)?;
+    }
+
+    let transactions = block_uids
+        .iter()
+        .zip(&appends)
+        .flat_map(|(block_uid, append)| {
+            append
+                .transactions
+                .iter()
+                .map(|tx| InsertableTransaction {
+                    tx_id: tx.tx_id.clone(),
+                    block_uid: block_uid.to_owned(),
+                    height: append.height as i32,
+                    tx_type: tx.tx_type,
+                })
+                .collect_vec()
+        })
+        .collect_vec();
+
+    if !transactions.is_empty() {
+        dbw.insert_transactions(&transactions)?;
+    }
+
+    Ok(last_uid)
+}
+
+/// The first of the writes is kept, so the dropped uids only leave gaps in the sequence.
+fn is_identical_write(
+    prev: &InsertableDataEntry,
+    cur: &InsertableDataEntry,
+    within_transaction: bool,
+) -> bool {
+    prev.block_uid == cur.block_uid
+        && (!within_transaction || prev.transaction_id == cur.transaction_id)
+        && prev.value_binary == cur.value_binary
+        && prev.value_bool == cur.value_bool
+        && prev.value_integer == cur.value_integer
+        && prev.value_string == cur.value_string
+}
+
+fn to_insertable_data_entry(
+    uid: i64,
+    BlockUidWithDataEntry {
+        block_uid,
+        data_entry,
+    }: BlockUidWithDataEntry,
+    decode_binary_value_fragments: bool,
+) -> InsertableDataEntry {
+    let key_fragments = extract_fragments(&split_to_fragments(&data_entry.key));
+    let value_fragments = match &data_entry.value {
+        DataEntryValue::String(value) => extract_fragments(&split_to_fragments(value)),
+        // a binary value which is not valid UTF-8 has no fragments
+        DataEntryValue::Binary(value) if decode_binary_value_fragments => {
+            match std::str::from_utf8(value) {
+                Ok(value) => extract_fragments(&split_to_fragments(value)),
+                Err(_) => extract_fragments(&[]),
+            }
+        }
+        _ => extract_fragments(&[]),
+    };
+    InsertableDataEntry::new(block_uid, uid, data_entry, key_fragments, value_fragments)
+}
+
+struct AppendedDataEntries {
+    /// Rows actually written, collapsed identical writes are not counted.
+    inserted: usize,
+    last_uid: Option<i64>,
+    /// Written rows of the allowlisted addresses.
+    by_address: Vec<(String, u64)>,
+}
+
+fn append_data_entries<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    updates: Vec<BlockUidWithDataEntry>,
+    settings: &Settings,
+) -> Result<AppendedDataEntries> {
+    let updates_count = updates.len() as i64;
+    let next_uid = dbw.reserve_update_uids(updates_count)?;
+
+    // uids follow the position in the batch, so the result does not depend on the order
+    // in which the entries are mapped
+    #[cfg(feature = "parallel")]
+    let entries = {
+        use rayon::prelude::*;
+
+        updates
+            .into_par_iter()
+            .enumerate()
+            .map(|(idx, update)| {
+                to_insertable_data_entry(
+                    next_uid + idx as i64,
+                    update,
+                    settings.decode_binary_value_fragments,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    #[cfg(not(feature = "parallel"))]
+    let entries = updates
+        .into_iter()
+        .enumerate()
+        .map(|(idx, update)| {
+            to_insertable_data_entry(
+                next_uid + idx as i64,
+                update,
+                settings.decode_binary_value_fragments,
+            )
+        })
+        .collect_vec();
+
+    let mut grouped_updates: HashMap<InsertableDataEntry, Vec<InsertableDataEntry>> =
+        HashMap::new();
+
+    entries.into_iter().for_each(|item| {
+        let group = grouped_updates.entry(item.clone()).or_insert(vec![]);
+        group.push(item);
+    });
+
+    let grouped_updates = grouped_updates.into_iter().collect_vec();
+
+    let grouped_updates_with_uids_superseded_by = grouped_updates
+        .into_iter()
+        .map(|(key, group)| {
+            let mut updates = group
+                .into_iter()
+                .sorted_by_key(|item| item.uid)
+                .collect::<Vec<InsertableDataEntry>>();
+
+            if settings.collapse_identical_writes {
+                updates.dedup_by(|cur, prev| {
+                    is_identical_write(prev, cur, settings.collapse_within_transaction)
+                });
+            }
+
+            // uids grow with the block order of the batch, also across squashed microblocks,
+            // so walking the versions backwards leaves only the latest one live
+            // and makes every earlier one superseded by the next version of the key
+            let mut last_uid = LIVE_SUPERSEDED_BY;
+            (
+                key,
+                updates
+                    .as_mut_slice()
+                    .iter_mut()
+                    .rev()
+                    .map(|cur| {
+                        cur.superseded_by = last_uid;
+                        last_uid = cur.uid;
+                        cur.to_owned()
+                    })
+                    .sorted_by_key(|item| item.uid)
+                    .collect(),
+            )
+        })
+        .collect::<Vec<(InsertableDataEntry, Vec<InsertableDataEntry>)>>();
+
+    // First uid for each asset in a new batch. This value closes superseded_by of previous updates.
+    let first_uids: Vec<DataEntryUpdate> = grouped_updates_with_uids_superseded_by
+        .iter()
+        .map(|(_, group)| {
+            let first = group.iter().next().unwrap().clone();
+            DataEntryUpdate {
+                address: first.address,
+                key: first.key,
+                superseded_by: first.uid,
+            }
+        })
+        .collect();
+
+    let written_keys = first_uids
+        .iter()
+        .map(|u| (u.address.clone(), u.key.clone()))
+        .collect_vec();
+
+    // a single statement with all of the keys of a large batch gets a huge plan,
+    // the chunks are still run within the batch transaction
+    for chunk in first_uids.chunks(settings.close_superseded_by_chunk_size.max(1)) {
+        dbw.close_superseded_by(chunk)?;
+    }
+
+    let by_address = grouped_updates_with_uids_superseded_by
+        .iter()
+        .filter(|(key, _)| settings.metrics_address_allowlist.contains(&key.address))
+        .map(|(key, group)| (key.address.clone(), group.len() as u64))
+        .collect_vec();
+
+    let updates_with_uids_superseded_by = grouped_updates_with_uids_superseded_by
+        .into_iter()
+        .flat_map(|(_, v)| v)
+        .sorted_by_key(|de| de.uid)
+        .collect_vec();
+    let last_uid = updates_with_uids_superseded_by.last().map(|de| de.uid);
+    let inserted = updates_with_uids_superseded_by.len();
+
+    if settings.use_copy_insert {
+        dbw.copy_data_entries(updates_with_uids_superseded_by)?;
+    } else {
+        dbw.insert_data_entries(updates_with_uids_superseded_by, settings.insert_chunk_size)?;
+    }
+
+    if settings.track_last_writes {
+        dbw.refresh_last_writes(&written_keys)?;
+    }
+
+    NEXT_UPDATE_UID.set(next_uid + updates_count);
+
+    Ok(AppendedDataEntries {
+        inserted,
+        last_uid,
+        by_address,
+    })
+}
+
+fn split_to_fragments(value: &str) -> Vec<(&str, &str)> {
+    let mut frs = value.split(FRAGMENT_SEPARATOR);
+
+    let types = frs
+        .next()
+        .map(|fragment| {
+            fragment
+                .split("%")
+                .skip(1) // first item is empty
+                .collect()
+        })
+        .unwrap_or(vec![]);
+
+    types.into_iter().zip(frs).collect()
+}
+
+fn squash_microblocks<U: DataEntriesRepoOperations>(dbw: &mut U) -> Result<()> {
+    let total_block_id = dbw.get_total_block_id()?;
+
+    if let Some(total_block_id) = total_block_id {
+        // microblocks without a key block, e.g. after a deep rollback, are left as they are
+        let key_block_uid = match dbw.get_key_block_uid()? {
+            Some(key_block_uid) => key_block_uid,
+            None => {
+                warn!("There is no key block for microblocks to be squashed into");
+                return Ok(());
+            }
+        };
+
+        dbw.update_data_entries_block_references(&key_block_uid)?;
+
+        dbw.update_leases_block_references(&key_block_uid)?;
+
+        dbw.update_transfers_block_references(&key_block_uid)?;
+
+        dbw.update_contract_events_block_references(&key_block_uid)?;
+
+        dbw.update_transactions_block_references(&key_block_uid)?;
+
+        dbw.delete_microblocks()?;
+
+        dbw.change_block_id(&key_block_uid, &total_block_id)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_entries::testing::{MockDataEntriesRepo, MockState};
+
+    const ADDRESS: &str = "3PAddress";
+
+    fn settings() -> Settings {
+        Settings {
+            updates_per_request: 100,
+            max_wait_time_in_secs: 5,
+            start_rollback_depth: 1,
+            min_rollback_height: 1,
+            track_last_writes: true,
+            implicit_rollbacks: true,
+            use_copy_insert: false,
+            insert_chunk_size: 100,
+            close_superseded_by_chunk_size: 100,
+            commit_every_n_batches: 1,
+            commit_max_wait_secs: 0,
+            live_max_blocks_per_minute: 10.0,
+            collapse_identical_writes: false,
+            collapse_within_transaction: false,
+            skip_empty_string_values: false,
+            decode_binary_value_fragments: false,
+            address_filter: HashSet::new(),
+            key_prefix_filter: vec![],
+            metrics_address_allowlist: HashSet::new(),
+            sync_to_height: None,
+            reset_to_height: None,
+            on_deep_rollback: DeepRollbackAction::Fail,
+            on_parse_error: ParseErrorAction::Fail,
+            dry_run: false,
+            reconcile_uid_seq_on_startup: false,
+            secondary_failures_are_fatal: false,
+        }
+    }
+
+    fn write(key: &str, value: i64) -> DataEntry {
+        DataEntry {
+            address: ADDRESS.to_string(),
+            key: key.to_string(),
+            transaction_id: format!("tx-{}-{}", key, value),
+            value: DataEntryValue::Integer(value),
+        }
+    }
+
+    fn append(
+        id: &str,
+        height: u32,
+        key_block: bool,
+        writes: Vec<DataEntry>,
+    ) -> BlockMicroblockAppend {
+        BlockMicroblockAppend {
+            id: id.to_string(),
+            time_stamp: key_block.then_some(height as i64 * 60_000),
+            height,
+            data_entries: writes,
+            leases: vec![],
+            transfers: vec![],
+            contract_events: vec![],
+            transactions: vec![],
+        }
+    }
+
+    fn block(id: &str, height: u32, writes: Vec<DataEntry>) -> BlockchainUpdate {
+        BlockchainUpdate::Block(append(id, height, true, writes))
+    }
+
+    fn microblock(id: &str, height: u32, writes: Vec<DataEntry>) -> BlockchainUpdate {
+        BlockchainUpdate::Microblock(append(id, height, false, writes))
+    }
+
+    fn handle(repo: &MockDataEntriesRepo, updates: Vec<BlockchainUpdate>) {
+        repo.transaction(|ops| handle_updates(ops, updates, &settings()))
+            .unwrap();
+    }
+
+    /// Stored versions of the key in uid order, as their value and whether they are live.
+    fn versions(state: &MockState, key: &str) -> Vec<(i64, bool)> {
+        state
+            .data_entries
+            .iter()
+            .filter(|de| de.key == key)
+            .sorted_by_key(|de| de.uid)
+            .map(|de| {
+                (
+                    de.value_integer.unwrap(),
+                    de.superseded_by == LIVE_SUPERSEDED_BY,
+                )
+            })
+            .collect()
+    }
+
+    /// Every version is superseded by the uid of the next one, and the last one is live.
+    fn assert_chained(state: &MockState, key: &str) {
+        let entries = state
+            .data_entries
+            .iter()
+            .filter(|de| de.key == key)
+            .sorted_by_key(|de| de.uid)
+            .collect_vec();
+        for (prev, next) in entries.iter().tuple_windows() {
+            assert_eq!(prev.superseded_by, next.uid, "{} is not chained", key);
+        }
+        if let Some(last) = entries.last() {
+            assert_eq!(last.superseded_by, LIVE_SUPERSEDED_BY);
+        }
+    }
+
+    fn write_value(key: &str, value: DataEntryValue) -> DataEntry {
+        DataEntry {
+            value,
+            ..write(key, 0)
+        }
+    }
+
+    #[test]
+    fn empty_string_is_skipped_but_deletion_is_kept() {
+        let settings = Settings {
+            skip_empty_string_values: true,
+            ..settings()
+        };
+
+        assert!(!settings.accepts(&write_value("k", DataEntryValue::String(String::new()))));
+        assert!(settings.accepts(&write_value("k", DataEntryValue::Deleted)));
+        assert!(settings.accepts(&write_value("k", DataEntryValue::String("v".to_string()))));
+        assert!(settings.accepts(&write_value("k", DataEntryValue::Binary(vec![]))));
+    }
+
+    #[test]
+    fn empty_string_is_kept_when_not_skipping() {
+        assert!(settings().accepts(&write_value("k", DataEntryValue::String(String::new()))));
+        assert!(settings().accepts(&write_value("k", DataEntryValue::Deleted)));
+    }
+
+    fn with_key_prefixes(prefixes: &[&str]) -> Settings {
+        Settings {
+            key_prefix_filter: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            ..settings()
+        }
+    }
+
+    #[test]
+    fn empty_key_prefix_filter_accepts_every_key() {
+        let settings = with_key_prefixes(&[]);
+
+        assert!(settings.accepts(&write("balance_a", 1)));
+        assert!(settings.accepts(&write("%s%s__config__fee", 1)));
+    }
+
+    #[test]
+    fn key_prefix_filter_matches_the_start_of_the_key() {
+        let settings = with_key_prefixes(&["balance_"]);
+
+        assert!(settings.accepts(&write("balance_a", 1)));
+        assert!(!settings.accepts(&write("Balance_a", 1)));
+        assert!(!settings.accepts(&write("a_balance_", 1)));
+    }
+
+    #[test]
+    fn key_prefix_filter_matches_any_of_the_prefixes() {
+        let settings = with_key_prefixes(&["balance_", "%s%s__config__"]);
+
+        assert!(settings.accepts(&write("balance_a", 1)));
+        assert!(settings.accepts(&write("%s%s__config__fee", 1)));
+        assert!(!settings.accepts(&write("%s__config", 1)));
+    }
+
+    #[test]
+    fn key_prefix_and_address_filters_both_have_to_match() {
+        let settings = Settings {
+            address_filter: HashSet::from(["3POther".to_string()]),
+            ..with_key_prefixes(&["balance_"])
+        };
+
+        assert!(!settings.accepts(&write("balance_a", 1)));
+        assert!(settings.accepts(&DataEntry {
+            address: "3POther".to_string(),
+            ..write("balance_a", 1)
+        }));
+    }
+
+    #[test]
+    fn fragments_of_every_descriptor_are_extracted() {
+        let values = split_to_fragments("%s%b%d__ADDR__base58__42");
+        let fragments = extract_fragments(&values);
+
+        assert_eq!(
+            fragments[..4],
+            [
+                (None, Some("ADDR".to_string())),
+                (None, Some("base58".to_string())),
+                (Some(42), None),
+                (None, None),
+            ]
+        );
+        assert_eq!(
+            extract_binary_fragment(&values, 1),
+            Some(bs58::decode("base58").into_vec().unwrap())
+        );
+        assert_eq!(extract_binary_fragment(&values, 0), None);
+    }
+
+    #[test]
+    fn binary_fragment_which_is_not_base58_is_left_out() {
+        let values = split_to_fragments("%s%b%d__ADDR__0OIl__42");
+        let fragments = extract_fragments(&values);
+
+        assert_eq!(extract_binary_fragment(&values, 1), None);
+        assert_eq!(fragments[1], (None, None));
+        assert_eq!(fragments[2], (Some(42), None));
+    }
+
+    fn binary_entry(value: &[u8], decode_binary_value_fragments: bool) -> InsertableDataEntry {
+        to_insertable_data_entry(
+            1,
+            BlockUidWithDataEntry {
+                block_uid: 1,
+                data_entry: write_value("k", DataEntryValue::Binary(value.to_vec())),
+            },
+            decode_binary_value_fragments,
+        )
+    }
+
+    #[test]
+    fn binary_value_in_utf8_has_fragments_when_decoding() {
+        let entry = binary_entry(b"%s%d__ADDR__42", true);
+
+        assert_eq!(entry.value_fragment_0_string.as_deref(), Some("ADDR"));
+        assert_eq!(entry.value_fragment_1_integer, Some(42));
+
+        let entry = binary_entry(b"%s%d__ADDR__42", false);
+
+        assert_eq!(entry.value_fragment_0_string, None);
+        assert_eq!(entry.value_fragment_1_integer, None);
+    }
+
+    #[test]
+    fn binary_value_which_is_not_utf8_has_no_fragments() {
+        let entry = binary_entry(&[b'%', b's', b'_', b'_', 0xff, 0xfe], true);
+
+        assert_eq!(entry.value_fragment_0_string, None);
+        assert_eq!(entry.value_fragment_0_integer, None);
+        assert_eq!(
+            entry.value_binary,
+            Some(vec![b'%', b's', b'_', b'_', 0xff, 0xfe])
+        );
+    }
+
+    #[test]
+    fn versions_of_a_key_are_chained_across_batches() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+        handle(&repo, vec![block("b3", 3, vec![write("k", 3)])]);
+
+        let state = repo.state();
+        assert_eq!(
+            versions(&state, "k"),
+            vec![(1, false), (2, false), (3, true)]
+        );
+        assert_chained(&state, "k");
+    }
+
+    #[test]
+    fn rollback_reopens_the_previous_version() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(
+            &repo,
+            vec![block("b2", 2, vec![write("k", 2), write("new", 1)])],
+        );
+        handle(&repo, vec![BlockchainUpdate::Rollback("b1".to_string(), 1)]);
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, true)]);
+        assert!(versions(&state, "new").is_empty());
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1"]
+        );
+    }
+
+    #[test]
+    fn microblocks_are_squashed_into_their_key_block() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![microblock("m1", 1, vec![write("k", 2)])]);
+        handle(&repo, vec![microblock("m2", 1, vec![write("other", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 3)])]);
+
+        let state = repo.state();
+        // the key block keeps its uid and takes the id of the last microblock
+        let blocks = state
+            .blocks_microblocks
+            .iter()
+            .map(|b| (b.uid, b.id.as_str(), b.height))
+            .collect_vec();
+        assert_eq!(blocks, vec![(1, "m2", 1), (4, "b2", 2)]);
+        assert!(state
+            .data_entries
+            .iter()
+            .filter(|de| de.value_integer != Some(3))
+            .all(|de| de.block_uid == 1));
+        assert_eq!(
+            versions(&state, "k"),
+            vec![(1, false), (2, false), (3, true)]
+        );
+        assert_chained(&state, "k");
+    }
+
+    #[test]
+    fn microblocks_without_a_key_block_are_left_as_they_are() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![microblock("m1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+
+        let state = repo.state();
+        let blocks = state
+            .blocks_microblocks
+            .iter()
+            .map(|b| (b.uid, b.id.as_str()))
+            .collect_vec();
+        assert_eq!(blocks, vec![(1, "m1"), (2, "b2")]);
+        let block_uids = state
+            .data_entries
+            .iter()
+            .map(|de| de.block_uid)
+            .collect_vec();
+        assert_eq!(block_uids, vec![1, 2]);
+        assert_chained(&state, "k");
+    }
+
+    #[test]
+    fn rollback_summary_has_the_reopened_count() {
+        let repo = MockDataEntriesRepo::new();
+        handle(
+            &repo,
+            vec![block("b1", 1, vec![write("k", 1), write("j", 1)])],
+        );
+        handle(
+            &repo,
+            vec![block("b2", 2, vec![write("k", 2), write("j", 2)])],
+        );
+        handle(&repo, vec![block("b3", 3, vec![write("k", 3)])]);
+
+        let rolled_back = repo
+            .transaction(|ops| rollback(ops, block_uid(&repo, "b1"), false))
+            .unwrap();
+
+        assert_eq!(
+            rolled_back.to_string(),
+            "3 data entries deleted, 2 reopened, block rollback"
+        );
+    }
+
+    #[test]
+    fn rollback_to_the_middle_reopens_the_version_of_the_target() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=4 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+        handle(&repo, vec![BlockchainUpdate::Rollback("b2".to_string(), 2)]);
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, false), (2, true)]);
+        assert_chained(&state, "k");
+        assert_eq!(
+            state.last_writes[&(ADDRESS.to_string(), "k".to_string())],
+            2
+        );
+    }
+
+    #[test]
+    fn rollback_reopens_every_key_at_its_own_version() {
+        let repo = MockDataEntriesRepo::new();
+        handle(
+            &repo,
+            vec![block("b1", 1, vec![write("a", 1), write("b", 1)])],
+        );
+        handle(&repo, vec![block("b2", 2, vec![write("a", 2)])]);
+        handle(
+            &repo,
+            vec![block(
+                "b3",
+                3,
+                vec![write("a", 3), write("b", 3), write("c", 3)],
+            )],
+        );
+        handle(&repo, vec![BlockchainUpdate::Rollback("b2".to_string(), 2)]);
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "a"), vec![(1, false), (2, true)]);
+        assert_eq!(versions(&state, "b"), vec![(1, true)]);
+        assert!(versions(&state, "c").is_empty());
+        for key in ["a", "b"] {
+            assert_chained(&state, key);
+        }
+    }
+
+    #[test]
+    fn consecutive_rollbacks_go_to_the_lowest_target() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=4 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+        handle(
+            &repo,
+            vec![
+                BlockchainUpdate::Rollback("b3".to_string(), 3),
+                BlockchainUpdate::Rollback("b1".to_string(), 1),
+                BlockchainUpdate::Rollback("b2".to_string(), 2),
+            ],
+        );
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, true)]);
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1"]
+        );
+    }
+
+    #[test]
+    fn rollback_to_a_block_deleted_by_the_previous_one_is_skipped() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=3 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+        handle(&repo, vec![BlockchainUpdate::Rollback("b1".to_string(), 1)]);
+        handle(&repo, vec![BlockchainUpdate::Rollback("b2".to_string(), 2)]);
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, true)]);
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1"]
+        );
+    }
+
+    #[test]
+    fn lower_block_without_a_rollback_rolls_back_implicitly() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=3 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+        handle(&repo, vec![block("b2'", 2, vec![write("k", 5)])]);
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, false), (5, true)]);
+        assert_chained(&state, "k");
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1", "b2'"]
+        );
+    }
+
+    #[test]
+    fn lower_block_without_a_rollback_fails_without_implicit_rollbacks() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+        let settings = Settings {
+            implicit_rollbacks: false,
+            ..settings()
+        };
+
+        let err = repo
+            .transaction(|ops| {
+                handle_updates(ops, vec![block("b2'", 2, vec![write("k", 5)])], &settings)
+            })
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::InvalidMessage(_))
+        ));
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, false), (2, true)]);
+    }
+
+    #[test]
+    fn rollback_to_a_missing_block_without_blocks_above_is_skipped() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+
+        handle(
+            &repo,
+            vec![BlockchainUpdate::Rollback("unknown".to_string(), 2)],
+        );
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, false), (2, true)]);
+        assert_eq!(state.blocks_microblocks.len(), 2);
+    }
+
+    /// Blocks up to height 4, of which the empty block at height 2 is pruned.
+    fn with_pruned_block() -> MockDataEntriesRepo {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![])]);
+        handle(&repo, vec![block("b3", 3, vec![write("k", 3)])]);
+        handle(&repo, vec![block("b4", 4, vec![write("k", 4)])]);
+        let pruned = repo
+            .transaction(|ops| ops.delete_unreferenced_blocks_microblocks(3))
+            .unwrap();
+        assert_eq!(pruned, 1);
+        repo
+    }
+
+    #[test]
+    fn rollback_deeper_than_the_kept_blocks_fails() {
+        let repo = with_pruned_block();
+
+        let err = repo
+            .transaction(|ops| {
+                handle_updates(
+                    ops,
+                    vec![BlockchainUpdate::Rollback("b2".to_string(), 2)],
+                    &settings(),
+                )
+            })
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::DeepRollback(_))
+        ));
+        assert_eq!(repo.state().blocks_microblocks.len(), 3);
+    }
+
+    #[test]
+    fn rollback_deeper_than_the_kept_blocks_resyncs_from_below() {
+        let repo = with_pruned_block();
+        let settings = Settings {
+            on_deep_rollback: DeepRollbackAction::Resync,
+            ..settings()
+        };
+
+        repo.transaction(|ops| {
+            handle_updates(
+                ops,
+                vec![BlockchainUpdate::Rollback("b2".to_string(), 2)],
+                &settings,
+            )
+        })
+        .unwrap();
+
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, true)]);
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1"]
+        );
+    }
+
+    fn block_uid(repo: &MockDataEntriesRepo, id: &str) -> i64 {
+        repo.state()
+            .blocks_microblocks
+            .iter()
+            .find(|b| b.id == id)
+            .unwrap()
+            .uid
+    }
+
+    #[test]
+    fn microblock_rollback_deletes_only_the_later_microblocks() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![microblock("m1", 1, vec![write("k", 2)])]);
+        handle(
+            &repo,
+            vec![microblock("m2", 1, vec![write("k", 3), write("new", 1)])],
+        );
+
+        let target = block_uid(&repo, "m1");
+        let rolled_back = repo.transaction(|ops| rollback(ops, target, true)).unwrap();
+
+        assert!(rolled_back.microblocks_only);
+        assert_eq!(rolled_back.kind(), "microblock");
+        assert_eq!(rolled_back.deleted_data_entries, 2);
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, false), (2, true)]);
+        assert!(versions(&state, "new").is_empty());
+        assert_chained(&state, "k");
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1", "m1"]
+        );
+    }
+
+    #[test]
+    fn rollback_below_the_lowest_allowed_height_is_refused() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=3 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+        let settings = Settings {
+            min_rollback_height: 2,
+            ..settings()
+        };
+
+        let refused = repo.transaction(|ops| {
+            handle_updates(
+                ops,
+                vec![BlockchainUpdate::Rollback("b1".to_string(), 1)],
+                &settings,
+            )
+        });
+        let err = refused.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<AppError>(),
+                Some(AppError::GenesisRollback(_))
+            ),
+            "{:#}",
+            err
+        );
+        assert_eq!(repo.state().blocks_microblocks.len(), 3);
+
+        repo.transaction(|ops| {
+            handle_updates(
+                ops,
+                vec![BlockchainUpdate::Rollback("b2".to_string(), 2)],
+                &settings,
+            )
+        })
+        .unwrap();
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, false), (2, true)]);
+    }
+
+    #[test]
+    fn block_rollback_deletes_every_block_above_the_target() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+        handle(
+            &repo,
+            vec![microblock("m1", 2, vec![write("k", 3), write("new", 1)])],
+        );
+
+        let target = block_uid(&repo, "b1");
+        let rolled_back = repo.transaction(|ops| rollback(ops, target, true)).unwrap();
+
+        assert!(!rolled_back.microblocks_only);
+        assert_eq!(rolled_back.kind(), "block");
+        assert_eq!(rolled_back.deleted_data_entries, 3);
+        let state = repo.state();
+        assert_eq!(versions(&state, "k"), vec![(1, true)]);
+        assert!(versions(&state, "new").is_empty());
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1"]
+        );
+    }
+
+    #[test]
+    fn key_written_in_consecutive_blocks_of_a_batch_has_one_live_version() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(
+            &repo,
+            vec![
+                block("b2", 2, vec![write("k", 2)]),
+                block("b3", 3, vec![write("k", 3)]),
+            ],
+        );
+
+        let state = repo.state();
+        assert_eq!(
+            versions(&state, "k"),
+            vec![(1, false), (2, false), (3, true)]
+        );
+        assert_chained(&state, "k");
+    }
+
+    fn append_to_one_block(settings: &Settings, writes: Vec<DataEntry>) -> AppendedDataEntries {
+        let repo = MockDataEntriesRepo::new();
+        let updates = writes
+            .into_iter()
+            .map(|data_entry| BlockUidWithDataEntry {
+                block_uid: 1,
+                data_entry,
+            })
+            .collect_vec();
+        let appended = repo
+            .transaction(|ops| append_data_entries(ops, updates, settings))
+            .unwrap();
+        assert_eq!(repo.state().data_entries.len(), appended.inserted);
+        appended
+    }
+
+    #[test]
+    fn identical_writes_are_not_counted_when_collapsed() {
+        let settings = Settings {
+            collapse_identical_writes: true,
+            ..settings()
+        };
+        let appended =
+            append_to_one_block(&settings, vec![write("k", 1), write("k", 1), write("j", 2)]);
+
+        assert_eq!(appended.inserted, 2);
+        assert_eq!(appended.last_uid, Some(3));
+    }
+
+    #[test]
+    fn identical_writes_are_counted_when_not_collapsed() {
+        let appended = append_to_one_block(
+            &settings(),
+            vec![write("k", 1), write("k", 1), write("j", 2)],
+        );
+
+        assert_eq!(appended.inserted, 3);
+        assert_eq!(appended.last_uid, Some(3));
+    }
+
+    fn cursor(repo: &MockDataEntriesRepo) -> Option<(i32, Option<i64>)> {
+        repo.state()
+            .consumer_state
+            .map(|state| (state.last_height, state.last_uid))
+    }
+
+    #[test]
+    fn cursor_is_the_last_update_of_the_batch() {
+        let repo = MockDataEntriesRepo::new();
+        handle(
+            &repo,
+            vec![
+                block("b1", 1, vec![write("k", 1)]),
+                block("b2", 2, vec![write("k", 2), write("j", 2)]),
+            ],
+        );
+        assert_eq!(cursor(&repo), Some((2, Some(3))));
+
+        // a batch without data entries keeps the uid
+        handle(&repo, vec![microblock("m1", 2, vec![])]);
+        assert_eq!(cursor(&repo), Some((2, Some(3))));
+
+        // the deleted uids are not handed out again, so the uid is kept too
+        handle(&repo, vec![BlockchainUpdate::Rollback("b1".to_string(), 1)]);
+        assert_eq!(cursor(&repo), Some((1, Some(3))));
+    }
+
+    #[test]
+    fn start_without_a_cursor_falls_back_to_the_last_block() {
+        let repo = MockDataEntriesRepo::new();
+        assert_eq!(starting_height(&repo, &settings()).unwrap(), 1);
+        assert_eq!(cursor(&repo), None);
+
+        // blocks written before the cursor existed
+        repo.transaction(|ops| {
+            append_blocks_or_microblocks(
+                ops,
+                &[
+                    append("b1", 1, true, vec![write("k", 1)]),
+                    append("b2", 2, true, vec![write("k", 2)]),
+                ],
+                &settings(),
+                &mut BatchCounts::default(),
+            )
+        })
+        .unwrap();
+
+        assert_eq!(starting_height(&repo, &settings()).unwrap(), 2);
+        assert_eq!(cursor(&repo), Some((1, Some(2))));
+    }
+
+    #[test]
+    fn start_resumes_from_the_stored_cursor() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=3 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+
+        // the last block is rolled back by `start_rollback_depth`
+        assert_eq!(starting_height(&repo, &settings()).unwrap(), 3);
+        assert_eq!(cursor(&repo), Some((2, Some(3))));
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, false), (2, true)]);
+    }
+
+    struct ScriptedSource(Vec<BlockchainUpdatesWithLastHeight>);
+
+    #[async_trait::async_trait]
+    impl DataEntriesSource for ScriptedSource {
+        async fn stream(
+            self,
+            _from_height: u32,
+            _to_height: Option<u32>,
+            _batch_max_size: usize,
+            _batch_max_time: Duration,
+        ) -> Result<Receiver<Result<BlockchainUpdatesWithLastHeight>>> {
+            let (tx, rx) = tokio::sync::mpsc::channel(self.0.len().max(1));
+            for batch in self.0 {
+                tx.send(Ok(batch)).await.unwrap();
+            }
+            Ok(rx)
+        }
+
+        async fn block(self, _height: u32) -> Result<Vec<BlockchainUpdate>> {
+            unimplemented!()
+        }
+    }
+
+    /// Held by the tests which run the consumer, since its gauges are shared by the whole process.
+    static CONSUMER: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Run the consumer over the batches until their last height.
+    async fn consume(
+        repo: &MockDataEntriesRepo,
+        settings: Settings,
+        batches: Vec<BlockchainUpdate>,
+    ) -> Result<()> {
+        let last_height = batches.len() as u32;
+        let batches = batches
+            .into_iter()
+            .enumerate()
+            .map(|(idx, update)| BlockchainUpdatesWithLastHeight {
+                last_height: idx as u32 + 1,
+                updates: vec![update],
+            })
+            .collect();
+        let settings = Settings {
+            sync_to_height: Some(last_height),
+            ..settings
+        };
+        let _consumer = CONSUMER.lock().await;
+        start(
+            ScriptedSource(batches),
+            Arc::new(repo.clone()),
+            None,
+            settings,
+            signals(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn last_height_gauge_follows_the_handled_batches() {
+        let repo = MockDataEntriesRepo::new();
+        let batches = vec![
+            BlockchainUpdatesWithLastHeight {
+                last_height: 2,
+                updates: vec![
+                    block("b1", 1, vec![write("k", 1)]),
+                    block("b2", 2, vec![write("k", 2)]),
+                ],
+            },
+            BlockchainUpdatesWithLastHeight {
+                last_height: 3,
+                updates: vec![block("b3", 3, vec![write("k", 3)])],
+            },
+        ];
+        let settings = Settings {
+            sync_to_height: Some(3),
+            ..settings()
+        };
+
+        let _consumer = CONSUMER.lock().await;
+        LAST_HEIGHT.set(0);
+        start(
+            ScriptedSource(batches),
+            Arc::new(repo.clone()),
+            None,
+            settings,
+            signals(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(LAST_HEIGHT.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn batch_durations_are_observed() {
+        let repo = MockDataEntriesRepo::new();
+        let batches = vec![BlockchainUpdatesWithLastHeight {
+            last_height: 1,
+            updates: vec![block("b1", 1, vec![write("k", 1)])],
+        }];
+        let settings = Settings {
+            sync_to_height: Some(1),
+            ..settings()
+        };
+
+        let _consumer = CONSUMER.lock().await;
+        let received = BATCH_RECEIVE_SECONDS.get_sample_count();
+        let processed = BATCH_PROCESS_SECONDS.get_sample_count();
+        start(
+            ScriptedSource(batches),
+            Arc::new(repo.clone()),
+            None,
+            settings,
+            signals(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(BATCH_RECEIVE_SECONDS.get_sample_count(), received + 1);
+        assert_eq!(BATCH_PROCESS_SECONDS.get_sample_count(), processed + 1);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_not_counted() {
+        let repo = MockDataEntriesRepo::new();
+        let settings = Settings {
+            implicit_rollbacks: false,
+            ..settings()
+        };
+        // the lower block fails the batch after both blocks have been appended
+        let batches = vec![BlockchainUpdatesWithLastHeight {
+            last_height: 2,
+            updates: vec![
+                block("b1", 1, vec![write("k", 1)]),
+                block("b2", 2, vec![write("k", 2)]),
+                block("b1'", 1, vec![write("k", 3)]),
+            ],
+        }];
+
+        let (blocks, data_entries) = {
+            let _consumer = CONSUMER.lock().await;
+            let counted = (BLOCKS_TOTAL.get(), DATA_ENTRIES_TOTAL.get());
+            let result = start(
+                ScriptedSource(batches),
+                Arc::new(repo.clone()),
+                None,
+                settings.clone(),
+                signals(),
+            )
+            .await;
+
+            assert!(result.is_err());
+            assert!(repo.state().blocks_microblocks.is_empty());
+            assert_eq!((BLOCKS_TOTAL.get(), DATA_ENTRIES_TOTAL.get()), counted);
+            counted
+        };
+
+        consume(&repo, settings, vec![block("b1", 1, vec![write("k", 1)])])
+            .await
+            .unwrap();
+
+        assert_eq!(BLOCKS_TOTAL.get(), blocks + 1);
+        assert_eq!(DATA_ENTRIES_TOTAL.get(), data_entries + 1);
+    }
+
+    fn with_panic_on_key(key: &str) -> MockDataEntriesRepo {
+        let repo = MockDataEntriesRepo::new();
+        repo.panic_on_key(key);
+        repo
+    }
+
+    #[tokio::test]
+    async fn consumer_continues_after_a_panicking_batch_when_skipping() {
+        let repo = with_panic_on_key("bad");
+        let settings = Settings {
+            on_parse_error: ParseErrorAction::Skip,
+            ..settings()
+        };
+        consume(
+            &repo,
+            settings,
+            vec![
+                block("b1", 1, vec![write("k", 1)]),
+                block("b2", 2, vec![write("k", 2), write("bad", 2)]),
+                block("b3", 3, vec![write("k", 3)]),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let state = repo.state();
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1", "b3"]
+        );
+        assert_eq!(versions(&state, "k"), vec![(1, false), (3, true)]);
+        assert!(versions(&state, "bad").is_empty());
+        assert_eq!(state.consumer_state.map(|state| state.last_height), Some(3));
+    }
+
+    #[tokio::test]
+    async fn only_the_panicking_update_of_a_batch_is_skipped() {
+        let repo = with_panic_on_key("bad");
+        let settings = Settings {
+            on_parse_error: ParseErrorAction::Skip,
+            sync_to_height: Some(3),
+            ..settings()
+        };
+        let batches = vec![BlockchainUpdatesWithLastHeight {
+            last_height: 3,
+            updates: vec![
+                block("b1", 1, vec![write("k", 1)]),
+                block("b2", 2, vec![write("k", 2), write("bad", 2)]),
+                block("b3", 3, vec![write("k", 3)]),
+            ],
+        }];
+
+        let _consumer = CONSUMER.lock().await;
+        let parse_errors = PARSE_ERRORS_TOTAL.get();
+        start(
+            ScriptedSource(batches),
+            Arc::new(repo.clone()),
+            None,
+            settings,
+            signals(),
+        )
+        .await
+        .unwrap();
+
+        let state = repo.state();
+        assert_eq!(
+            state.blocks_microblocks.iter().map(|b| &b.id).collect_vec(),
+            vec!["b1", "b3"]
+        );
+        assert_eq!(versions(&state, "k"), vec![(1, false), (3, true)]);
+        assert!(versions(&state, "bad").is_empty());
+        assert_eq!(PARSE_ERRORS_TOTAL.get(), parse_errors + 1);
+    }
+
+    #[test]
+    fn cursor_is_moved_past_a_skipped_update() {
+        let repo = with_panic_on_key("bad");
+        let settings = Settings {
+            on_parse_error: ParseErrorAction::Skip,
+            ..settings()
+        };
+        let updates = vec![
+            block("b1", 1, vec![write("k", 1)]),
+            block("b2", 2, vec![write("bad", 2)]),
+        ];
+
+        let written = write_batch(&repo, updates, 2, &settings).unwrap();
+
+        assert_eq!(written.skipped, vec![1]);
+        assert_eq!(written.counts.blocks, 1);
+        assert_eq!(written.counts.parse_errors, 1);
+        assert_eq!(cursor(&repo), Some((2, written.last_data_entry_uid)));
+    }
+
+    /// Data entries counted for the address, `None` if it has no series.
+    fn counted_for_address(address: &str) -> Option<u64> {
+        use prometheus::core::Collector;
+
+        DATA_ENTRIES_BY_ADDRESS_TOTAL.collect()[0]
+            .get_metric()
+            .iter()
+            .find(|metric| metric.get_label()[0].get_value() == address)
+            .map(|metric| metric.get_counter().get_value() as u64)
+    }
+
+    #[tokio::test]
+    async fn only_allowlisted_addresses_are_counted() {
+        let repo = MockDataEntriesRepo::new();
+        let allowlisted = "3PAllowlistedCounted";
+        let other = "3POtherNotCounted";
+        let write_of = |address: &str, key: &str| DataEntry {
+            address: address.to_string(),
+            ..write(key, 1)
+        };
+        let settings = Settings {
+            metrics_address_allowlist: HashSet::from([allowlisted.to_string()]),
+            ..settings()
+        };
+
+        consume(
+            &repo,
+            settings,
+            vec![
+                block(
+                    "b1",
+                    1,
+                    vec![write_of(allowlisted, "a"), write_of(other, "a")],
+                ),
+                block("b2", 2, vec![write_of(allowlisted, "b")]),
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(counted_for_address(allowlisted), Some(2));
+        assert_eq!(counted_for_address(other), None);
+    }
+
+    #[tokio::test]
+    async fn consumer_stops_on_a_panicking_batch_when_failing() {
+        let repo = with_panic_on_key("bad");
+        let result = consume(
+            &repo,
+            settings(),
+            vec![
+                block("b1", 1, vec![write("k", 1)]),
+                block("b2", 2, vec![write("bad", 2)]),
+                block("b3", 3, vec![write("k", 3)]),
+            ],
+        )
+        .await;
+
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("height 2 panicked"), "{:#}", err);
+        assert_eq!(
+            repo.state()
+                .blocks_microblocks
+                .iter()
+                .map(|b| &b.id)
+                .collect_vec(),
+            vec!["b1"]
+        );
+    }
+
+    /// Streams the batches and keeps the stream open afterwards, like a node at the chain tip.
+    struct OpenSource(Vec<BlockchainUpdatesWithLastHeight>);
+
+    #[async_trait::async_trait]
+    impl DataEntriesSource for OpenSource {
+        async fn stream(
+            self,
+            _from_height: u32,
+            _to_height: Option<u32>,
+            _batch_max_size: usize,
+            _batch_max_time: Duration,
+        ) -> Result<Receiver<Result<BlockchainUpdatesWithLastHeight>>> {
+            let (tx, rx) = tokio::sync::mpsc::channel(self.0.len().max(1));
+            for batch in self.0 {
+                tx.send(Ok(batch)).await.unwrap();
+            }
+            tokio::spawn(async move { tx.closed().await });
+            Ok(rx)
+        }
+
+        async fn block(self, _height: u32) -> Result<Vec<BlockchainUpdate>> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn consumer_stops_when_the_shutdown_is_requested() {
+        let repo = MockDataEntriesRepo::new();
+        let signals = Signals {
+            consuming: Arc::new(AtomicBool::new(false)),
+            ..signals()
+        };
+        let consuming = signals.consuming.clone();
+        let shutdown = signals.shutdown.clone();
+        let source = OpenSource(vec![BlockchainUpdatesWithLastHeight {
+            last_height: 1,
+            updates: vec![block("b1", 1, vec![write("k", 1)])],
+        }]);
+        let _consumer = CONSUMER.lock().await;
+        let consumer = tokio::spawn(start(
+            source,
+            Arc::new(repo.clone()),
+            None,
+            settings(),
+            signals,
+        ));
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !consuming.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the batch has to be handled");
+        shutdown.cancel();
+
+        tokio::time::timeout(Duration::from_secs(10), consumer)
+            .await
+            .expect("the consumer has to stop")
+            .unwrap()
+            .unwrap();
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, true)]);
+    }
+
+    /// Fails to open the stream as many times as `failures` says, then streams the updates.
+    #[derive(Clone)]
+    struct FlakySource {
+        failures: Arc<std::sync::atomic::AtomicUsize>,
+        updates: Vec<BlockchainUpdate>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataEntriesSource for FlakySource {
+        async fn stream(
+            self,
+            from_height: u32,
+            to_height: Option<u32>,
+            batch_max_size: usize,
+            batch_max_time: Duration,
+        ) -> Result<Receiver<Result<BlockchainUpdatesWithLastHeight>>> {
+            let failed = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            if failed {
+                return Err(Error::new(AppError::StreamClosed(
+                    "node is down".to_string(),
+                )));
+            }
+            let batches = self
+                .updates
+                .into_iter()
+                .enumerate()
+                .map(|(idx, update)| BlockchainUpdatesWithLastHeight {
+                    last_height: idx as u32 + 1,
+                    updates: vec![update],
+                })
+                .collect();
+            ScriptedSource(batches)
+                .stream(from_height, to_height, batch_max_size, batch_max_time)
+                .await
+        }
+
+        async fn block(self, _height: u32) -> Result<Vec<BlockchainUpdate>> {
+            unimplemented!()
+        }
+    }
+
+    fn signals() -> Signals {
+        Signals {
+            heartbeat: Arc::new(AtomicI64::new(0)),
+            consuming: Arc::new(AtomicBool::new(true)),
+            syncing: Arc::new(AtomicBool::new(false)),
+            changes: broadcast::channel(1).0,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn degraded_consumer_is_restarted_until_it_recovers() {
+        let repo = MockDataEntriesRepo::new();
+        let failures = Arc::new(std::sync::atomic::AtomicUsize::new(2));
+        let source = FlakySource {
+            failures: failures.clone(),
+            updates: vec![block("b1", 1, vec![write("k", 1)])],
+        };
+        let signals = signals();
+        let consuming = signals.consuming.clone();
+        let settings = Settings {
+            sync_to_height: Some(1),
+            ..settings()
+        };
+
+        let _consumer = CONSUMER.lock().await;
+        start_degraded(source, Arc::new(repo.clone()), None, settings, signals)
+            .await
+            .unwrap();
+
+        assert_eq!(failures.load(Ordering::Relaxed), 0);
+        assert!(consuming.load(Ordering::Relaxed));
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, true)]);
+    }
+
+    #[tokio::test]
+    async fn reads_are_served_while_the_node_is_down() {
+        let repo = Arc::new(MockDataEntriesRepo::new());
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+
+        // nothing listens on the port, and the source is built without connecting
+        let source = crate::data_entries::updates::DataEntriesSourceImpl::new(
+            &crate::data_entries::Config {
+                blockchain_updates_url: "http://127.0.0.1:1".to_string(),
+                blockchain_updates_tls_domain_name: None,
+                blockchain_updates_ca_cert_path: None,
+                blockchain_updates_proxy_url: None,
+                updates_per_request: 100,
+                max_wait_time_in_secs: 5,
+                max_reconnect_attempts: Some(0),
+                channel_capacity: 16,
+                batch_max_bytes: usize::MAX,
+                expected_chain_id: None,
+                on_parse_error: ParseErrorAction::Fail,
+                ingest_tx_types: None,
+                grpc_connect_timeout_secs: 1,
+                grpc_tcp_keepalive_secs: 0,
+                grpc_http2_keepalive_interval_secs: 0,
+            },
+        )
+        .unwrap();
+        let signals = signals();
+        let consuming = signals.consuming.clone();
+        let shutdown = signals.shutdown.clone();
+        let changes = signals.changes.clone();
+        let _consumer = CONSUMER.lock().await;
+        let consumer = tokio::spawn(start_degraded(
+            source,
+            repo.clone(),
+            None,
+            settings(),
+            signals,
+        ));
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while consuming.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the consumer has to fail");
+
+        let routes = crate::api::routes(
+            crate::api::Probes {
+                consuming,
+                ready: Arc::new(std::sync::Mutex::new(None)),
+                syncing: Arc::new(AtomicBool::new(false)),
+                heartbeat: Arc::new(AtomicI64::new(0)),
+                liveness_timeout: Duration::from_secs(1),
+                last_message_at: Arc::new(AtomicI64::new(0)),
+                max_stream_idle: Duration::from_secs(1),
+            },
+            changes,
+            repo,
+        );
+        let entry = warp::test::request()
+            .path(&format!("/data_entries/{}/k", ADDRESS))
+            .reply(&routes)
+            .await;
+        assert_eq!(entry.status(), 200);
+        assert!(String::from_utf8_lossy(entry.body()).contains(r#""key":"k""#));
+        let status = warp::test::request().path("/status").reply(&routes).await;
+        assert!(String::from_utf8_lossy(status.body()).contains(r#""consuming":"down""#));
+
+        shutdown.cancel();
+        consumer.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn reset_to_height_is_done_once() {
+        let settings = Settings {
+            reset_to_height: Some(1),
+            ..settings()
+        };
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=3 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+
+        assert_eq!(starting_height(&repo, &settings).unwrap(), 2);
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, true)]);
+
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+        handle(&repo, vec![block("b3", 3, vec![write("k", 3)])]);
+
+        // the next start only rolls back `start_rollback_depth`
+        assert_eq!(starting_height(&repo, &settings).unwrap(), 3);
+        assert_eq!(versions(&repo.state(), "k"), vec![(1, false), (2, true)]);
+    }
+
+    #[test]
+    fn reset_to_height_of_an_empty_database_is_recorded() {
+        let settings = Settings {
+            reset_to_height: Some(1),
+            ..settings()
+        };
+        let repo = MockDataEntriesRepo::new();
+
+        assert_eq!(starting_height(&repo, &settings).unwrap(), 1);
+        for height in 1..=3 {
+            let id = format!("b{}", height);
+            handle(
+                &repo,
+                vec![block(&id, height, vec![write("k", height as i64)])],
+            );
+        }
+
+        assert_eq!(starting_height(&repo, &settings).unwrap(), 3);
+        assert_eq!(repo.state().blocks_microblocks.len(), 2);
+    }
+
+    #[test]
+    fn start_advances_the_uid_sequence_past_the_cursor() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+        repo.transaction(|ops| ops.set_next_update_uid(1)).unwrap();
+
+        starting_height(&repo, &settings()).unwrap();
+
+        assert_eq!(repo.state().next_update_uid, 3);
+    }
+
+    #[test]
+    fn uid_sequence_behind_the_data_entries_is_advanced() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+        repo.transaction(|ops| ops.set_next_update_uid(1)).unwrap();
+
+        repo.transaction(reconcile_uid_seq).unwrap();
+
+        assert_eq!(repo.state().next_update_uid, 3);
+    }
+
+    #[test]
+    fn uid_sequence_ahead_of_the_data_entries_is_kept() {
+        let repo = MockDataEntriesRepo::new();
+        handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+        repo.transaction(|ops| ops.set_next_update_uid(10)).unwrap();
+
+        repo.transaction(reconcile_uid_seq).unwrap();
+
+        assert_eq!(repo.state().next_update_uid, 10);
+    }
+
+    #[test]
+    fn sync_progress_is_the_share_of_the_handled_blocks() {
+        let progress = SyncProgress::new(100, 300);
+
+        assert_eq!(progress.ratio(100), 0.0);
+        assert_eq!(progress.ratio(150), 0.25);
+        assert_eq!(progress.ratio(300), 1.0);
+        // a rollback below the start and a batch past the target are clamped
+        assert_eq!(progress.ratio(50), 0.0);
+        assert_eq!(progress.ratio(400), 1.0);
+    }
+
+    #[test]
+    fn sync_from_the_target_height_is_complete() {
+        assert_eq!(SyncProgress::new(300, 300).ratio(300), 1.0);
+        assert_eq!(SyncProgress::new(400, 300).ratio(400), 1.0);
+    }
+
+    #[test]
+    fn eta_follows_the_rate_of_the_handled_blocks() {
+        assert_eq!(
+            eta(100, Duration::from_secs(10), 1000),
+            Some(Duration::from_secs(100))
+        );
+        assert_eq!(
+            eta(1000, Duration::from_secs(10), 100),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn eta_is_unknown_without_handled_blocks() {
+        assert_eq!(eta(0, Duration::from_secs(10), 1000), None);
+    }
+
+    #[test]
+    fn eta_at_the_target_height_is_zero() {
+        assert_eq!(eta(100, Duration::from_secs(10), 0), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn mode_switches_from_syncing_to_live() {
+        let gauge = IntGauge::new("mode", "mode").unwrap();
+        let mut mode = ModeTracker::new(10.0, gauge.clone());
+
+        assert!(!mode.update(1));
+        assert_eq!(gauge.get(), 0);
+
+        // 99 blocks within the first minute
+        assert!(mode.update(100));
+        assert_eq!(gauge.get(), 1);
+
+        // a block followed by its microblocks is live once the first batch leaves the window
+        for _ in 2..MODE_WINDOW_BATCHES {
+            assert!(mode.update(101));
+        }
+        assert!(!mode.update(101));
+        assert_eq!(gauge.get(), 0);
+    }
+
+    /// Scenarios of the in-memory repo against Postgres, which runs the SQL of `PgDataEntriesRepo`.
+    /// They need a database, see the README.
+    mod pg {
+        use super::*;
+        use crate::data_entries::repo::PgDataEntriesRepo;
+        use crate::data_entries::{
+            BlockTransaction, Lease, LEASE_STATUS_ACTIVE, LEASE_STATUS_CANCELED, VALUE_TYPE_BINARY,
+            VALUE_TYPE_BOOL, VALUE_TYPE_DELETED, VALUE_TYPE_INTEGER, VALUE_TYPE_STRING,
+        };
+        use crate::db::testing::TestDatabase;
+        use crate::schema::{
+            blocks_microblocks, consumer_state, data_entries, data_entries_history_keys,
+            data_entries_last_writes, leases, transactions,
+        };
+        use diesel::prelude::*;
+
+        fn handle_with(
+            repo: &PgDataEntriesRepo,
+            updates: Vec<BlockchainUpdate>,
+            settings: &Settings,
+        ) {
+            repo.transaction(|ops| handle_updates(ops, updates, settings))
+                .unwrap();
+        }
+
+        fn handle(repo: &PgDataEntriesRepo, updates: Vec<BlockchainUpdate>) {
+            handle_with(repo, updates, &settings());
+        }
+
+        fn query<R>(
+            repo: &PgDataEntriesRepo,
+            f: impl FnOnce(&mut PgConnection) -> QueryResult<R>,
+        ) -> R {
+            repo.execute(|mut conn| Ok(f(&mut conn)?)).unwrap()
+        }
+
+        /// Stored versions of the key in uid order, as their value and whether they are live.
+        fn versions(repo: &PgDataEntriesRepo, key: &str) -> Vec<(i64, bool)> {
+            query(repo, |conn| {
+                data_entries::table
+                    .select((data_entries::value_integer, data_entries::superseded_by))
+                    .filter(data_entries::key.eq(key))
+                    .order(data_entries::uid)
+                    .load::<(Option<i64>, i64)>(conn)
+            })
+            .into_iter()
+            .map(|(value, superseded_by)| (value.unwrap(), superseded_by == LIVE_SUPERSEDED_BY))
+            .collect()
+        }
+
+        /// Every version is superseded by the uid of the next one, and the last one is live.
+        fn assert_chained(repo: &PgDataEntriesRepo, key: &str) {
+            let entries = query(repo, |conn| {
+                data_entries::table
+                    .select((data_entries::uid, data_entries::superseded_by))
+                    .filter(data_entries::key.eq(key))
+                    .order(data_entries::uid)
+                    .load::<(i64, i64)>(conn)
+            });
+            for (prev, next) in entries.iter().tuple_windows() {
+                assert_eq!(prev.1, next.0, "{} is not chained", key);
+            }
+            if let Some(last) = entries.last() {
+                assert_eq!(last.1, LIVE_SUPERSEDED_BY);
+            }
+        }
+
+        fn blocks(repo: &PgDataEntriesRepo) -> Vec<(i64, String, i32)> {
+            query(repo, |conn| {
+                blocks_microblocks::table
+                    .select((
+                        blocks_microblocks::uid,
+                        blocks_microblocks::id,
+                        blocks_microblocks::height,
+                    ))
+                    .order(blocks_microblocks::uid)
+                    .load(conn)
+            })
+        }
+
+        fn block_uid(repo: &PgDataEntriesRepo, id: &str) -> i64 {
+            repo.execute(|mut ops| ops.get_block_uid(id))
+                .unwrap()
+                .unwrap()
+        }
+
+        /// Heights of the history keys of the key in the order of their data entries.
+        fn history_heights(repo: &PgDataEntriesRepo, key: &str) -> Vec<Option<i32>> {
+            query(repo, |conn| {
+                data_entries_history_keys::table
+                    .select(data_entries_history_keys::height)
+                    .filter(data_entries_history_keys::key.eq(key))
+                    .order(data_entries_history_keys::data_entry_uid)
+                    .load(conn)
+            })
+        }
+
+        fn last_write_height(repo: &PgDataEntriesRepo, key: &str) -> Option<i32> {
+            query(repo, |conn| {
+                data_entries_last_writes::table
+                    .select(data_entries_last_writes::height)
+                    .filter(data_entries_last_writes::address.eq(ADDRESS))
+                    .filter(data_entries_last_writes::key.eq(key))
+                    .first(conn)
+                    .optional()
+            })
+        }
+
+        fn append_three_blocks(repo: &PgDataEntriesRepo, settings: &Settings) {
+            for height in 1..=3 {
+                let id = format!("b{}", height);
+                handle_with(
+                    repo,
+                    vec![block(&id, height, vec![write("k", height as i64)])],
+                    settings,
+                );
+            }
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn appended_versions_are_chained() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            append_three_blocks(&repo, &settings());
+
+            assert_eq!(
+                versions(&repo, "k"),
+                vec![(1, false), (2, false), (3, true)]
+            );
+            assert_chained(&repo, "k");
+            assert_eq!(history_heights(&repo, "k"), vec![Some(1), Some(2), Some(3)]);
+            assert_eq!(last_write_height(&repo, "k"), Some(3));
+            // the reserved uids are taken from the sequence
+            assert_eq!(
+                repo.execute(|mut ops| ops.get_next_update_uid()).unwrap(),
+                4
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn copied_versions_are_the_same_as_inserted() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let settings = Settings {
+                use_copy_insert: true,
+                ..settings()
+            };
+            append_three_blocks(&repo, &settings);
+
+            assert_eq!(
+                versions(&repo, "k"),
+                vec![(1, false), (2, false), (3, true)]
+            );
+            assert_chained(&repo, "k");
+            assert_eq!(history_heights(&repo, "k"), vec![Some(1), Some(2), Some(3)]);
+            assert_eq!(last_write_height(&repo, "k"), Some(3));
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn rollback_reopens_the_previous_version() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+            handle(
+                &repo,
+                vec![block("b2", 2, vec![write("k", 2), write("new", 1)])],
+            );
+            handle(&repo, vec![BlockchainUpdate::Rollback("b1".to_string(), 1)]);
+
+            assert_eq!(versions(&repo, "k"), vec![(1, true)]);
+            assert!(versions(&repo, "new").is_empty());
+            assert_eq!(history_heights(&repo, "k"), vec![Some(1)]);
+            assert!(history_heights(&repo, "new").is_empty());
+            assert_eq!(last_write_height(&repo, "k"), Some(1));
+            assert_eq!(last_write_height(&repo, "new"), None);
+            assert_eq!(
+                blocks(&repo).into_iter().map(|b| b.1).collect_vec(),
+                vec!["b1"]
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn microblock_rollback_deletes_only_the_later_microblocks() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+            handle(&repo, vec![microblock("m1", 1, vec![write("k", 2)])]);
+            handle(
+                &repo,
+                vec![microblock("m2", 1, vec![write("k", 3), write("new", 1)])],
+            );
+
+            let target = block_uid(&repo, "m1");
+            let rolled_back = repo.transaction(|ops| rollback(ops, target, true)).unwrap();
+
+            assert!(rolled_back.microblocks_only);
+            assert_eq!(rolled_back.deleted_data_entries, 2);
+            assert_eq!(versions(&repo, "k"), vec![(1, false), (2, true)]);
+            assert!(versions(&repo, "new").is_empty());
+            assert_chained(&repo, "k");
+            assert_eq!(
+                blocks(&repo).into_iter().map(|b| b.1).collect_vec(),
+                vec!["b1", "m1"]
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn microblocks_are_squashed_into_their_key_block() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+            handle(&repo, vec![microblock("m1", 1, vec![write("k", 2)])]);
+            handle(&repo, vec![microblock("m2", 1, vec![write("other", 1)])]);
+            handle(&repo, vec![block("b2", 2, vec![write("k", 3)])]);
+
+            // the key block keeps its uid and takes the id of the last microblock
+            let blocks = blocks(&repo);
+            let key_block_uid = blocks[0].0;
+            assert_eq!(
+                blocks.iter().map(|b| (b.1.as_str(), b.2)).collect_vec(),
+                vec![("m2", 1), ("b2", 2)]
+            );
+            let squashed = query(&repo, |conn| {
+                data_entries::table
+                    .select(data_entries::block_uid)
+                    .filter(data_entries::value_integer.ne(3))
+                    .load::<i64>(conn)
+            });
+            assert_eq!(squashed, vec![key_block_uid; 3]);
+            let squashed_history = query(&repo, |conn| {
+                data_entries_history_keys::table
+                    .select(data_entries_history_keys::block_uid)
+                    .filter(data_entries_history_keys::block_uid.ne(blocks[1].0))
+                    .load::<i64>(conn)
+            });
+            assert_eq!(squashed_history, vec![key_block_uid; 3]);
+            assert_eq!(
+                versions(&repo, "k"),
+                vec![(1, false), (2, false), (3, true)]
+            );
+            assert_chained(&repo, "k");
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn microblocks_without_a_key_block_are_left_as_they_are() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            handle(&repo, vec![microblock("m1", 1, vec![write("k", 1)])]);
+            handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+
+            let blocks = blocks(&repo);
+            assert_eq!(
+                blocks.iter().map(|b| b.1.as_str()).collect_vec(),
+                vec!["m1", "b2"]
+            );
+            let block_uids = query(&repo, |conn| {
+                data_entries::table
+                    .select(data_entries::block_uid)
+                    .order(data_entries::uid)
+                    .load::<i64>(conn)
+            });
+            assert_eq!(block_uids, vec![blocks[0].0, blocks[1].0]);
+            assert_chained(&repo, "k");
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn block_appended_twice_is_written_once() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let b1 = [append("b1", 1, true, vec![write("k", 1)])];
+            for _ in 0..2 {
+                repo.transaction(|ops| {
+                    append_blocks_or_microblocks(ops, &b1, &settings(), &mut BatchCounts::default())
+                })
+                .unwrap();
+            }
+
+            assert_eq!(versions(&repo, "k"), vec![(1, true)]);
+            assert_eq!(history_heights(&repo, "k"), vec![Some(1)]);
+            assert_eq!(blocks(&repo).len(), 1);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn last_write_is_updated_and_corrected_by_a_rollback() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            handle(&repo, vec![block("b1", 1, vec![write("k", 1)])]);
+            handle(&repo, vec![block("b2", 2, vec![write("other", 2)])]);
+            handle(
+                &repo,
+                vec![block("b3", 3, vec![write("k", 3), write("new", 3)])],
+            );
+
+            assert_eq!(last_write_height(&repo, "k"), Some(3));
+            assert_eq!(last_write_height(&repo, "new"), Some(3));
+
+            handle(&repo, vec![BlockchainUpdate::Rollback("b2".to_string(), 2)]);
+
+            assert_eq!(last_write_height(&repo, "k"), Some(1));
+            assert_eq!(last_write_height(&repo, "other"), Some(2));
+            assert_eq!(last_write_height(&repo, "new"), None);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn last_writes_are_not_tracked_when_disabled() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let settings = Settings {
+                track_last_writes: false,
+                ..settings()
+            };
+            append_three_blocks(&repo, &settings);
+
+            assert_eq!(last_write_height(&repo, "k"), None);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn cancel_of_a_lease_is_rolled_back() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let lease = |transaction_id: &str, status: &str| Lease {
+                lease_id: "lease".to_string(),
+                transaction_id: transaction_id.to_string(),
+                sender: "3PSender".to_string(),
+                recipient: "3PRecipient".to_string(),
+                amount: 100,
+                status: status.to_string(),
+            };
+            let with_lease = |id: &str, height: u32, lease: Lease| {
+                BlockchainUpdate::Block(BlockMicroblockAppend {
+                    leases: vec![lease],
+                    ..append(id, height, true, vec![])
+                })
+            };
+            let leases = |repo: &PgDataEntriesRepo| {
+                query(repo, |conn| {
+                    leases::table
+                        .select((leases::transaction_id, leases::status))
+                        .order(leases::uid)
+                        .load::<(String, String)>(conn)
+                })
+            };
+            handle(
+                &repo,
+                vec![with_lease("b1", 1, lease("create", LEASE_STATUS_ACTIVE))],
+            );
+            handle(
+                &repo,
+                vec![with_lease("b2", 2, lease("cancel", LEASE_STATUS_CANCELED))],
+            );
+
+            assert_eq!(
+                leases(&repo),
+                vec![
+                    ("create".to_string(), LEASE_STATUS_ACTIVE.to_string()),
+                    ("cancel".to_string(), LEASE_STATUS_CANCELED.to_string())
+                ]
+            );
+
+            handle(&repo, vec![BlockchainUpdate::Rollback("b1".to_string(), 1)]);
+
+            assert_eq!(
+                leases(&repo),
+                vec![("create".to_string(), LEASE_STATUS_ACTIVE.to_string())]
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn value_type_is_set_for_each_value_kind() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            handle(
+                &repo,
+                vec![block(
+                    "b1",
+                    1,
+                    vec![
+                        write_value("binary", DataEntryValue::Binary(vec![1])),
+                        write_value("bool", DataEntryValue::Bool(false)),
+                        write_value("deleted", DataEntryValue::Deleted),
+                        write_value("integer", DataEntryValue::Integer(0)),
+                        write_value("string", DataEntryValue::String(String::new())),
+                    ],
+                )],
+            );
+
+            let value_types = query(&repo, |conn| {
+                data_entries::table
+                    .select((data_entries::key, data_entries::value_type))
+                    .order(data_entries::key)
+                    .load::<(String, Option<String>)>(conn)
+            });
+
+            assert_eq!(
+                value_types,
+                [
+                    ("binary", VALUE_TYPE_BINARY),
+                    ("bool", VALUE_TYPE_BOOL),
+                    ("deleted", VALUE_TYPE_DELETED),
+                    ("integer", VALUE_TYPE_INTEGER),
+                    ("string", VALUE_TYPE_STRING),
+                ]
+                .map(|(key, value_type)| (key.to_string(), Some(value_type.to_string())))
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn transactions_are_inserted_and_rolled_back() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let with_transactions = |id: &str, height: u32, transactions: Vec<(&str, i16)>| {
+                BlockchainUpdate::Block(BlockMicroblockAppend {
+                    transactions: transactions
+                        .into_iter()
+                        .map(|(tx_id, tx_type)| BlockTransaction {
+                            tx_id: tx_id.to_string(),
+                            tx_type,
+                        })
+                        .collect(),
+                    ..append(id, height, true, vec![])
+                })
+            };
+            let transactions = |repo: &PgDataEntriesRepo| {
+                query(repo, |conn| {
+                    transactions::table
+                        .select((
+                            transactions::tx_id,
+                            transactions::height,
+                            transactions::tx_type,
+                        ))
+                        .order(transactions::tx_id)
+                        .load::<(String, i32, i16)>(conn)
+                })
+            };
+            handle(
+                &repo,
+                vec![with_transactions("b1", 1, vec![("t1", 4), ("t2", 16)])],
+            );
+            handle(&repo, vec![with_transactions("b2", 2, vec![("t3", 12)])]);
+
+            assert_eq!(
+                transactions(&repo),
+                vec![
+                    ("t1".to_string(), 1, 4),
+                    ("t2".to_string(), 1, 16),
+                    ("t3".to_string(), 2, 12)
+                ]
+            );
+
+            handle(&repo, vec![BlockchainUpdate::Rollback("b1".to_string(), 1)]);
+
+            assert_eq!(
+                transactions(&repo),
+                vec![("t1".to_string(), 1, 4), ("t2".to_string(), 1, 16)]
+            );
+            let stored = repo
+                .execute(|mut ops| Ok((ops.get_transaction("t2")?, ops.get_transaction("t3")?)))
+                .unwrap();
+            assert_eq!(stored.0.map(|tx| (tx.height, tx.tx_type)), Some((1, 16)));
+            assert!(stored.1.is_none());
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn keys_of_every_chunk_are_closed() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let settings = Settings {
+                close_superseded_by_chunk_size: 2,
+                ..settings()
+            };
+            let keys = ["k1", "k2", "k3", "k4", "k5"];
+            for height in 1..=2 {
+                let writes = keys
+                    .iter()
+                    .map(|key| write(key, height as i64))
+                    .collect_vec();
+                let id = format!("b{}", height);
+                handle_with(&repo, vec![block(&id, height, writes)], &settings);
+            }
+
+            // 5 keys are closed by 3 statements
+            for key in keys {
+                assert_eq!(versions(&repo, key), vec![(1, false), (2, true)]);
+                assert_chained(&repo, key);
+            }
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn uid_sequence_behind_the_data_entries_is_advanced() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            append_three_blocks(&repo, &settings());
+            repo.transaction(|ops| ops.set_next_update_uid(1)).unwrap();
+
+            repo.transaction(reconcile_uid_seq).unwrap();
+
+            assert_eq!(
+                repo.execute(|mut ops| ops.get_next_update_uid()).unwrap(),
+                4
+            );
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        async fn pending_batches_are_written_before_a_rollback_to_them() {
+            let db = TestDatabase::create();
+            let repo = Arc::new(PgDataEntriesRepo::new(db.pool.clone()));
+            let batch =
+                |last_height: u32, update: BlockchainUpdate| BlockchainUpdatesWithLastHeight {
+                    last_height,
+                    updates: vec![update],
+                };
+            // the rollback targets a block of the same group, which is not committed yet
+            let batches = vec![
+                batch(1, block("b1", 1, vec![write("k", 1)])),
+                batch(2, block("b2", 2, vec![write("k", 2)])),
+                batch(3, block("b3", 3, vec![write("k", 3)])),
+                batch(2, BlockchainUpdate::Rollback("b2".to_string(), 2)),
+                batch(3, block("b3'", 3, vec![write("k", 4)])),
+            ];
+            let settings = Settings {
+                commit_every_n_batches: 10,
+                commit_max_wait_secs: 60,
+                sync_to_height: Some(3),
+                ..settings()
+            };
+
+            let _consumer = CONSUMER.lock().await;
+            start(
+                ScriptedSource(batches),
+                repo.clone(),
+                None,
+                settings,
+                signals(),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                blocks(&repo).into_iter().map(|b| (b.1, b.2)).collect_vec(),
+                vec![
+                    ("b1".to_string(), 1),
+                    ("b2".to_string(), 2),
+                    ("b3'".to_string(), 3)
+                ]
+            );
+            assert_eq!(
+                versions(&repo, "k"),
+                vec![(1, false), (2, false), (4, true)]
+            );
+            assert_chained(&repo, "k");
+            assert_eq!(history_heights(&repo, "k"), vec![Some(1), Some(2), Some(3)]);
+            let cursor = query(&repo, |conn| {
+                consumer_state::table
+                    .select(consumer_state::last_height)
+                    .first::<i32>(conn)
+            });
+            assert_eq!(cursor, 3);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn reset_to_height_is_done_once() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let settings = Settings {
+                reset_to_height: Some(1),
+                ..settings()
+            };
+            append_three_blocks(&repo, &super::settings());
+
+            assert_eq!(starting_height(&repo, &settings).unwrap(), 2);
+            assert_eq!(versions(&repo, "k"), vec![(1, true)]);
+            let applied = query(&repo, |conn| {
+                consumer_state::table
+                    .select(consumer_state::applied_reset_to_height)
+                    .first::<Option<i32>>(conn)
+            });
+            assert_eq!(applied, Some(1));
+
+            handle(&repo, vec![block("b2", 2, vec![write("k", 2)])]);
+            handle(&repo, vec![block("b3", 3, vec![write("k", 3)])]);
+
+            // the next start only rolls back `start_rollback_depth`
+            assert_eq!(starting_height(&repo, &settings).unwrap(), 3);
+            assert_eq!(versions(&repo, "k"), vec![(1, false), (2, true)]);
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use crate::logging::info;
+use anyhow::Result;
+
+use super::DataEntriesSource;
+
+/// Run extraction for exactly one block and log the parsed updates without touching the database.
+pub async fn dump_block<T>(updates_src: T, height: u32) -> Result<()>
+where
+    T: DataEntriesSource + Send + Sync + 'static,
+{
+    let updates = updates_src.block(height).await?;
+
+    info!(
+        "{} updates were received for height {}",
+        updates.len(),
+        height
+    );
+
+    updates.iter().for_each(|update| info!("{:#?}", update));
+
+    Ok(())
+}
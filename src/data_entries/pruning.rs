@@ -0,0 +1,294 @@
+use crate::logging::{error, info};
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{DataEntriesRepo, DataEntriesRepoOperations};
+
+const PRUNING_INTERVAL: Duration = Duration::from_secs(600);
+const HISTORY_COMPACTION_INTERVAL: Duration = Duration::from_secs(600);
+/// Superseded versions deleted in one transaction.
+const HISTORY_COMPACTION_BATCH_SIZE: i64 = 10000;
+/// Batches deleted per run, the rest is left for the next one.
+const HISTORY_COMPACTION_MAX_BATCHES: usize = 100;
+
+/// Periodically remove old blocks which are not referenced anymore.
+/// A failed run, e.g. on a lost connection, is retried on the next interval, so this never ends.
+pub async fn start<U: DataEntriesRepo>(dbw: Arc<U>, retention_depth: u32) {
+    loop {
+        if let Err(err) = dbw.transaction(|ops| prune_blocks_microblocks(ops, retention_depth)) {
+            error!(
+                "Pruning failed, it is retried in {:?}: {:#}",
+                PRUNING_INTERVAL, err
+            );
+        }
+
+        tokio::time::sleep(PRUNING_INTERVAL).await;
+    }
+}
+
+fn prune_blocks_microblocks<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    retention_depth: u32,
+) -> Result<()> {
+    let retention_height = match dbw.get_last_height()? {
+        Some(last_height) => last_height - retention_depth as i32,
+        None => return Ok(()),
+    };
+
+    if retention_height > 0 {
+        let deleted = dbw.delete_unreferenced_blocks_microblocks(retention_height)?;
+
+        info!(
+            "{} unreferenced blocks below height {} were pruned",
+            deleted, retention_height
+        );
+    }
+
+    Ok(())
+}
+
+/// Periodically remove versions which were superseded more than `retention_blocks` ago.
+/// Historical queries cannot go below the retention window after that,
+/// the live versions are never removed.
+/// A failed run is retried on the next interval, so this never ends.
+pub async fn start_history_compaction<U: DataEntriesRepo>(dbw: Arc<U>, retention_blocks: u32) {
+    loop {
+        match compact_history_batches(dbw.as_ref(), retention_blocks) {
+            Ok(deleted) => info!(
+                "{} data entries superseded more than {} blocks ago were deleted",
+                deleted, retention_blocks
+            ),
+            // the batches deleted before the failure are committed already
+            Err(err) => error!(
+                "History compaction failed, it is retried in {:?}: {:#}",
+                HISTORY_COMPACTION_INTERVAL, err
+            ),
+        }
+
+        tokio::time::sleep(HISTORY_COMPACTION_INTERVAL).await;
+    }
+}
+
+/// Every batch is deleted in a transaction of its own, so the locks are short.
+fn compact_history_batches<U: DataEntriesRepo>(dbw: &U, retention_blocks: u32) -> Result<usize> {
+    let mut deleted = 0;
+
+    for _ in 0..HISTORY_COMPACTION_MAX_BATCHES {
+        let batch = dbw.transaction(|ops| compact_history(ops, retention_blocks))?;
+        deleted += batch;
+        if batch < HISTORY_COMPACTION_BATCH_SIZE as usize {
+            break;
+        }
+    }
+
+    Ok(deleted)
+}
+
+fn compact_history<U: DataEntriesRepoOperations>(
+    dbw: &mut U,
+    retention_blocks: u32,
+) -> Result<usize> {
+    let retention_height = match dbw.get_last_height()? {
+        Some(last_height) => last_height - retention_blocks as i32,
+        None => return Ok(0),
+    };
+
+    // versions superseded not above the retention height are not visible within the window
+    match dbw.get_last_block_uid_below(retention_height + 1)? {
+        Some(block_uid) => {
+            dbw.delete_superseded_data_entries(&block_uid, HISTORY_COMPACTION_BATCH_SIZE)
+        }
+        None => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_entries::testing::MockDataEntriesRepo;
+    use crate::data_entries::{
+        BlockMicroblock, DataEntry, DataEntryUpdate, DataEntryValue, InsertableDataEntry,
+        LIVE_SUPERSEDED_BY,
+    };
+    use itertools::Itertools;
+
+    const ADDRESS: &str = "3PAddress";
+
+    /// Append a key block writing the keys, every write supersedes the live version of its key.
+    fn append_block<U: DataEntriesRepo>(repo: &U, height: i32, keys: &[&str]) {
+        repo.transaction(|ops| {
+            let block_uid = ops.insert_blocks_or_microblocks(&[BlockMicroblock {
+                id: format!("b{}", height),
+                time_stamp: Some(height as i64 * 60_000),
+                height,
+            }])?[0]
+                .unwrap();
+            for key in keys {
+                let uid = ops.reserve_update_uids(1)?;
+                ops.close_superseded_by(&[DataEntryUpdate {
+                    superseded_by: uid,
+                    address: ADDRESS.to_string(),
+                    key: key.to_string(),
+                }])?;
+                let mut entry = InsertableDataEntry::new(
+                    block_uid,
+                    uid,
+                    DataEntry {
+                        address: ADDRESS.to_string(),
+                        key: key.to_string(),
+                        transaction_id: format!("tx-{}-{}", key, height),
+                        value: DataEntryValue::Integer(height as i64),
+                    },
+                    Default::default(),
+                    Default::default(),
+                );
+                entry.superseded_by = LIVE_SUPERSEDED_BY;
+                ops.insert_data_entries(vec![entry], 1)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn block_ids(repo: &MockDataEntriesRepo) -> Vec<String> {
+        repo.state()
+            .blocks_microblocks
+            .iter()
+            .map(|b| b.id.clone())
+            .collect_vec()
+    }
+
+    #[test]
+    fn blocks_are_removed_only_when_nothing_references_them() {
+        let repo = MockDataEntriesRepo::new();
+        append_block(&repo, 1, &["k", "j"]);
+        for height in 2..=5 {
+            append_block(&repo, height, &["k"]);
+        }
+
+        // every old block is still referenced by a superseded version
+        repo.transaction(|ops| prune_blocks_microblocks(ops, 2))
+            .unwrap();
+        assert_eq!(block_ids(&repo), vec!["b1", "b2", "b3", "b4", "b5"]);
+
+        // versions of heights 1 and 2 are superseded within the retention window,
+        // the live version of `j` still references the first block
+        let deleted = repo.transaction(|ops| compact_history(ops, 2)).unwrap();
+        assert_eq!(deleted, 2);
+        repo.transaction(|ops| prune_blocks_microblocks(ops, 2))
+            .unwrap();
+        assert_eq!(block_ids(&repo), vec!["b1", "b3", "b4", "b5"]);
+    }
+
+    #[test]
+    fn compaction_keeps_the_versions_within_the_window() {
+        let repo = MockDataEntriesRepo::new();
+        for height in 1..=5 {
+            append_block(&repo, height, &["k"]);
+        }
+
+        assert_eq!(compact_history_batches(&repo, 2).unwrap(), 2);
+        // the next run has nothing left to delete
+        assert_eq!(compact_history_batches(&repo, 2).unwrap(), 0);
+
+        let values = repo
+            .state()
+            .data_entries
+            .iter()
+            .map(|de| {
+                (
+                    de.value_integer.unwrap(),
+                    de.superseded_by == LIVE_SUPERSEDED_BY,
+                )
+            })
+            .sorted()
+            .collect_vec();
+        assert_eq!(values, vec![(3, false), (4, false), (5, true)]);
+    }
+
+    /// The same scenario against Postgres, see the README on how to run it.
+    mod pg {
+        use super::*;
+        use crate::data_entries::repo::PgDataEntriesRepo;
+        use crate::db::testing::TestDatabase;
+        use crate::schema::{blocks_microblocks, data_entries, data_entries_history_keys};
+        use diesel::prelude::*;
+
+        fn query<R>(
+            repo: &PgDataEntriesRepo,
+            f: impl FnOnce(&mut PgConnection) -> QueryResult<R>,
+        ) -> R {
+            repo.execute(|mut conn| Ok(f(&mut conn)?)).unwrap()
+        }
+
+        fn block_ids(repo: &PgDataEntriesRepo) -> Vec<String> {
+            query(repo, |conn| {
+                blocks_microblocks::table
+                    .select(blocks_microblocks::id)
+                    .order(blocks_microblocks::uid)
+                    .load(conn)
+            })
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn blocks_are_removed_only_when_nothing_references_them() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            append_block(&repo, 1, &["k", "j"]);
+            for height in 2..=5 {
+                append_block(&repo, height, &["k"]);
+            }
+
+            repo.transaction(|ops| prune_blocks_microblocks(ops, 2))
+                .unwrap();
+            assert_eq!(block_ids(&repo), vec!["b1", "b2", "b3", "b4", "b5"]);
+
+            assert_eq!(compact_history_batches(&repo, 2).unwrap(), 2);
+            assert_eq!(compact_history_batches(&repo, 2).unwrap(), 0);
+            let values = query(&repo, |conn| {
+                data_entries::table
+                    .select((
+                        data_entries::key,
+                        data_entries::value_integer,
+                        data_entries::superseded_by,
+                    ))
+                    .order(data_entries::uid)
+                    .load::<(String, Option<i64>, i64)>(conn)
+            })
+            .into_iter()
+            .map(|(key, value, superseded_by)| {
+                (key, value.unwrap(), superseded_by == LIVE_SUPERSEDED_BY)
+            })
+            .collect_vec();
+            assert_eq!(
+                values,
+                vec![
+                    ("j".to_string(), 1, true),
+                    ("k".to_string(), 3, false),
+                    ("k".to_string(), 4, false),
+                    ("k".to_string(), 5, true),
+                ]
+            );
+            // the history keys of the deleted versions are deleted with them
+            let history_uids = query(&repo, |conn| {
+                data_entries_history_keys::table
+                    .select(data_entries_history_keys::data_entry_uid)
+                    .order(data_entries_history_keys::data_entry_uid)
+                    .load::<i64>(conn)
+            });
+            let uids = query(&repo, |conn| {
+                data_entries::table
+                    .select(data_entries::uid)
+                    .order(data_entries::uid)
+                    .load::<i64>(conn)
+            });
+            assert_eq!(history_uids, uids);
+
+            repo.transaction(|ops| prune_blocks_microblocks(ops, 2))
+                .unwrap();
+            assert_eq!(block_ids(&repo), vec!["b1", "b3", "b4", "b5"]);
+        }
+    }
+}
@@ -1,21 +1,107 @@
 use super::{
-    BlockMicroblock, DataEntryUpdate, DeletedDataEntry, InsertableDataEntry, InsertedDataEntry,
-    PrevHandledHeight,
+    BlockMicroblock, ConsumerState, DataEntry, DataEntryUpdate, DataEntryVersion, DeletedDataEntry,
+    FragmentFilter, InsertableContractEvent, InsertableDataEntry, InsertableLease,
+    InsertableTransaction, InsertableTransfer, InsertedDataEntry, PrevHandledHeight,
+    StoredTransaction, FRAGMENT_COUNT, LIVE_SUPERSEDED_BY, VALUE_TYPE_BINARY, VALUE_TYPE_BOOL,
+    VALUE_TYPE_DELETED, VALUE_TYPE_INTEGER, VALUE_TYPE_STRING,
 };
 pub use super::{DataEntriesRepo, DataEntriesRepoOperations};
 use crate::db::{PgPool, PooledPgConnection};
 use crate::error::AppError;
+use crate::logging::warn;
 use crate::schema::blocks_microblocks;
 use crate::schema::blocks_microblocks::dsl::*;
+use crate::schema::consumer_state;
+use crate::schema::contract_events;
 use crate::schema::data_entries;
 use crate::schema::data_entries_history_keys;
 use crate::schema::data_entries_uid_seq;
 use crate::schema::data_entries_uid_seq::dsl::*;
+use crate::schema::leases;
+use crate::schema::transactions;
+use crate::schema::transfers;
 use anyhow::{Error, Result};
 use diesel::prelude::*;
-use diesel::sql_types::{Array, BigInt, VarChar};
+use diesel::sql_types::{Array, BigInt, Integer, Nullable, VarChar};
+use itertools::{Itertools, MinMaxResult};
+use std::collections::HashMap;
+use std::sync::OnceLock;
 
-const MAX_UID: i64 = std::i64::MAX - 1;
+/// Postgres cannot bind more parameters to one statement.
+const MAX_BIND_PARAMS: usize = 65535;
+
+/// Ids, address, key, the value columns with the value type
+/// and both integer and string columns of every key and value fragment.
+const INSERTED_DATA_ENTRY_COLUMNS: usize = 11 + 4 * FRAGMENT_COUNT;
+
+/// Number of data entries inserted by one statement, the largest one
+/// within the bind parameters limit unless `requested` is lower.
+pub fn insert_chunk_size(requested: Option<usize>) -> usize {
+    let max_chunk_size = MAX_BIND_PARAMS / INSERTED_DATA_ENTRY_COLUMNS;
+    match requested {
+        Some(requested) if requested > max_chunk_size => {
+            warn!(
+                "Insert chunk size {} exceeds the bind parameters limit, {} is used instead",
+                requested, max_chunk_size
+            );
+            max_chunk_size
+        }
+        Some(requested) => requested.max(1),
+        None => max_chunk_size,
+    }
+}
+
+static TABLE_PREFIX: OnceLock<String> = OnceLock::new();
+
+/// Set the prefix of the tables named by the raw `sql_query` statements, e.g. `mainnet_`,
+/// so consumers of several chains can share one database. It is set once, before the repo is used.
+///
+/// The tables of the diesel DSL queries are hardcoded by the generated schema and are not
+/// prefixed. A prefixed deployment needs the unprefixed names to resolve to its own tables,
+/// e.g. through views in the schema of `PGSCHEMA`, until those queries are moved to raw SQL too.
+pub fn set_table_prefix(prefix: &str) {
+    if TABLE_PREFIX.set(prefix.to_string()).is_err() {
+        warn!("Table prefix is already set, {:?} is ignored", prefix);
+    }
+}
+
+/// Name of a table or a sequence in the raw queries, with the configured prefix.
+pub(crate) fn table(name: &str) -> String {
+    format!("{}{}", TABLE_PREFIX.get().map_or("", String::as_str), name)
+}
+
+/// Key of the advisory lock held by a transaction which reserved uids.
+const UID_RESERVATION_LOCK: i64 = 0x5ca1ab1e;
+
+#[derive(QueryableByName)]
+struct ValueTypeValidated {
+    #[diesel(sql_type = diesel::sql_types::Bool)]
+    validated: bool,
+}
+
+#[derive(QueryableByName)]
+struct ReservedUids {
+    #[diesel(sql_type = BigInt)]
+    first_uid: i64,
+}
+
+const DATA_ENTRY_COLUMNS: (
+    data_entries::address,
+    data_entries::key,
+    data_entries::transaction_id,
+    data_entries::value_binary,
+    data_entries::value_bool,
+    data_entries::value_integer,
+    data_entries::value_string,
+) = (
+    data_entries::address,
+    data_entries::key,
+    data_entries::transaction_id,
+    data_entries::value_binary,
+    data_entries::value_bool,
+    data_entries::value_integer,
+    data_entries::value_string,
+);
 
 pub struct PgDataEntriesRepo {
     pool: PgPool,
@@ -27,7 +113,9 @@ impl PgDataEntriesRepo {
     }
 
     pub fn get_conn(&self) -> Result<PooledPgConnection> {
-        Ok(self.pool.get()?)
+        self.pool
+            .get()
+            .map_err(|err| Error::new(AppError::ConnectionPoolTimeout(err)))
     }
 }
 
@@ -57,7 +145,12 @@ impl DataEntriesRepo for PgDataEntriesRepo {
 
 impl DataEntriesRepoOperations for PooledPgConnection {
     fn get_handled_height(&mut self, depth: u32) -> Result<Option<PrevHandledHeight>> {
-        let sql_height = format!("(select max(height) - {} from blocks_microblocks)", depth);
+        let sql_height = format!(
+            "(select coalesce((select last_height from {}), max(height)) - {} from {})",
+            table("consumer_state"),
+            depth,
+            table("blocks_microblocks")
+        );
 
         blocks_microblocks
             .select((blocks_microblocks::uid, blocks_microblocks::height))
@@ -69,26 +162,319 @@ impl DataEntriesRepoOperations for PooledPgConnection {
             .order(blocks_microblocks::uid.asc())
             .first(self)
             .optional()
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn get_consumer_state(&mut self) -> Result<Option<ConsumerState>> {
+        consumer_state::table
+            .select((consumer_state::last_height, consumer_state::last_uid))
+            .first(self)
+            .optional()
+            .map_err(|err| Error::new(AppError::from(err)).context("Cannot get consumer state."))
+    }
+
+    fn set_consumer_state(&mut self, state: &ConsumerState) -> Result<()> {
+        diesel::sql_query(format!(
+            "insert into {} (id, last_height, last_uid) values (1, $1, $2)
+                on conflict (id) do update set last_height = excluded.last_height, last_uid = excluded.last_uid",
+            table("consumer_state")
+        ))
+        .bind::<Integer, _>(state.last_height)
+        .bind::<Nullable<BigInt>, _>(state.last_uid)
+        .execute(self)
+        .map(|_| ())
+        .map_err(|err| Error::new(AppError::from(err)).context("Cannot set consumer state."))
+    }
+
+    fn get_applied_reset_to_height(&mut self) -> Result<Option<i32>> {
+        consumer_state::table
+            .select(consumer_state::applied_reset_to_height)
+            .first::<Option<i32>>(self)
+            .optional()
+            .map(Option::flatten)
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn set_applied_reset_to_height(&mut self, reset_height: i32) -> Result<()> {
+        diesel::sql_query(format!(
+            "insert into {} (id, last_height, applied_reset_to_height) values (1, 0, $1)
+                on conflict (id) do update set applied_reset_to_height = excluded.applied_reset_to_height",
+            table("consumer_state")
+        ))
+        .bind::<Integer, _>(reset_height)
+        .execute(self)
+        .map(|_| ())
+        .map_err(|err| Error::new(AppError::from(err)))
     }
 
-    fn get_block_uid(&mut self, block_id: &str) -> Result<i64> {
+    fn get_block_uid(&mut self, block_id: &str) -> Result<Option<i64>> {
         blocks_microblocks
             .select(blocks_microblocks::uid)
             .filter(blocks_microblocks::id.eq(block_id))
             .get_result(self)
+            .optional()
             .map_err(|err| {
-                Error::new(AppError::DbError(err))
+                Error::new(AppError::from(err))
                     .context(format!("Cannot get block_uid by block id {}.", block_id))
             })
     }
 
-    fn get_key_block_uid(&mut self) -> Result<i64> {
+    fn get_data_entry(&mut self, address: &str, key: &str) -> Result<Option<DataEntry>> {
+        // a deleted key keeps its deletion as the live version, it is not found
+        data_entries::table
+            .select(DATA_ENTRY_COLUMNS)
+            .filter(data_entries::address.eq(address))
+            .filter(data_entries::key.eq(key))
+            .filter(data_entries::superseded_by.eq(LIVE_SUPERSEDED_BY))
+            .filter(data_entries::value_type.is_distinct_from(VALUE_TYPE_DELETED))
+            .first(self)
+            .optional()
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn get_data_entry_at_height(
+        &mut self,
+        address: &str,
+        key: &str,
+        at_height: i32,
+    ) -> Result<Option<DataEntry>> {
+        // the last version written not above the height is superseded above it or is live
+        let data_entry_uid: Option<i64> = data_entries_history_keys::table
+            .select(data_entries_history_keys::data_entry_uid)
+            .filter(data_entries_history_keys::address.eq(address))
+            .filter(data_entries_history_keys::key.eq(key))
+            .filter(data_entries_history_keys::height.le(at_height))
+            .order((
+                data_entries_history_keys::height.desc(),
+                data_entries_history_keys::data_entry_uid.desc(),
+            ))
+            .first(self)
+            .optional()
+            .map_err(|err| Error::new(AppError::from(err)))?;
+
+        // a key deleted at or below the height is not found
+        match data_entry_uid {
+            Some(data_entry_uid) => data_entries::table
+                .select(DATA_ENTRY_COLUMNS)
+                .filter(data_entries::uid.eq(data_entry_uid))
+                .filter(data_entries::value_type.is_distinct_from(VALUE_TYPE_DELETED))
+                .first(self)
+                .optional()
+                .map_err(|err| Error::new(AppError::from(err))),
+            None => Ok(None),
+        }
+    }
+
+    fn list_data_entries(
+        &mut self,
+        address: &str,
+        after_key: Option<&str>,
+        limit: i64,
+    ) -> Result<(Vec<DataEntry>, Option<String>)> {
+        // deleted keys are left out, so a page is never filled with them
+        let mut query = data_entries::table
+            .select(DATA_ENTRY_COLUMNS)
+            .filter(data_entries::address.eq(address))
+            .filter(data_entries::superseded_by.eq(LIVE_SUPERSEDED_BY))
+            .filter(data_entries::value_type.is_distinct_from(VALUE_TYPE_DELETED))
+            .order(data_entries::key)
+            .into_boxed();
+
+        if let Some(after_key) = after_key {
+            query = query.filter(data_entries::key.gt(after_key));
+        }
+
+        // one more entry is fetched to know whether there is a next page
+        let mut entries: Vec<DataEntry> = query
+            .limit(limit + 1)
+            .load(self)
+            .map_err(|err| Error::new(AppError::from(err)))?;
+
+        let cursor = if entries.len() as i64 > limit {
+            entries.truncate(limit as usize);
+            entries.last().map(|de| de.key.clone())
+        } else {
+            None
+        };
+
+        Ok((entries, cursor))
+    }
+
+    fn get_data_entry_history(
+        &mut self,
+        address: &str,
+        key: &str,
+        after_uid: Option<i64>,
+        limit: i64,
+    ) -> Result<(Vec<DataEntryVersion>, Option<i64>)> {
+        let mut query = data_entries::table
+            .inner_join(
+                data_entries_history_keys::table.on(data_entries_history_keys::data_entry_uid
+                    .eq(data_entries::uid)
+                    .and(data_entries_history_keys::address.eq(data_entries::address))
+                    .and(data_entries_history_keys::key.eq(data_entries::key))),
+            )
+            .select((
+                data_entries::uid,
+                data_entries_history_keys::height,
+                data_entries_history_keys::block_timestamp,
+                DATA_ENTRY_COLUMNS,
+            ))
+            .filter(data_entries::address.eq(address))
+            .filter(data_entries::key.eq(key))
+            .order(data_entries::uid.asc())
+            .into_boxed();
+
+        if let Some(after_uid) = after_uid {
+            query = query.filter(data_entries::uid.gt(after_uid));
+        }
+
+        // one more version is fetched to know whether there is a next page
+        let rows: Vec<(i64, Option<i32>, Option<chrono::NaiveDateTime>, DataEntry)> = query
+            .limit(limit + 1)
+            .load(self)
+            .map_err(|err| Error::new(AppError::from(err)))?;
+
+        let mut versions = rows
+            .into_iter()
+            .map(
+                |(version_uid, version_height, block_timestamp, data_entry)| DataEntryVersion {
+                    uid: version_uid,
+                    height: version_height,
+                    block_timestamp,
+                    data_entry,
+                },
+            )
+            .collect_vec();
+
+        let cursor = if versions.len() as i64 > limit {
+            versions.truncate(limit as usize);
+            versions.last().map(|version| version.uid)
+        } else {
+            None
+        };
+
+        Ok((versions, cursor))
+    }
+
+    fn search_by_fragments(
+        &mut self,
+        filters: &[FragmentFilter],
+        limit: i64,
+        after: Option<i64>,
+    ) -> Result<(Vec<DataEntry>, Option<i64>)> {
+        let mut query = data_entries::table
+            .select((data_entries::uid, DATA_ENTRY_COLUMNS))
+            .filter(data_entries::superseded_by.eq(LIVE_SUPERSEDED_BY))
+            .order(data_entries::uid)
+            .into_boxed();
+
+        // fragment columns are picked by index, which is a number, so it is safe to format
+        for filter in filters {
+            query = match filter {
+                FragmentFilter::String(index, value) => query.filter(
+                    diesel::dsl::sql::<diesel::sql_types::Bool>(&format!(
+                        "fragment_{}_string = ",
+                        index
+                    ))
+                    .bind::<VarChar, _>(value.clone()),
+                ),
+                FragmentFilter::Integer(index, value) => query.filter(
+                    diesel::dsl::sql::<diesel::sql_types::Bool>(&format!(
+                        "fragment_{}_integer = ",
+                        index
+                    ))
+                    .bind::<BigInt, _>(*value),
+                ),
+            };
+        }
+
+        if let Some(after) = after {
+            query = query.filter(data_entries::uid.gt(after));
+        }
+
+        // one more entry is fetched to know whether there is a next page
+        let mut entries: Vec<(i64, DataEntry)> = query
+            .limit(limit + 1)
+            .load(self)
+            .map_err(|err| Error::new(AppError::from(err)))?;
+
+        let cursor = if entries.len() as i64 > limit {
+            entries.truncate(limit as usize);
+            entries.last().map(|(last_uid, _)| *last_uid)
+        } else {
+            None
+        };
+
+        Ok((entries.into_iter().map(|(_, de)| de).collect(), cursor))
+    }
+
+    fn get_block_height(&mut self, block_uid: &i64) -> Result<i32> {
         blocks_microblocks
-            .select(diesel::dsl::sql::<diesel::sql_types::BigInt>("max(uid)"))
+            .select(blocks_microblocks::height)
+            .filter(blocks_microblocks::uid.eq(block_uid))
+            .get_result(self)
+            .map_err(|err| {
+                Error::new(AppError::from(err))
+                    .context(format!("Cannot get height by block uid {}.", block_uid))
+            })
+    }
+
+    fn get_transaction(&mut self, tx_id: &str) -> Result<Option<StoredTransaction>> {
+        transactions::table
+            .select((
+                transactions::tx_id,
+                transactions::height,
+                transactions::tx_type,
+            ))
+            .filter(transactions::tx_id.eq(tx_id))
+            .first(self)
+            .optional()
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn get_key_block_uid(&mut self) -> Result<Option<i64>> {
+        blocks_microblocks
+            .select(diesel::dsl::max(blocks_microblocks::uid))
             .filter(blocks_microblocks::time_stamp.is_not_null())
             .get_result(self)
-            .map_err(|err| Error::new(AppError::DbError(err)).context("Cannot get key block uid."))
+            .map_err(|err| Error::new(AppError::from(err)).context("Cannot get key block uid."))
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        diesel::sql_query("SELECT 1")
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn get_last_height(&mut self) -> Result<Option<i32>> {
+        blocks_microblocks
+            .select(diesel::dsl::max(blocks_microblocks::height))
+            .get_result(self)
+            .map_err(|err| Error::new(AppError::from(err)).context("Cannot get last height."))
+    }
+
+    fn get_last_block_timestamp(&mut self) -> Result<Option<i64>> {
+        blocks_microblocks
+            .select(diesel::dsl::max(blocks_microblocks::time_stamp))
+            .get_result(self)
+            .map_err(|err| {
+                Error::new(AppError::from(err)).context("Cannot get last block timestamp.")
+            })
+    }
+
+    fn get_last_block_uid_below(&mut self, below_height: i32) -> Result<Option<i64>> {
+        blocks_microblocks
+            .select(diesel::dsl::max(blocks_microblocks::uid))
+            .filter(blocks_microblocks::height.lt(below_height))
+            .get_result(self)
+            .map_err(|err| {
+                Error::new(AppError::from(err)).context(format!(
+                    "Cannot get last block uid below height {}.",
+                    below_height
+                ))
+            })
     }
 
     fn get_total_block_id(&mut self) -> Result<Option<String>> {
@@ -98,80 +484,162 @@ impl DataEntriesRepoOperations for PooledPgConnection {
             .order(blocks_microblocks::uid.desc())
             .first(self)
             .optional()
-            .map_err(|err| Error::new(AppError::DbError(err)).context("Cannot get total block id."))
+            .map_err(|err| Error::new(AppError::from(err)).context("Cannot get total block id."))
     }
 
     fn get_next_update_uid(&mut self) -> Result<i64> {
         data_entries_uid_seq
             .select(data_entries_uid_seq::last_value)
             .first(self)
+            .map_err(|err| Error::new(AppError::from(err)).context("Cannot get next update uid."))
+    }
+
+    fn reserve_update_uids(&mut self, count: i64) -> Result<i64> {
+        diesel::sql_query("select pg_advisory_xact_lock($1)")
+            .bind::<BigInt, _>(UID_RESERVATION_LOCK)
+            .execute(self)
+            .map_err(|err| Error::new(AppError::from(err)).context("Cannot lock update uids."))?;
+
+        // is_called is false, so last_value is the next uid to be taken
+        diesel::sql_query(format!(
+            "select setval('{uid_seq}', last_value + $1, false) - $1 as first_uid from {uid_seq}",
+            uid_seq = table("data_entries_uid_seq")
+        ))
+        .bind::<BigInt, _>(count)
+        .get_result::<ReservedUids>(self)
+        .map(|reserved| reserved.first_uid)
+        .map_err(|err| Error::new(AppError::from(err)).context("Cannot reserve update uids."))
+    }
+
+    fn get_last_data_entry_uid(&mut self) -> Result<Option<i64>> {
+        data_entries::table
+            .select(diesel::dsl::max(data_entries::uid))
+            .get_result(self)
             .map_err(|err| {
-                Error::new(AppError::DbError(err)).context("Cannot get next update uid.")
+                Error::new(AppError::from(err)).context("Cannot get last data entry uid.")
             })
     }
 
-    fn insert_blocks_or_microblocks(&mut self, blocks: &Vec<BlockMicroblock>) -> Result<Vec<i64>> {
-        diesel::insert_into(blocks_microblocks::table)
+    fn insert_blocks_or_microblocks(
+        &mut self,
+        blocks: &[BlockMicroblock],
+    ) -> Result<Vec<Option<i64>>> {
+        let inserted: HashMap<String, i64> = diesel::insert_into(blocks_microblocks::table)
             .values(blocks)
-            .returning(blocks_microblocks::uid)
-            .get_results(self)
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .on_conflict(blocks_microblocks::id)
+            .do_nothing()
+            .returning((blocks_microblocks::id, blocks_microblocks::uid))
+            .get_results::<(String, i64)>(self)
+            .map_err(|err| Error::new(AppError::from(err)))?
+            .into_iter()
+            .collect();
+
+        Ok(blocks
+            .iter()
+            .map(|block| inserted.get(&block.id).copied())
+            .collect())
     }
 
-    fn insert_data_entries(&mut self, entries: &Vec<InsertableDataEntry>) -> Result<()> {
-        // one data entry has 29 columns
-        // pg cannot insert more then 65535
-        // so the biggest chunk should be less then 2259
-        let chunk_size = 2000;
-        entries
-            .to_owned()
-            .chunks(chunk_size)
-            .into_iter()
-            .try_fold((), |_, chunk| {
-                let mut  recs : Vec<_> = vec![];
-                let mut  hist_uids : Vec<_> = vec![];
-
-                diesel::insert_into(data_entries::table)
-                    .values(chunk)
-                    .returning((data_entries::address, data_entries::key, data_entries::uid, data_entries::block_uid))
-                    .get_results(self)
-                    .map(|rows: Vec<(String, String, i64, i64)>| {
-                        recs = rows.into_iter()
-                                .map(|(address, key, data_entry_uid, block_uid)| InsertedDataEntry {
-                                    address: address,
-                                    key: key,
-                                    data_entry_uid: data_entry_uid,
-                                    block_uid: block_uid,
-                                    height: None,
-                                    block_timestamp: None
-                                }).collect();
+    fn insert_data_entries<I>(&mut self, entries: I, chunk_size: usize) -> Result<()>
+    where
+        I: IntoIterator<Item = InsertableDataEntry>,
+    {
+        // chunks are pulled from the iterator lazily,
+        // so only one chunk is materialized at a time
+        for chunk in &entries.into_iter().chunks(chunk_size) {
+            let chunk = chunk.collect_vec();
 
-                    })
-                    .map_err(|err| Error::new(AppError::DbError(err)))?;
-
-                diesel::insert_into(data_entries_history_keys::table)
-                    .values(recs)
-                    .returning(data_entries_history_keys::uid)
-                    .get_results(self)
-                    .map(|r: Vec<i64>| {
-                        hist_uids = r;
-                    })
-                    .map_err(|err| Error::new(AppError::DbError(err)))?;
-
-                diesel::sql_query(r#"
-                        update data_entries_history_keys hk set
-                            height = (select height from blocks_microblocks where uid = hk.block_uid),
-                            block_timestamp = (select to_timestamp(time_stamp / 1000) from blocks_microblocks where uid = hk.block_uid)
-                        where hk.uid  = ANY($1)
-                    "#)
-                    .bind::<Array<BigInt>, _>(hist_uids)
-                    .execute(self)
-                    .map(|_| ())
-                    .map_err(|err| Error::new(AppError::DbError(err)))
-            })
+            let recs = diesel::insert_into(data_entries::table)
+                .values(&chunk)
+                .returning((
+                    data_entries::address,
+                    data_entries::key,
+                    data_entries::uid,
+                    data_entries::block_uid,
+                ))
+                .get_results(self)
+                .map(into_inserted_data_entries)
+                .map_err(|err| Error::new(AppError::from(err)))?;
+
+            insert_history_keys(self, recs)?;
+        }
+
+        Ok(())
+    }
+
+    fn copy_data_entries<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = InsertableDataEntry>,
+    {
+        // copy has no bind parameters limit,
+        // chunks only bound the memory used by one statement
+        let chunk_size = 10000;
+        for chunk in &entries.into_iter().chunks(chunk_size) {
+            let chunk = chunk.collect_vec();
+            let (first_uid, last_uid) = match chunk.iter().map(|de| de.uid).minmax() {
+                MinMaxResult::NoElements => continue,
+                MinMaxResult::OneElement(u) => (u, u),
+                MinMaxResult::MinMax(min, max) => (min, max),
+            };
+
+            diesel::copy_from(data_entries::table)
+                .from_insertable(&chunk)
+                .execute(self)
+                .map_err(|err| Error::new(AppError::from(err)))?;
+
+            // copy cannot return rows, but uids are assigned by the consumer,
+            // so the inserted rows are exactly the ones in the uid range of the chunk
+            let recs = data_entries::table
+                .select((
+                    data_entries::address,
+                    data_entries::key,
+                    data_entries::uid,
+                    data_entries::block_uid,
+                ))
+                .filter(data_entries::uid.between(first_uid, last_uid))
+                .load(self)
+                .map(into_inserted_data_entries)
+                .map_err(|err| Error::new(AppError::from(err)))?;
+
+            insert_history_keys(self, recs)?;
+        }
+
+        Ok(())
+    }
+
+    fn insert_leases(&mut self, new_leases: &[InsertableLease]) -> Result<()> {
+        diesel::insert_into(leases::table)
+            .values(new_leases)
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn insert_transfers(&mut self, new_transfers: &[InsertableTransfer]) -> Result<()> {
+        diesel::insert_into(transfers::table)
+            .values(new_transfers)
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
     }
 
-    fn close_superseded_by(&mut self, updates: &Vec<DataEntryUpdate>) -> Result<()> {
+    fn insert_contract_events(&mut self, events: &[InsertableContractEvent]) -> Result<()> {
+        diesel::insert_into(contract_events::table)
+            .values(events)
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn insert_transactions(&mut self, transactions: &[InsertableTransaction]) -> Result<()> {
+        diesel::insert_into(transactions::table)
+            .values(transactions)
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn close_superseded_by(&mut self, updates: &[DataEntryUpdate]) -> Result<()> {
         let mut addresses = vec![];
         let mut keys = vec![];
         let mut superseded_bys = vec![];
@@ -181,33 +649,34 @@ impl DataEntriesRepoOperations for PooledPgConnection {
             superseded_bys.push(&u.superseded_by);
         });
 
-        diesel::sql_query("UPDATE data_entries SET superseded_by = updates.superseded_by FROM (SELECT UNNEST($1) as address, UNNEST($2) as key, UNNEST($3) as superseded_by) as updates where data_entries.address = updates.address and data_entries.key = updates.key and data_entries.superseded_by = $4")
+        diesel::sql_query(format!("UPDATE {data_entries} SET superseded_by = updates.superseded_by FROM (SELECT UNNEST($1) as address, UNNEST($2) as key, UNNEST($3) as superseded_by) as updates where {data_entries}.address = updates.address and {data_entries}.key = updates.key and {data_entries}.superseded_by = $4", data_entries = table("data_entries")))
                 .bind::<Array<VarChar>, _>(addresses)
                 .bind::<Array<VarChar>, _>(keys)
                 .bind::<Array<BigInt>, _>(superseded_bys)
-                .bind::<BigInt, _>(MAX_UID)
+                .bind::<BigInt, _>(LIVE_SUPERSEDED_BY)
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
     }
 
-    fn reopen_superseded_by(&mut self, current_superseded_by: &Vec<i64>) -> Result<()> {
-        diesel::sql_query("UPDATE data_entries SET superseded_by = $1 FROM (SELECT UNNEST($2) AS superseded_by) AS current WHERE data_entries.superseded_by = current.superseded_by;")
-            .bind::<BigInt, _>(MAX_UID)
+    fn reopen_superseded_by(&mut self, current_superseded_by: &[i64]) -> Result<()> {
+        diesel::sql_query(format!("UPDATE {data_entries} SET superseded_by = $1 FROM (SELECT UNNEST($2) AS superseded_by) AS current WHERE {data_entries}.superseded_by = current.superseded_by;", data_entries = table("data_entries")))
+            .bind::<BigInt, _>(LIVE_SUPERSEDED_BY)
             .bind::<Array<BigInt>, _>(current_superseded_by)
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
     }
 
     fn set_next_update_uid(&mut self, new_uid: i64) -> Result<()> {
         diesel::sql_query(format!(
-            "select setval('data_entries_uid_seq', {}, false);", // 3rd param - is called; in case of true, value'll be incremented before returning
+            "select setval('{}', {}, false);", // 3rd param - is called; in case of true, value'll be incremented before returning
+            table("data_entries_uid_seq"),
             new_uid
         ))
         .execute(self)
         .map(|_| ())
-        .map_err(|err| Error::new(AppError::DbError(err)))
+        .map_err(|err| Error::new(AppError::from(err)))
     }
 
     fn change_block_id(&mut self, block_uid: &i64, new_block_id: &str) -> Result<()> {
@@ -216,7 +685,7 @@ impl DataEntriesRepoOperations for PooledPgConnection {
             .filter(blocks_microblocks::uid.eq(block_uid))
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
     }
 
     fn update_data_entries_block_references(&mut self, block_uid: &i64) -> Result<()> {
@@ -225,24 +694,60 @@ impl DataEntriesRepoOperations for PooledPgConnection {
             .filter(data_entries::block_uid.gt(block_uid))
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))?;
+            .map_err(|err| Error::new(AppError::from(err)))?;
 
         diesel::update(data_entries_history_keys::table)
             .set(data_entries_history_keys::block_uid.eq(block_uid))
             .filter(data_entries_history_keys::block_uid.gt(block_uid))
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))?;
+            .map_err(|err| Error::new(AppError::from(err)))?;
 
         Ok(())
     }
 
+    fn update_leases_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        diesel::update(leases::table)
+            .set(leases::block_uid.eq(block_uid))
+            .filter(leases::block_uid.gt(block_uid))
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn update_transfers_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        diesel::update(transfers::table)
+            .set(transfers::block_uid.eq(block_uid))
+            .filter(transfers::block_uid.gt(block_uid))
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn update_contract_events_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        diesel::update(contract_events::table)
+            .set(contract_events::block_uid.eq(block_uid))
+            .filter(contract_events::block_uid.gt(block_uid))
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn update_transactions_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        diesel::update(transactions::table)
+            .set(transactions::block_uid.eq(block_uid))
+            .filter(transactions::block_uid.gt(block_uid))
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
     fn delete_microblocks(&mut self) -> Result<()> {
         diesel::delete(blocks_microblocks::table)
             .filter(blocks_microblocks::time_stamp.is_null())
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
     }
 
     fn rollback_blocks_microblocks(&mut self, block_uid: &i64) -> Result<()> {
@@ -250,7 +755,16 @@ impl DataEntriesRepoOperations for PooledPgConnection {
             .filter(blocks_microblocks::uid.gt(block_uid))
             .execute(self)
             .map(|_| ())
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn rollback_microblocks(&mut self, block_uid: &i64) -> Result<()> {
+        diesel::delete(blocks_microblocks::table)
+            .filter(blocks_microblocks::uid.gt(block_uid))
+            .filter(blocks_microblocks::time_stamp.is_null())
+            .execute(self)
+            .map(|_| ())
+            .map_err(|err| Error::new(AppError::from(err)))
     }
 
     fn rollback_data_entries(&mut self, block_uid: &i64) -> Result<Vec<DeletedDataEntry>> {
@@ -267,6 +781,604 @@ impl DataEntriesRepoOperations for PooledPgConnection {
                     })
                     .collect()
             })
-            .map_err(|err| Error::new(AppError::DbError(err)))
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn rollback_microblock_data_entries(
+        &mut self,
+        block_uid: &i64,
+    ) -> Result<Vec<DeletedDataEntry>> {
+        // the backward scan of the primary key stops at the first kept entry
+        diesel::sql_query(format!(
+            r#"
+                delete from {data_entries}
+                where uid > coalesce(
+                    (select uid from {data_entries} where block_uid <= $1 order by uid desc limit 1),
+                    0
+                )
+                returning address, key, uid
+            "#,
+            data_entries = table("data_entries")
+        ))
+        .bind::<BigInt, _>(block_uid)
+        .get_results::<DeletedDataEntry>(self)
+        .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn delete_unreferenced_blocks_microblocks(&mut self, below_height: i32) -> Result<usize> {
+        diesel::sql_query(format!(
+            r#"
+                delete from {} b
+                where b.height < $1
+                    and not exists (select 1 from {} d where d.block_uid = b.uid)
+                    and not exists (select 1 from {} hk where hk.block_uid = b.uid)
+                    and not exists (select 1 from {} l where l.block_uid = b.uid)
+                    and not exists (select 1 from {} t where t.block_uid = b.uid)
+                    and not exists (select 1 from {} ce where ce.block_uid = b.uid)
+                    and not exists (select 1 from {} tx where tx.block_uid = b.uid)
+            "#,
+            table("blocks_microblocks"),
+            table("data_entries"),
+            table("data_entries_history_keys"),
+            table("leases"),
+            table("transfers"),
+            table("contract_events"),
+            table("transactions")
+        ))
+        .bind::<Integer, _>(below_height)
+        .execute(self)
+        .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn delete_superseded_data_entries(
+        &mut self,
+        up_to_block_uid: &i64,
+        limit: i64,
+    ) -> Result<usize> {
+        let last_uid: Option<i64> = data_entries::table
+            .select(diesel::dsl::max(data_entries::uid))
+            .filter(data_entries::block_uid.le(up_to_block_uid))
+            .get_result(self)
+            .map_err(|err| Error::new(AppError::from(err)))?;
+
+        // live versions are superseded by LIVE_SUPERSEDED_BY, so they are never below the last uid
+        let last_uid = match last_uid {
+            Some(last_uid) => last_uid,
+            None => return Ok(0),
+        };
+
+        let (uids, addresses, keys): (Vec<i64>, Vec<String>, Vec<String>) = data_entries::table
+            .select((data_entries::uid, data_entries::address, data_entries::key))
+            .filter(data_entries::superseded_by.le(last_uid))
+            .limit(limit)
+            .load::<(i64, String, String)>(self)
+            .map_err(|err| Error::new(AppError::from(err)))?
+            .into_iter()
+            .multiunzip();
+
+        if uids.is_empty() {
+            return Ok(0);
+        }
+
+        diesel::sql_query(format!(
+            r#"
+                delete from {} hk
+                using (select unnest($1) as address, unnest($2) as key, unnest($3) as data_entry_uid) d
+                where hk.address = d.address and hk.key = d.key and hk.data_entry_uid = d.data_entry_uid
+            "#,
+            table("data_entries_history_keys")
+        ))
+        .bind::<Array<VarChar>, _>(&addresses)
+        .bind::<Array<VarChar>, _>(&keys)
+        .bind::<Array<BigInt>, _>(&uids)
+        .execute(self)
+        .map_err(|err| Error::new(AppError::from(err)))?;
+
+        diesel::delete(data_entries::table)
+            .filter(data_entries::uid.eq_any(&uids))
+            .execute(self)
+            .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn is_value_type_validated(&mut self) -> Result<bool> {
+        diesel::sql_query(
+            "select coalesce(bool_and(convalidated), false) as validated from pg_constraint where conname = 'data_entries_value_type_not_null' and conrelid = $1::regclass",
+        )
+        .bind::<VarChar, _>(table("data_entries"))
+        .get_result::<ValueTypeValidated>(self)
+        .map(|row| row.validated)
+        .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn backfill_value_types(&mut self, from_uid: i64, to_uid: i64) -> Result<usize> {
+        // the range is read by the primary key, so a batch never scans the whole table
+        diesel::sql_query(format!(
+            r#"
+                update {}
+                set value_type = case
+                    when value_binary is not null then '{}'
+                    when value_bool is not null then '{}'
+                    when value_integer is not null then '{}'
+                    when value_string is not null then '{}'
+                    else '{}'
+                end
+                where uid >= $1 and uid < $2 and value_type is null
+            "#,
+            table("data_entries"),
+            VALUE_TYPE_BINARY,
+            VALUE_TYPE_BOOL,
+            VALUE_TYPE_INTEGER,
+            VALUE_TYPE_STRING,
+            VALUE_TYPE_DELETED
+        ))
+        .bind::<BigInt, _>(from_uid)
+        .bind::<BigInt, _>(to_uid)
+        .execute(self)
+        .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn validate_value_type(&mut self) -> Result<()> {
+        // validation only takes a SHARE UPDATE EXCLUSIVE lock, the writes go on meanwhile
+        diesel::sql_query(format!(
+            "alter table {} validate constraint data_entries_value_type_not_null",
+            table("data_entries")
+        ))
+        .execute(self)
+        .map(|_| ())
+        .map_err(|err| Error::new(AppError::from(err)))
+    }
+
+    fn refresh_last_writes(&mut self, keys: &[(String, String)]) -> Result<()> {
+        let (addresses, keys): (Vec<&String>, Vec<&String>) =
+            keys.iter().map(|(address, key)| (address, key)).unzip();
+
+        diesel::sql_query(format!(
+            r#"
+                delete from {} lw
+                using (select unnest($1) as address, unnest($2) as key) k
+                where lw.address = k.address and lw.key = k.key
+            "#,
+            table("data_entries_last_writes")
+        ))
+        .bind::<Array<VarChar>, _>(&addresses)
+        .bind::<Array<VarChar>, _>(&keys)
+        .execute(self)
+        .map_err(|err| Error::new(AppError::from(err)))?;
+
+        diesel::sql_query(format!(
+            r#"
+                insert into {} (address, key, height, block_timestamp)
+                select d.address, d.key, b.height, to_timestamp(b.time_stamp / 1000)
+                from {} d
+                    inner join (select unnest($1) as address, unnest($2) as key) k
+                        on d.address = k.address and d.key = k.key
+                    inner join {} b on b.uid = d.block_uid
+                where d.superseded_by = $3
+            "#,
+            table("data_entries_last_writes"),
+            table("data_entries"),
+            table("blocks_microblocks")
+        ))
+        .bind::<Array<VarChar>, _>(&addresses)
+        .bind::<Array<VarChar>, _>(&keys)
+        .bind::<BigInt, _>(LIVE_SUPERSEDED_BY)
+        .execute(self)
+        .map(|_| ())
+        .map_err(|err| Error::new(AppError::from(err)))
+    }
+}
+
+fn into_inserted_data_entries(rows: Vec<(String, String, i64, i64)>) -> Vec<InsertedDataEntry> {
+    rows.into_iter()
+        .map(
+            |(address, key, data_entry_uid, block_uid)| InsertedDataEntry {
+                address,
+                key,
+                data_entry_uid,
+                block_uid,
+                height: None,
+                block_timestamp: None,
+            },
+        )
+        .collect()
+}
+
+fn insert_history_keys(conn: &mut PooledPgConnection, recs: Vec<InsertedDataEntry>) -> Result<()> {
+    // history keys are unique per data entry, so a retried insert is a no-op
+    let hist_uids: Vec<i64> = diesel::insert_into(data_entries_history_keys::table)
+        .values(recs)
+        .on_conflict((
+            data_entries_history_keys::address,
+            data_entries_history_keys::key,
+            data_entries_history_keys::data_entry_uid,
+        ))
+        .do_nothing()
+        .returning(data_entries_history_keys::uid)
+        .get_results(conn)
+        .map_err(|err| Error::new(AppError::from(err)))?;
+
+    diesel::sql_query(format!(r#"
+            update {} hk set
+                height = (select height from {blocks} where uid = hk.block_uid),
+                block_timestamp = (select to_timestamp(time_stamp / 1000) from {blocks} where uid = hk.block_uid)
+            where hk.uid  = ANY($1)
+        "#, table("data_entries_history_keys"), blocks = table("blocks_microblocks")))
+        .bind::<Array<BigInt>, _>(hist_uids)
+        .execute(conn)
+        .map(|_| ())
+        .map_err(|err| Error::new(AppError::from(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_chunk_size_defaults_to_the_bind_parameters_limit() {
+        let max_chunk_size = MAX_BIND_PARAMS / INSERTED_DATA_ENTRY_COLUMNS;
+
+        assert_eq!(insert_chunk_size(None), max_chunk_size);
+        assert!(max_chunk_size * INSERTED_DATA_ENTRY_COLUMNS <= MAX_BIND_PARAMS);
+    }
+
+    #[test]
+    fn insert_chunk_size_is_clamped() {
+        let max_chunk_size = MAX_BIND_PARAMS / INSERTED_DATA_ENTRY_COLUMNS;
+
+        assert_eq!(insert_chunk_size(Some(100)), 100);
+        assert_eq!(insert_chunk_size(Some(max_chunk_size)), max_chunk_size);
+        assert_eq!(insert_chunk_size(Some(max_chunk_size + 1)), max_chunk_size);
+        assert_eq!(insert_chunk_size(Some(0)), 1);
+    }
+
+    /// Queries of `PgDataEntriesRepo` against Postgres. They need a database, see the README.
+    mod pg {
+        use super::*;
+        use crate::data_entries::DataEntryValue;
+        use crate::db::testing::TestDatabase;
+
+        const ADDRESS: &str = "3PAddress";
+
+        /// Write the values in a block of the height, each one superseding the live version of its key.
+        fn write(repo: &PgDataEntriesRepo, block_height: i32, values: Vec<(&str, DataEntryValue)>) {
+            write_in_chunks(repo, block_height, values, 100);
+        }
+
+        fn write_in_chunks(
+            repo: &PgDataEntriesRepo,
+            block_height: i32,
+            values: Vec<(&str, DataEntryValue)>,
+            chunk_size: usize,
+        ) {
+            repo.transaction(|ops| {
+                let block_uid = ops.insert_blocks_or_microblocks(&[BlockMicroblock {
+                    id: format!("b{}", block_height),
+                    time_stamp: Some(block_height as i64 * 60_000),
+                    height: block_height,
+                }])?[0]
+                    .expect("the block is new");
+                let first_uid = ops.reserve_update_uids(values.len() as i64)?;
+                let entries = values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, (key, value))| {
+                        let data_entry = DataEntry {
+                            address: ADDRESS.to_string(),
+                            key: key.to_string(),
+                            transaction_id: format!("tx-{}-{}", key, block_height),
+                            value,
+                        };
+                        InsertableDataEntry {
+                            superseded_by: LIVE_SUPERSEDED_BY,
+                            ..InsertableDataEntry::new(
+                                block_uid,
+                                first_uid + idx as i64,
+                                data_entry,
+                                Default::default(),
+                                Default::default(),
+                            )
+                        }
+                    })
+                    .collect_vec();
+                let superseded = entries
+                    .iter()
+                    .map(|de| DataEntryUpdate {
+                        superseded_by: de.uid,
+                        address: de.address.clone(),
+                        key: de.key.clone(),
+                    })
+                    .collect_vec();
+                ops.close_superseded_by(&superseded)?;
+                ops.insert_data_entries(entries, chunk_size)
+            })
+            .unwrap();
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn entries_of_every_chunk_are_inserted() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let keys = ["k1", "k2", "k3", "k4", "k5"];
+
+            // 5 entries are inserted by 3 statements
+            write_in_chunks(
+                &repo,
+                1,
+                keys.iter()
+                    .map(|key| (*key, DataEntryValue::Integer(1)))
+                    .collect(),
+                2,
+            );
+
+            let counts = repo
+                .execute(|mut ops| {
+                    let entries = data_entries::table.count().get_result::<i64>(&mut ops)?;
+                    let history_keys = data_entries_history_keys::table
+                        .count()
+                        .get_result::<i64>(&mut ops)?;
+                    Ok((entries, history_keys))
+                })
+                .unwrap();
+            assert_eq!(counts, (5, 5));
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn deleted_key_is_not_found() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            write(&repo, 1, vec![("k", DataEntryValue::Integer(1))]);
+            write(&repo, 2, vec![("k", DataEntryValue::Deleted)]);
+
+            let data_entry = repo
+                .execute(|mut ops| ops.get_data_entry(ADDRESS, "k"))
+                .unwrap();
+
+            assert!(data_entry.is_none());
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn key_deleted_at_or_below_the_height_is_not_found() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            write(&repo, 1, vec![("k", DataEntryValue::Integer(1))]);
+            write(&repo, 2, vec![("k", DataEntryValue::Deleted)]);
+            write(&repo, 4, vec![("k", DataEntryValue::Integer(4))]);
+
+            let values = (1..=4)
+                .map(|at_height| {
+                    repo.execute(|mut ops| ops.get_data_entry_at_height(ADDRESS, "k", at_height))
+                        .unwrap()
+                        .map(|de| de.value)
+                })
+                .collect_vec();
+
+            assert_eq!(
+                values,
+                vec![
+                    Some(DataEntryValue::Integer(1)),
+                    None,
+                    None,
+                    Some(DataEntryValue::Integer(4))
+                ]
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn pages_of_live_keys_are_disjoint_and_ordered() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let keys = ["e", "a", "d", "b", "f", "c"];
+            write(
+                &repo,
+                1,
+                keys.iter()
+                    .map(|key| (*key, DataEntryValue::Integer(1)))
+                    .collect(),
+            );
+            write(&repo, 2, vec![("d", DataEntryValue::Deleted)]);
+
+            let mut pages = vec![];
+            let mut after_key = None;
+            loop {
+                let (entries, cursor) = repo
+                    .execute(|mut ops| ops.list_data_entries(ADDRESS, after_key.as_deref(), 2))
+                    .unwrap();
+                pages.push(entries.into_iter().map(|de| de.key).collect_vec());
+                match cursor {
+                    Some(cursor) => after_key = Some(cursor),
+                    None => break,
+                }
+            }
+
+            assert_eq!(pages, vec![vec!["a", "b"], vec!["c", "e"], vec!["f"]]);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn history_has_every_version_of_the_key() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            write(&repo, 1, vec![("k", DataEntryValue::Integer(1))]);
+            write(
+                &repo,
+                2,
+                vec![
+                    ("k", DataEntryValue::String("two".to_string())),
+                    ("other", DataEntryValue::Integer(2)),
+                ],
+            );
+            write(&repo, 3, vec![("k", DataEntryValue::Deleted)]);
+            let history = |after_uid, limit| {
+                repo.execute(|mut ops| ops.get_data_entry_history(ADDRESS, "k", after_uid, limit))
+                    .unwrap()
+            };
+
+            let (versions, cursor) = history(None, 10);
+
+            assert_eq!(cursor, None);
+            assert_eq!(
+                versions
+                    .iter()
+                    .map(|v| (v.height, v.data_entry.value.clone()))
+                    .collect_vec(),
+                vec![
+                    (Some(1), DataEntryValue::Integer(1)),
+                    (Some(2), DataEntryValue::String("two".to_string())),
+                    (Some(3), DataEntryValue::Deleted),
+                ]
+            );
+            assert!(versions.iter().all(|v| v.block_timestamp.is_some()));
+            assert!(versions.windows(2).all(|w| w[0].uid < w[1].uid));
+
+            let (first_page, cursor) = history(None, 2);
+            let (second_page, last_cursor) = history(cursor, 2);
+
+            assert_eq!(cursor, Some(first_page[1].uid));
+            assert_eq!(last_cursor, None);
+            assert_eq!(
+                first_page
+                    .iter()
+                    .chain(&second_page)
+                    .map(|v| v.uid)
+                    .collect_vec(),
+                versions.iter().map(|v| v.uid).collect_vec()
+            );
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn history_key_inserted_twice_is_stored_once() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            write(&repo, 1, vec![("k", DataEntryValue::Integer(1))]);
+            let history_key = repo
+                .execute(|mut ops| {
+                    data_entries_history_keys::table
+                        .select((
+                            data_entries_history_keys::block_uid,
+                            data_entries_history_keys::data_entry_uid,
+                        ))
+                        .get_result::<(i64, i64)>(&mut ops)
+                        .map_err(Error::new)
+                })
+                .unwrap();
+
+            // as a retried insert would do it
+            repo.transaction(|conn| {
+                let (block_uid, data_entry_uid) = history_key;
+                insert_history_keys(
+                    conn,
+                    into_inserted_data_entries(vec![(
+                        ADDRESS.to_string(),
+                        "k".to_string(),
+                        data_entry_uid,
+                        block_uid,
+                    )]),
+                )
+            })
+            .unwrap();
+
+            let count = repo
+                .execute(|mut ops| {
+                    data_entries_history_keys::table
+                        .count()
+                        .get_result::<i64>(&mut ops)
+                        .map_err(Error::new)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn missing_block_has_no_uid() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            write(&repo, 1, vec![("k", DataEntryValue::Integer(1))]);
+
+            let found = repo.execute(|mut ops| ops.get_block_uid("b1")).unwrap();
+            let missing = repo.execute(|mut ops| ops.get_block_uid("b2")).unwrap();
+
+            assert!(found.is_some());
+            assert_eq!(missing, None);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn block_inserted_twice_is_not_returned_again() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+            let blocks = |ids: &[(&str, i32)]| {
+                ids.iter()
+                    .map(|(block_id, block_height)| BlockMicroblock {
+                        id: block_id.to_string(),
+                        time_stamp: Some(*block_height as i64 * 60_000),
+                        height: *block_height,
+                    })
+                    .collect_vec()
+            };
+
+            let first = repo
+                .transaction(|ops| ops.insert_blocks_or_microblocks(&blocks(&[("b1", 1)])))
+                .unwrap();
+            let second = repo
+                .transaction(|ops| {
+                    ops.insert_blocks_or_microblocks(&blocks(&[("b1", 1), ("b2", 2)]))
+                })
+                .unwrap();
+
+            assert!(first[0].is_some());
+            // the stored block is left as it is, only the new one is inserted
+            assert_eq!(second[0], None);
+            assert!(second[1].is_some());
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn reserved_uid_ranges_follow_each_other() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+
+            let first = repo.transaction(|ops| ops.reserve_update_uids(3)).unwrap();
+            let second = repo.transaction(|ops| ops.reserve_update_uids(2)).unwrap();
+            let next = repo.execute(|mut ops| ops.get_next_update_uid()).unwrap();
+
+            assert_eq!(second, first + 3);
+            assert_eq!(next, second + 2);
+        }
+
+        #[test]
+        #[ignore = "needs a Postgres at TEST_DATABASE_URL"]
+        fn concurrent_reservations_do_not_overlap() {
+            let db = TestDatabase::create();
+            let repo = PgDataEntriesRepo::new(db.pool.clone());
+
+            let first_uids = std::thread::scope(|scope| {
+                let reservers = (0..2)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            (0..20)
+                                .map(|_| repo.transaction(|ops| ops.reserve_update_uids(10)))
+                                .collect::<Result<Vec<_>>>()
+                                .unwrap()
+                        })
+                    })
+                    .collect_vec();
+                reservers
+                    .into_iter()
+                    .flat_map(|reserver| reserver.join().unwrap())
+                    .sorted()
+                    .collect_vec()
+            });
+
+            assert_eq!(first_uids.len(), 40);
+            for (prev, next) in first_uids.iter().tuple_windows() {
+                assert_eq!(next - prev, 10);
+            }
+        }
     }
 }
@@ -0,0 +1,825 @@
+use anyhow::{Error, Result};
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::{
+    BlockMicroblock, ConsumerState, DataEntriesRepo, DataEntriesRepoOperations, DataEntry,
+    DataEntryUpdate, DataEntryVersion, DeletedDataEntry, FragmentFilter, InsertableContractEvent,
+    InsertableDataEntry, InsertableLease, InsertableTransaction, InsertableTransfer,
+    PrevHandledHeight, StoredTransaction, LIVE_SUPERSEDED_BY, VALUE_TYPE_DELETED,
+};
+use crate::error::AppError;
+
+#[derive(Clone, Debug)]
+pub struct MockBlockMicroblock {
+    pub uid: i64,
+    pub id: String,
+    pub time_stamp: Option<i64>,
+    pub height: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockHistoryKey {
+    pub address: String,
+    pub key: String,
+    pub data_entry_uid: i64,
+    pub block_uid: i64,
+    pub height: Option<i32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockState {
+    pub blocks_microblocks: Vec<MockBlockMicroblock>,
+    pub data_entries: Vec<InsertableDataEntry>,
+    pub history_keys: Vec<MockHistoryKey>,
+    pub leases: Vec<InsertableLease>,
+    pub transfers: Vec<InsertableTransfer>,
+    pub contract_events: Vec<InsertableContractEvent>,
+    pub transactions: Vec<InsertableTransaction>,
+    pub consumer_state: Option<ConsumerState>,
+    /// Inserting a data entry with this key panics, as a bug in the handling of an update would.
+    pub panic_on_key: Option<String>,
+    /// Every query fails, as with a database which is down.
+    pub unavailable: bool,
+    pub applied_reset_to_height: Option<i32>,
+    pub last_writes: HashMap<(String, String), i32>,
+    pub next_update_uid: i64,
+    next_block_uid: i64,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        Self {
+            blocks_microblocks: vec![],
+            data_entries: vec![],
+            history_keys: vec![],
+            leases: vec![],
+            transfers: vec![],
+            contract_events: vec![],
+            transactions: vec![],
+            consumer_state: None,
+            panic_on_key: None,
+            unavailable: false,
+            applied_reset_to_height: None,
+            last_writes: HashMap::new(),
+            next_update_uid: 1,
+            next_block_uid: 1,
+        }
+    }
+}
+
+impl MockState {
+    fn block(&self, block_uid: i64) -> Option<&MockBlockMicroblock> {
+        self.blocks_microblocks.iter().find(|b| b.uid == block_uid)
+    }
+
+    /// Deleting blocks cascades to every row referencing them, as the foreign keys do.
+    fn delete_blocks_microblocks<P: Fn(&MockBlockMicroblock) -> bool>(&mut self, predicate: P) {
+        let (deleted, kept): (Vec<_>, Vec<_>) = self
+            .blocks_microblocks
+            .drain(..)
+            .partition(|b| predicate(b));
+        self.blocks_microblocks = kept;
+
+        let deleted_uids = deleted.iter().map(|b| b.uid).collect_vec();
+        self.data_entries
+            .retain(|de| !deleted_uids.contains(&de.block_uid));
+        self.history_keys
+            .retain(|hk| !deleted_uids.contains(&hk.block_uid));
+        self.leases.retain(|l| !deleted_uids.contains(&l.block_uid));
+        self.transfers
+            .retain(|t| !deleted_uids.contains(&t.block_uid));
+        self.contract_events
+            .retain(|ce| !deleted_uids.contains(&ce.block_uid));
+        self.transactions
+            .retain(|tx| !deleted_uids.contains(&tx.block_uid));
+    }
+}
+
+/// In-memory repo for tests of the daemon logic without a database.
+///
+/// A failed or panicked transaction restores the state which was before it.
+#[derive(Clone, Default)]
+pub struct MockDataEntriesRepo {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockDataEntriesRepo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> MockState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn panic_on_key(&self, key: &str) {
+        self.state.lock().unwrap().panic_on_key = Some(key.to_string());
+    }
+
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.state.lock().unwrap().unavailable = unavailable;
+    }
+
+    fn check_available(&self) -> Result<()> {
+        if self.state.lock().unwrap().unavailable {
+            Err(Error::new(AppError::DbError(
+                diesel::result::Error::BrokenTransactionManager,
+            ))
+            .context("the database is down"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct MockConnection {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockConnection {
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl DataEntriesRepo for MockDataEntriesRepo {
+    type Operations = MockConnection;
+
+    fn execute<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(MockConnection) -> Result<R>,
+    {
+        self.check_available()?;
+        f(MockConnection {
+            state: self.state.clone(),
+        })
+    }
+
+    fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut MockConnection) -> Result<R>,
+    {
+        self.check_available()?;
+        let snapshot = self.state();
+        let mut conn = MockConnection {
+            state: self.state.clone(),
+        };
+
+        let restore = |snapshot| {
+            *self.state.lock().unwrap_or_else(PoisonError::into_inner) = snapshot;
+        };
+        match std::panic::catch_unwind(AssertUnwindSafe(|| f(&mut conn))) {
+            Ok(result) => result.inspect_err(|_| restore(snapshot)),
+            Err(panic) => {
+                restore(snapshot);
+                std::panic::resume_unwind(panic)
+            }
+        }
+    }
+}
+
+fn not_found(context: String) -> Error {
+    Error::new(AppError::DbError(diesel::result::Error::NotFound)).context(context)
+}
+
+fn to_data_entry(de: &InsertableDataEntry) -> DataEntry {
+    DataEntry {
+        address: de.address.clone(),
+        key: de.key.clone(),
+        transaction_id: de.transaction_id.clone(),
+        value: (
+            de.value_binary.clone(),
+            de.value_bool,
+            de.value_integer,
+            de.value_string.clone(),
+        )
+            .into(),
+    }
+}
+
+fn key_fragment(de: &InsertableDataEntry, index: usize) -> (Option<&String>, Option<i64>) {
+    match index {
+        0 => (de.fragment_0_string.as_ref(), de.fragment_0_integer),
+        1 => (de.fragment_1_string.as_ref(), de.fragment_1_integer),
+        2 => (de.fragment_2_string.as_ref(), de.fragment_2_integer),
+        3 => (de.fragment_3_string.as_ref(), de.fragment_3_integer),
+        4 => (de.fragment_4_string.as_ref(), de.fragment_4_integer),
+        5 => (de.fragment_5_string.as_ref(), de.fragment_5_integer),
+        6 => (de.fragment_6_string.as_ref(), de.fragment_6_integer),
+        7 => (de.fragment_7_string.as_ref(), de.fragment_7_integer),
+        8 => (de.fragment_8_string.as_ref(), de.fragment_8_integer),
+        9 => (de.fragment_9_string.as_ref(), de.fragment_9_integer),
+        10 => (de.fragment_10_string.as_ref(), de.fragment_10_integer),
+        _ => (None, None),
+    }
+}
+
+fn matches_fragment(de: &InsertableDataEntry, filter: &FragmentFilter) -> bool {
+    match filter {
+        FragmentFilter::String(index, value) => key_fragment(de, *index).0 == Some(value),
+        FragmentFilter::Integer(index, value) => key_fragment(de, *index).1 == Some(*value),
+    }
+}
+
+impl DataEntriesRepoOperations for MockConnection {
+    fn get_handled_height(&mut self, depth: u32) -> Result<Option<PrevHandledHeight>> {
+        let state = self.state();
+        let last_height = match &state.consumer_state {
+            Some(consumer_state) => Some(consumer_state.last_height),
+            None => state.blocks_microblocks.iter().map(|b| b.height).max(),
+        };
+        let height = match last_height {
+            Some(last_height) => last_height - depth as i32,
+            None => return Ok(None),
+        };
+
+        Ok(state
+            .blocks_microblocks
+            .iter()
+            .filter(|b| b.height == height)
+            .min_by_key(|b| b.uid)
+            .map(|b| PrevHandledHeight {
+                uid: b.uid,
+                height: b.height,
+            }))
+    }
+
+    fn get_consumer_state(&mut self) -> Result<Option<ConsumerState>> {
+        Ok(self.state().consumer_state.clone())
+    }
+
+    fn set_consumer_state(&mut self, state: &ConsumerState) -> Result<()> {
+        self.state().consumer_state = Some(state.clone());
+        Ok(())
+    }
+
+    fn get_applied_reset_to_height(&mut self) -> Result<Option<i32>> {
+        Ok(self.state().applied_reset_to_height)
+    }
+
+    fn set_applied_reset_to_height(&mut self, height: i32) -> Result<()> {
+        let mut state = self.state();
+        state.consumer_state.get_or_insert(ConsumerState {
+            last_height: 0,
+            last_uid: None,
+        });
+        state.applied_reset_to_height = Some(height);
+        Ok(())
+    }
+
+    fn get_block_uid(&mut self, block_id: &str) -> Result<Option<i64>> {
+        Ok(self
+            .state()
+            .blocks_microblocks
+            .iter()
+            .find(|b| b.id == block_id)
+            .map(|b| b.uid))
+    }
+
+    fn get_block_height(&mut self, block_uid: &i64) -> Result<i32> {
+        self.state()
+            .block(*block_uid)
+            .map(|b| b.height)
+            .ok_or_else(|| not_found(format!("Cannot get height by block uid {}.", block_uid)))
+    }
+
+    fn get_data_entry(&mut self, address: &str, key: &str) -> Result<Option<DataEntry>> {
+        Ok(self
+            .state()
+            .data_entries
+            .iter()
+            .find(|de| {
+                de.address == address
+                    && de.key == key
+                    && de.superseded_by == LIVE_SUPERSEDED_BY
+                    && de.value_type != VALUE_TYPE_DELETED
+            })
+            .map(to_data_entry))
+    }
+
+    fn get_data_entry_at_height(
+        &mut self,
+        address: &str,
+        key: &str,
+        at_height: i32,
+    ) -> Result<Option<DataEntry>> {
+        let state = self.state();
+        let data_entry_uid = state
+            .history_keys
+            .iter()
+            .filter(|hk| hk.address == address && hk.key == key)
+            .filter(|hk| hk.height.is_some_and(|h| h <= at_height))
+            .max_by_key(|hk| (hk.height, hk.data_entry_uid))
+            .map(|hk| hk.data_entry_uid);
+
+        Ok(data_entry_uid.and_then(|uid| {
+            state
+                .data_entries
+                .iter()
+                .find(|de| de.uid == uid && de.value_type != VALUE_TYPE_DELETED)
+                .map(to_data_entry)
+        }))
+    }
+
+    fn list_data_entries(
+        &mut self,
+        address: &str,
+        after_key: Option<&str>,
+        limit: i64,
+    ) -> Result<(Vec<DataEntry>, Option<String>)> {
+        let mut entries = self
+            .state()
+            .data_entries
+            .iter()
+            .filter(|de| de.address == address && de.superseded_by == LIVE_SUPERSEDED_BY)
+            .filter(|de| de.value_type != VALUE_TYPE_DELETED)
+            .filter(|de| after_key.is_none_or(|after_key| de.key.as_str() > after_key))
+            .sorted_by(|a, b| a.key.cmp(&b.key))
+            .take(limit as usize + 1)
+            .map(to_data_entry)
+            .collect_vec();
+
+        let cursor = if entries.len() as i64 > limit {
+            entries.truncate(limit as usize);
+            entries.last().map(|de| de.key.clone())
+        } else {
+            None
+        };
+
+        Ok((entries, cursor))
+    }
+
+    fn get_data_entry_history(
+        &mut self,
+        address: &str,
+        key: &str,
+        after_uid: Option<i64>,
+        limit: i64,
+    ) -> Result<(Vec<DataEntryVersion>, Option<i64>)> {
+        let state = self.state();
+        let mut versions = state
+            .data_entries
+            .iter()
+            .filter(|de| de.address == address && de.key == key)
+            .filter(|de| after_uid.is_none_or(|after_uid| de.uid > after_uid))
+            .filter_map(|de| {
+                let history_key = state
+                    .history_keys
+                    .iter()
+                    .find(|hk| hk.data_entry_uid == de.uid)?;
+                Some(DataEntryVersion {
+                    uid: de.uid,
+                    height: history_key.height,
+                    block_timestamp: state
+                        .block(history_key.block_uid)
+                        .and_then(|b| b.time_stamp)
+                        .and_then(|ts| chrono::DateTime::from_timestamp(ts / 1000, 0))
+                        .map(|dt| dt.naive_utc()),
+                    data_entry: to_data_entry(de),
+                })
+            })
+            .sorted_by_key(|version| version.uid)
+            .collect_vec();
+
+        let cursor = if versions.len() as i64 > limit {
+            versions.truncate(limit as usize);
+            versions.last().map(|version| version.uid)
+        } else {
+            None
+        };
+
+        Ok((versions, cursor))
+    }
+
+    fn search_by_fragments(
+        &mut self,
+        filters: &[FragmentFilter],
+        limit: i64,
+        after: Option<i64>,
+    ) -> Result<(Vec<DataEntry>, Option<i64>)> {
+        let mut entries = self
+            .state()
+            .data_entries
+            .iter()
+            .filter(|de| de.superseded_by == LIVE_SUPERSEDED_BY)
+            .filter(|de| filters.iter().all(|filter| matches_fragment(de, filter)))
+            .filter(|de| after.is_none_or(|after| de.uid > after))
+            .sorted_by_key(|de| de.uid)
+            .take(limit as usize + 1)
+            .map(|de| (de.uid, to_data_entry(de)))
+            .collect_vec();
+
+        let cursor = if entries.len() as i64 > limit {
+            entries.truncate(limit as usize);
+            entries.last().map(|(uid, _)| *uid)
+        } else {
+            None
+        };
+
+        Ok((entries.into_iter().map(|(_, de)| de).collect(), cursor))
+    }
+
+    fn get_transaction(&mut self, tx_id: &str) -> Result<Option<StoredTransaction>> {
+        Ok(self
+            .state()
+            .transactions
+            .iter()
+            .find(|tx| tx.tx_id == tx_id)
+            .map(|tx| StoredTransaction {
+                tx_id: tx.tx_id.clone(),
+                height: tx.height,
+                tx_type: tx.tx_type,
+            }))
+    }
+
+    fn get_key_block_uid(&mut self) -> Result<Option<i64>> {
+        Ok(self
+            .state()
+            .blocks_microblocks
+            .iter()
+            .filter(|b| b.time_stamp.is_some())
+            .map(|b| b.uid)
+            .max())
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_last_height(&mut self) -> Result<Option<i32>> {
+        Ok(self
+            .state()
+            .blocks_microblocks
+            .iter()
+            .map(|b| b.height)
+            .max())
+    }
+
+    fn get_last_block_timestamp(&mut self) -> Result<Option<i64>> {
+        Ok(self
+            .state()
+            .blocks_microblocks
+            .iter()
+            .filter_map(|b| b.time_stamp)
+            .max())
+    }
+
+    fn get_last_block_uid_below(&mut self, height: i32) -> Result<Option<i64>> {
+        Ok(self
+            .state()
+            .blocks_microblocks
+            .iter()
+            .filter(|b| b.height < height)
+            .map(|b| b.uid)
+            .max())
+    }
+
+    fn get_total_block_id(&mut self) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .blocks_microblocks
+            .iter()
+            .filter(|b| b.time_stamp.is_none())
+            .max_by_key(|b| b.uid)
+            .map(|b| b.id.clone()))
+    }
+
+    fn get_next_update_uid(&mut self) -> Result<i64> {
+        Ok(self.state().next_update_uid)
+    }
+
+    fn reserve_update_uids(&mut self, count: i64) -> Result<i64> {
+        let mut state = self.state();
+        let first_uid = state.next_update_uid;
+        state.next_update_uid += count;
+        Ok(first_uid)
+    }
+
+    fn get_last_data_entry_uid(&mut self) -> Result<Option<i64>> {
+        Ok(self.state().data_entries.iter().map(|de| de.uid).max())
+    }
+
+    fn insert_blocks_or_microblocks(
+        &mut self,
+        blocks: &[BlockMicroblock],
+    ) -> Result<Vec<Option<i64>>> {
+        let mut state = self.state();
+        Ok(blocks
+            .iter()
+            .map(|block| {
+                if state.blocks_microblocks.iter().any(|b| b.id == block.id) {
+                    return None;
+                }
+                let uid = state.next_block_uid;
+                state.next_block_uid += 1;
+                state.blocks_microblocks.push(MockBlockMicroblock {
+                    uid,
+                    id: block.id.clone(),
+                    time_stamp: block.time_stamp,
+                    height: block.height,
+                });
+                Some(uid)
+            })
+            .collect())
+    }
+
+    fn insert_data_entries<I>(&mut self, entries: I, _chunk_size: usize) -> Result<()>
+    where
+        I: IntoIterator<Item = InsertableDataEntry>,
+    {
+        let entries = entries.into_iter().collect_vec();
+        let panic_on_key = self.state().panic_on_key.clone();
+        if let Some(key) = panic_on_key.filter(|key| entries.iter().any(|de| &de.key == key)) {
+            panic!("data entry {} cannot be inserted", key);
+        }
+
+        let mut state = self.state();
+        for de in entries {
+            let height = state.block(de.block_uid).map(|b| b.height);
+            let is_known = state.history_keys.iter().any(|hk| {
+                hk.address == de.address && hk.key == de.key && hk.data_entry_uid == de.uid
+            });
+            if !is_known {
+                state.history_keys.push(MockHistoryKey {
+                    address: de.address.clone(),
+                    key: de.key.clone(),
+                    data_entry_uid: de.uid,
+                    block_uid: de.block_uid,
+                    height,
+                });
+            }
+            state.data_entries.push(de);
+        }
+        Ok(())
+    }
+
+    fn copy_data_entries<I>(&mut self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = InsertableDataEntry>,
+    {
+        self.insert_data_entries(entries, usize::MAX)
+    }
+
+    fn insert_leases(&mut self, leases: &[InsertableLease]) -> Result<()> {
+        self.state().leases.extend(leases.iter().cloned());
+        Ok(())
+    }
+
+    fn insert_transfers(&mut self, transfers: &[InsertableTransfer]) -> Result<()> {
+        self.state().transfers.extend(transfers.iter().cloned());
+        Ok(())
+    }
+
+    fn insert_contract_events(&mut self, events: &[InsertableContractEvent]) -> Result<()> {
+        self.state().contract_events.extend(events.iter().cloned());
+        Ok(())
+    }
+
+    fn insert_transactions(&mut self, transactions: &[InsertableTransaction]) -> Result<()> {
+        self.state()
+            .transactions
+            .extend(transactions.iter().cloned());
+        Ok(())
+    }
+
+    fn close_superseded_by(&mut self, updates: &[DataEntryUpdate]) -> Result<()> {
+        let mut state = self.state();
+        for update in updates {
+            state
+                .data_entries
+                .iter_mut()
+                .filter(|de| {
+                    de.address == update.address
+                        && de.key == update.key
+                        && de.superseded_by == LIVE_SUPERSEDED_BY
+                })
+                .for_each(|de| de.superseded_by = update.superseded_by);
+        }
+        Ok(())
+    }
+
+    fn reopen_superseded_by(&mut self, current_superseded_by: &[i64]) -> Result<()> {
+        self.state()
+            .data_entries
+            .iter_mut()
+            .filter(|de| current_superseded_by.contains(&de.superseded_by))
+            .for_each(|de| de.superseded_by = LIVE_SUPERSEDED_BY);
+        Ok(())
+    }
+
+    fn set_next_update_uid(&mut self, uid: i64) -> Result<()> {
+        self.state().next_update_uid = uid;
+        Ok(())
+    }
+
+    fn change_block_id(&mut self, block_uid: &i64, new_block_id: &str) -> Result<()> {
+        self.state()
+            .blocks_microblocks
+            .iter_mut()
+            .filter(|b| b.uid == *block_uid)
+            .for_each(|b| b.id = new_block_id.to_string());
+        Ok(())
+    }
+
+    fn update_data_entries_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        let mut state = self.state();
+        state
+            .data_entries
+            .iter_mut()
+            .filter(|de| de.block_uid > *block_uid)
+            .for_each(|de| de.block_uid = *block_uid);
+        state
+            .history_keys
+            .iter_mut()
+            .filter(|hk| hk.block_uid > *block_uid)
+            .for_each(|hk| hk.block_uid = *block_uid);
+        Ok(())
+    }
+
+    fn update_leases_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.state()
+            .leases
+            .iter_mut()
+            .filter(|l| l.block_uid > *block_uid)
+            .for_each(|l| l.block_uid = *block_uid);
+        Ok(())
+    }
+
+    fn update_transfers_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.state()
+            .transfers
+            .iter_mut()
+            .filter(|t| t.block_uid > *block_uid)
+            .for_each(|t| t.block_uid = *block_uid);
+        Ok(())
+    }
+
+    fn update_contract_events_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.state()
+            .contract_events
+            .iter_mut()
+            .filter(|ce| ce.block_uid > *block_uid)
+            .for_each(|ce| ce.block_uid = *block_uid);
+        Ok(())
+    }
+
+    fn update_transactions_block_references(&mut self, block_uid: &i64) -> Result<()> {
+        self.state()
+            .transactions
+            .iter_mut()
+            .filter(|tx| tx.block_uid > *block_uid)
+            .for_each(|tx| tx.block_uid = *block_uid);
+        Ok(())
+    }
+
+    fn delete_microblocks(&mut self) -> Result<()> {
+        self.state()
+            .delete_blocks_microblocks(|b| b.time_stamp.is_none());
+        Ok(())
+    }
+
+    fn rollback_blocks_microblocks(&mut self, block_uid: &i64) -> Result<()> {
+        self.state()
+            .delete_blocks_microblocks(|b| b.uid > *block_uid);
+        Ok(())
+    }
+
+    fn rollback_microblocks(&mut self, block_uid: &i64) -> Result<()> {
+        self.state()
+            .delete_blocks_microblocks(|b| b.uid > *block_uid && b.time_stamp.is_none());
+        Ok(())
+    }
+
+    fn rollback_data_entries(&mut self, block_uid: &i64) -> Result<Vec<DeletedDataEntry>> {
+        let mut state = self.state();
+        let (deleted, kept): (Vec<_>, Vec<_>) = state
+            .data_entries
+            .drain(..)
+            .partition(|de| de.block_uid > *block_uid);
+        state.data_entries = kept;
+
+        Ok(deleted
+            .into_iter()
+            .map(|de| DeletedDataEntry {
+                uid: de.uid,
+                address: de.address,
+                key: de.key,
+            })
+            .collect())
+    }
+
+    fn rollback_microblock_data_entries(
+        &mut self,
+        block_uid: &i64,
+    ) -> Result<Vec<DeletedDataEntry>> {
+        let mut state = self.state();
+        let last_kept_uid = state
+            .data_entries
+            .iter()
+            .filter(|de| de.block_uid <= *block_uid)
+            .map(|de| de.uid)
+            .max()
+            .unwrap_or(0);
+        let (deleted, kept): (Vec<_>, Vec<_>) = state
+            .data_entries
+            .drain(..)
+            .partition(|de| de.uid > last_kept_uid);
+        state.data_entries = kept;
+
+        Ok(deleted
+            .into_iter()
+            .map(|de| DeletedDataEntry {
+                uid: de.uid,
+                address: de.address,
+                key: de.key,
+            })
+            .collect())
+    }
+
+    fn refresh_last_writes(&mut self, keys: &[(String, String)]) -> Result<()> {
+        let mut state = self.state();
+        for (address, key) in keys {
+            let height = state
+                .data_entries
+                .iter()
+                .find(|de| {
+                    de.address == *address
+                        && de.key == *key
+                        && de.superseded_by == LIVE_SUPERSEDED_BY
+                })
+                .and_then(|de| state.block(de.block_uid))
+                .map(|b| b.height);
+
+            match height {
+                Some(height) => state
+                    .last_writes
+                    .insert((address.clone(), key.clone()), height),
+                None => state.last_writes.remove(&(address.clone(), key.clone())),
+            };
+        }
+        Ok(())
+    }
+
+    fn delete_unreferenced_blocks_microblocks(&mut self, below_height: i32) -> Result<usize> {
+        let mut state = self.state();
+        let referenced = state
+            .data_entries
+            .iter()
+            .map(|de| de.block_uid)
+            .chain(state.history_keys.iter().map(|hk| hk.block_uid))
+            .chain(state.leases.iter().map(|l| l.block_uid))
+            .chain(state.transfers.iter().map(|t| t.block_uid))
+            .chain(state.contract_events.iter().map(|ce| ce.block_uid))
+            .chain(state.transactions.iter().map(|tx| tx.block_uid))
+            .collect::<std::collections::HashSet<i64>>();
+
+        let before = state.blocks_microblocks.len();
+        state
+            .delete_blocks_microblocks(|b| b.height < below_height && !referenced.contains(&b.uid));
+        Ok(before - state.blocks_microblocks.len())
+    }
+
+    /// Every mock entry has its `value_type`, there is nothing to backfill.
+    fn is_value_type_validated(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn backfill_value_types(&mut self, _from_uid: i64, _to_uid: i64) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn validate_value_type(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_superseded_data_entries(&mut self, block_uid: &i64, limit: i64) -> Result<usize> {
+        let mut state = self.state();
+        let last_uid = match state
+            .data_entries
+            .iter()
+            .filter(|de| de.block_uid <= *block_uid)
+            .map(|de| de.uid)
+            .max()
+        {
+            Some(last_uid) => last_uid,
+            None => return Ok(0),
+        };
+
+        let uids = state
+            .data_entries
+            .iter()
+            .filter(|de| de.superseded_by <= last_uid)
+            .take(limit as usize)
+            .map(|de| de.uid)
+            .collect::<std::collections::HashSet<i64>>();
+
+        state
+            .history_keys
+            .retain(|hk| !uids.contains(&hk.data_entry_uid));
+        state.data_entries.retain(|de| !uids.contains(&de.uid));
+        Ok(uids.len())
+    }
+}
@@ -1,15 +1,28 @@
+use super::proxy::ProxyConnector;
 use super::{
-    BlockMicroblockAppend, BlockchainUpdate, BlockchainUpdatesWithLastHeight, DataEntriesSource,
-    DataEntry,
+    BlockMicroblockAppend, BlockTransaction, BlockchainUpdate, BlockchainUpdatesWithLastHeight,
+    Config, ContractEvent, DataEntriesSource, DataEntry, DataEntryValue, Lease, Transfer,
+    CONTRACT_EVENT_SET_SCRIPT, CONTRACT_EVENT_UPDATE_ASSET_INFO, LEASE_STATUS_ACTIVE,
+    LEASE_STATUS_CANCELED,
 };
-use crate::error::AppError;
-use anyhow::Result;
+use crate::error::{panic_message, AppError};
+use crate::logging::{error, warn};
+use crate::metrics::{GRPC_KEEPALIVES_TOTAL, PARSE_ERRORS_TOTAL};
+use anyhow::{Context, Error, Result};
 use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::time::{Duration, Instant};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use waves_protobuf_schemas::{
-    tonic,
+    tonic::{
+        self,
+        transport::{Certificate, ClientTlsConfig, Endpoint},
+    },
     waves::{
         data_entry::Value,
         events::{
@@ -20,111 +33,545 @@ use waves_protobuf_schemas::{
                 blockchain_updates_api_client::BlockchainUpdatesApiClient, SubscribeEvent,
                 SubscribeRequest,
             },
-            BlockchainUpdated,
+            state_update::lease_update::LeaseStatus,
+            transaction_metadata::Metadata,
+            BlockchainUpdated, StateUpdate, TransactionMetadata,
         },
+        recipient::Recipient as RecipientKind,
+        signed_transaction::Transaction,
+        transaction::Data,
+        Recipient, SignedTransaction,
     },
 };
 
+/// What to do with an update which cannot be parsed.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseErrorAction {
+    /// Log the update and continue with the next one, so it is missing from the database.
+    Skip,
+    /// Stop the stream.
+    Fail,
+}
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Messages to be received after a reconnect before the connection counts as restored,
+/// so an endpoint which fails right after a message still exhausts the attempts.
+const RECONNECT_RESET_MESSAGES: u32 = 10;
+
 #[derive(Clone)]
 pub struct DataEntriesSourceImpl {
     grpc_client: BlockchainUpdatesApiClient<tonic::transport::Channel>,
+    max_reconnect_attempts: Option<u32>,
+    channel_capacity: usize,
+    batch_max_bytes: usize,
+    expected_chain_id: Option<u8>,
+    on_parse_error: ParseErrorAction,
+    ingest_tx_types: Option<HashSet<i16>>,
+    last_message_at: Arc<AtomicI64>,
 }
 
 impl DataEntriesSourceImpl {
-    pub async fn new(blockchain_updates_url: &str) -> Result<Self> {
+    /// The channel connects on its first request, so the service starts while the node is down,
+    /// and a failed connection is retried like a failed stream.
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut endpoint = ChannelSettings::new(config).apply(Endpoint::from_shared(
+            config.blockchain_updates_url.clone(),
+        )?);
+
+        if let Some(tls_config) = tls_config(config)? {
+            endpoint = endpoint.tls_config(tls_config)?;
+        }
+
+        let channel = match &config.blockchain_updates_proxy_url {
+            Some(proxy_url) => {
+                endpoint.connect_with_connector_lazy(ProxyConnector::new(proxy_url)?)
+            }
+            None => endpoint.connect_lazy(),
+        };
+
         Ok(Self {
             grpc_client: {
                 const MAX_MSG_SIZE: usize = 8 * 1024 * 1024; // 8 MB instead of the default 4 MB
-                BlockchainUpdatesApiClient::connect(blockchain_updates_url.to_owned())
-                    .await?
-                    .max_decoding_message_size(MAX_MSG_SIZE)
+                BlockchainUpdatesApiClient::new(channel).max_decoding_message_size(MAX_MSG_SIZE)
             },
+            max_reconnect_attempts: config.max_reconnect_attempts,
+            channel_capacity: config.channel_capacity,
+            batch_max_bytes: config.batch_max_bytes,
+            expected_chain_id: config.expected_chain_id,
+            on_parse_error: config.on_parse_error,
+            ingest_tx_types: config.ingest_tx_types.clone(),
+            last_message_at: Arc::new(AtomicI64::new(now_millis())),
         })
     }
 
-    async fn run(
+    /// Time of the last message received from the stream in epoch milliseconds,
+    /// so a stalled stream can be noticed before the blocks in the database get old.
+    pub fn last_message_at(&self) -> Arc<AtomicI64> {
+        self.last_message_at.clone()
+    }
+
+    /// The stream is read by a spawned task, so the error it stops on is sent
+    /// as the last item of the channel instead of only closing it.
+    async fn stream_from<S>(
+        self,
+        subscriber: S,
+        from_height: u32,
+        to_height: Option<u32>,
+        batch_max_size: usize,
+        batch_max_wait_time: Duration,
+    ) -> Result<Receiver<Result<BlockchainUpdatesWithLastHeight>>>
+    where
+        S: Subscribe + 'static,
+    {
+        let stream = subscriber.subscribe(from_height, to_height).await?;
+
+        let (tx, rx) = channel(self.channel_capacity);
+
+        tokio::spawn(async move {
+            let result = self
+                .run(
+                    &subscriber,
+                    stream,
+                    tx.clone(),
+                    from_height,
+                    to_height,
+                    batch_max_size,
+                    batch_max_wait_time,
+                )
+                .await;
+            if let Err(err) = result {
+                // the receiver is only gone when the consumer has stopped already
+                let _ = tx.send(Err(err)).await;
+            }
+        });
+
+        Ok(rx)
+    }
+
+    /// Re-subscribe with exponential backoff until the stream is restored
+    /// or `max_reconnect_attempts` are exhausted. Attempts are counted across consecutive
+    /// failures and reset by the caller after `RECONNECT_RESET_MESSAGES` are received.
+    /// The stream is resumed from the last received height, which requires `implicit_rollbacks`.
+    async fn reconnect<S: Subscribe>(
+        &self,
+        subscriber: &S,
+        reconnect_attempts: &mut u32,
+        from_height: u32,
+        to_height: Option<u32>,
+        reason: String,
+    ) -> Result<S::Stream> {
+        let mut reason = reason;
+
+        loop {
+            *reconnect_attempts += 1;
+
+            if let Some(max_reconnect_attempts) = self.max_reconnect_attempts {
+                if *reconnect_attempts > max_reconnect_attempts {
+                    return Err(Error::new(AppError::StreamClosed(format!(
+                        "Giving up after {} reconnect attempts: {}",
+                        max_reconnect_attempts, reason
+                    ))));
+                }
+            }
+
+            let delay = reconnect_delay(*reconnect_attempts);
+
+            warn!(
+                "GRPC stream failed: {}. Reconnecting from height {} in {:?} (attempt {}).",
+                reason, from_height, delay, reconnect_attempts
+            );
+
+            tokio::time::sleep(delay).await;
+
+            match subscriber.subscribe(from_height, to_height).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => reason = err.to_string(),
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn run<S: Subscribe>(
         &self,
-        mut stream: tonic::Streaming<SubscribeEvent>,
-        tx: Sender<BlockchainUpdatesWithLastHeight>,
+        subscriber: &S,
+        mut stream: S::Stream,
+        tx: Sender<Result<BlockchainUpdatesWithLastHeight>>,
         from_height: u32,
+        to_height: Option<u32>,
         batch_max_size: usize,
         batch_max_wait_time: Duration,
     ) -> Result<()> {
         let mut result = vec![];
+        let mut result_bytes = 0;
         let mut last_height = from_height;
+        let mut resume_height = from_height;
+        let mut reconnect_attempts = 0;
+        let mut messages_since_reconnect = 0;
 
         let mut start = Instant::now();
         let mut should_receive_more = true;
 
         loop {
-            if let Some(SubscribeEvent {
-                update: Some(update),
-            }) = stream.message().await?
-            {
-                last_height = update.height as u32;
-                match BlockchainUpdate::try_from(update) {
-                    Ok(upd) => Ok({
-                        result.push(upd.clone());
-                        match upd {
-                            BlockchainUpdate::Block(_) => {
-                                if result.len() >= batch_max_size
-                                    || start.elapsed().ge(&batch_max_wait_time)
-                                {
-                                    should_receive_more = false;
+            let message = match stream.message().await {
+                Ok(Some(message)) => {
+                    self.last_message_at.store(now_millis(), Ordering::Relaxed);
+                    Some(message)
+                }
+                // a bounded stream is closed by the server after the target height
+                Ok(None) if to_height.is_some_and(|to_height| last_height >= to_height) => {
+                    if !result.is_empty() {
+                        tx.send(Ok(BlockchainUpdatesWithLastHeight {
+                            last_height,
+                            updates: result,
+                        }))
+                        .await?;
+                    }
+                    return Ok(());
+                }
+                Ok(None) => {
+                    stream = self
+                        .reconnect(
+                            subscriber,
+                            &mut reconnect_attempts,
+                            resume_height,
+                            to_height,
+                            "stream was closed by the server".to_string(),
+                        )
+                        .await?;
+                    messages_since_reconnect = 0;
+                    None
+                }
+                Err(err) => {
+                    stream = self
+                        .reconnect(
+                            subscriber,
+                            &mut reconnect_attempts,
+                            resume_height,
+                            to_height,
+                            err.to_string(),
+                        )
+                        .await?;
+                    messages_since_reconnect = 0;
+                    None
+                }
+            };
+
+            match message {
+                Some(SubscribeEvent {
+                    update: Some(update),
+                }) => {
+                    messages_since_reconnect += 1;
+                    if messages_since_reconnect >= RECONNECT_RESET_MESSAGES {
+                        reconnect_attempts = 0;
+                    }
+                    last_height = update.height as u32;
+                    // microblocks of the last height may still follow, so it is received again
+                    // and the consumer replaces it by an implicit rollback
+                    resume_height = last_height;
+                    if let Some(expected_chain_id) = self.expected_chain_id {
+                        check_chain_id(&update, expected_chain_id)?;
+                    }
+                    let update_id = bs58::encode(&update.id).into_string();
+                    match parse_update(update) {
+                        Ok(upd) => {
+                            let upd = match &self.ingest_tx_types {
+                                Some(tx_types) => retain_tx_types(upd, tx_types),
+                                None => upd,
+                            };
+                            result_bytes += estimate_size(&upd);
+                            // the update is counted before it is moved into the batch
+                            match &upd {
+                                BlockchainUpdate::Block(_) => {
+                                    if result.len() + 1 >= batch_max_size
+                                        || result_bytes >= self.batch_max_bytes
+                                        || start.elapsed().ge(&batch_max_wait_time)
+                                    {
+                                        should_receive_more = false;
+                                    }
                                 }
+                                BlockchainUpdate::Microblock(_)
+                                | BlockchainUpdate::Rollback(..) => should_receive_more = false,
                             }
-                            BlockchainUpdate::Microblock(_) | BlockchainUpdate::Rollback(_) => {
-                                should_receive_more = false
-                            }
+                            result.push(upd);
                         }
-                    }),
-                    Err(err) => Err(err),
-                }?;
+                        // a reconnect receives the update again, and it is skipped again
+                        Err(err) if self.on_parse_error == ParseErrorAction::Skip => {
+                            error!(
+                                "Skipping update {} at height {} which cannot be parsed: {}",
+                                update_id, last_height, err
+                            );
+                            PARSE_ERRORS_TOTAL.inc();
+                        }
+                        Err(err) => {
+                            PARSE_ERRORS_TOTAL.inc();
+                            return Err(Error::new(err).context(format!(
+                                "Update {} at height {} cannot be parsed",
+                                update_id, last_height
+                            )));
+                        }
+                    }
+                }
+                // some nodes send events without an update to keep the connection alive
+                Some(SubscribeEvent { update: None }) => GRPC_KEEPALIVES_TOTAL.inc(),
+                None => (),
             }
 
             if !should_receive_more {
-                tx.send(BlockchainUpdatesWithLastHeight {
-                    last_height: last_height,
-                    updates: result.clone(),
-                })
+                tx.send(Ok(BlockchainUpdatesWithLastHeight {
+                    last_height,
+                    updates: std::mem::take(&mut result),
+                }))
                 .await?;
                 should_receive_more = true;
                 start = Instant::now();
-                result.clear();
+                result_bytes = 0;
             }
         }
     }
 }
 
+/// A panic while mapping an update is turned into a parse error, so a pathological block
+/// is handled by `on_parse_error` instead of taking the process down.
+/// `catch_unwind` requires the closure to be unwind safe: the update is moved into it,
+/// so nothing can observe a half-mapped value afterwards, which makes asserting it sound.
+fn parse_update(update: BlockchainUpdated) -> Result<BlockchainUpdate, AppError> {
+    std::panic::catch_unwind(AssertUnwindSafe(move || BlockchainUpdate::try_from(update)))
+        .unwrap_or_else(|panic| {
+            Err(AppError::InvalidMessage(format!(
+                "mapping of the update panicked: {}",
+                panic_message(panic.as_ref())
+            )))
+        })
+}
+
+/// Rough size of an update in memory, only the variable length fields are counted.
+fn estimate_size(update: &BlockchainUpdate) -> usize {
+    match update {
+        BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => {
+            let data_entries: usize = append
+                .data_entries
+                .iter()
+                .map(|de| {
+                    de.address.len()
+                        + de.key.len()
+                        + de.transaction_id.len()
+                        + match &de.value {
+                            DataEntryValue::Binary(v) => v.len(),
+                            DataEntryValue::String(v) => v.len(),
+                            _ => 0,
+                        }
+                        + std::mem::size_of::<DataEntry>()
+                })
+                .sum();
+            append.id.len()
+                + data_entries
+                + append.leases.len() * std::mem::size_of::<Lease>()
+                + append.transfers.len() * std::mem::size_of::<Transfer>()
+                + append.contract_events.len() * std::mem::size_of::<ContractEvent>()
+                + append.transactions.len() * std::mem::size_of::<BlockTransaction>()
+        }
+        BlockchainUpdate::Rollback(id, _) => id.len(),
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Zero seconds disable the setting they are passed to.
+fn non_zero_secs(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|d| !d.is_zero())
+}
+
+/// Timeouts and keepalives of the channel to the node, they are kept apart from the endpoint
+/// since it does not expose them.
+#[derive(Debug, PartialEq)]
+struct ChannelSettings {
+    connect_timeout: Duration,
+    tcp_keepalive: Option<Duration>,
+    http2_keep_alive_interval: Option<Duration>,
+}
+
+impl ChannelSettings {
+    fn new(config: &Config) -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(config.grpc_connect_timeout_secs),
+            tcp_keepalive: non_zero_secs(config.grpc_tcp_keepalive_secs),
+            http2_keep_alive_interval: non_zero_secs(config.grpc_http2_keepalive_interval_secs),
+        }
+    }
+
+    fn apply(&self, endpoint: Endpoint) -> Endpoint {
+        let endpoint = endpoint
+            .connect_timeout(self.connect_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+
+        match self.http2_keep_alive_interval {
+            Some(interval) => endpoint.http2_keep_alive_interval(interval),
+            None => endpoint,
+        }
+    }
+}
+
+/// Backoff doubles from `RECONNECT_MIN_DELAY` on every attempt up to `RECONNECT_MAX_DELAY`.
+fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_MIN_DELAY
+        .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .map_or(RECONNECT_MAX_DELAY, |delay| delay.min(RECONNECT_MAX_DELAY))
+}
+
+/// Transactions of another network would mix its state into the database,
+/// so the stream is stopped on the first one, and the consumer fails with `WrongNetwork`.
+fn check_chain_id(update: &BlockchainUpdated, expected_chain_id: u8) -> Result<(), AppError> {
+    let (transaction_ids, transactions) = match &update.update {
+        Some(Update::Append(Append {
+            transaction_ids,
+            body:
+                Some(Body::Block(BlockAppend {
+                    block: Some(block), ..
+                })),
+            ..
+        })) => (transaction_ids, &block.transactions),
+        Some(Update::Append(Append {
+            transaction_ids,
+            body:
+                Some(Body::MicroBlock(MicroBlockAppend {
+                    micro_block: Some(micro_block),
+                    ..
+                })),
+            ..
+        })) => match &micro_block.micro_block {
+            Some(micro_block) => (transaction_ids, &micro_block.transactions),
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+
+    for (id, tx) in transaction_ids.iter().zip(transactions) {
+        if let Some(Transaction::WavesTransaction(tx)) = &tx.transaction {
+            if tx.chain_id != expected_chain_id as i32 {
+                return Err(AppError::WrongNetwork(format!(
+                    "Transaction {} at height {} has chain id {} ('{}'), but {} ('{}') is expected. Is the node of another network?",
+                    bs58::encode(id).into_string(),
+                    update.height,
+                    tx.chain_id,
+                    char::from(tx.chain_id as u8),
+                    expected_chain_id,
+                    char::from(expected_chain_id)
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// TLS is enabled automatically for `https` urls, and also when a domain name is configured,
+/// so the certificate can be validated against it while connecting by IP address.
+/// Certificates are checked against the webpki roots, a configured CA only adds to them.
+fn tls_config(config: &Config) -> Result<Option<ClientTlsConfig>> {
+    let is_https = config.blockchain_updates_url.starts_with("https://");
+
+    if !is_https && config.blockchain_updates_tls_domain_name.is_none() {
+        return Ok(None);
+    }
+
+    let mut tls_config = ClientTlsConfig::new();
+
+    if let Some(domain_name) = &config.blockchain_updates_tls_domain_name {
+        tls_config = tls_config.domain_name(domain_name);
+    }
+
+    if let Some(ca_cert_path) = &config.blockchain_updates_ca_cert_path {
+        let pem = std::fs::read(ca_cert_path)
+            .with_context(|| format!("Cannot read CA certificate from {}", ca_cert_path))?;
+        tls_config = tls_config.ca_certificate(Certificate::from_pem(pem));
+    }
+
+    Ok(Some(tls_config))
+}
+
+/// Opens the update streams, so the reconnecting loop does not depend on a node connection.
+#[async_trait]
+trait Subscribe: Send + Sync {
+    type Stream: UpdatesStream + Send;
+
+    async fn subscribe(&self, from_height: u32, to_height: Option<u32>) -> Result<Self::Stream>;
+}
+
+#[async_trait]
+trait UpdatesStream {
+    async fn message(&mut self) -> Result<Option<SubscribeEvent>, tonic::Status>;
+}
+
+#[async_trait]
+impl Subscribe for BlockchainUpdatesApiClient<tonic::transport::Channel> {
+    type Stream = tonic::Streaming<SubscribeEvent>;
+
+    async fn subscribe(&self, from_height: u32, to_height: Option<u32>) -> Result<Self::Stream> {
+        let request = tonic::Request::new(SubscribeRequest {
+            from_height: from_height as i32,
+            // zero means an unbounded stream
+            to_height: to_height.map_or(0, |h| h as i32),
+        });
+
+        Ok(
+            BlockchainUpdatesApiClient::subscribe(&mut self.clone(), request)
+                .await?
+                .into_inner(),
+        )
+    }
+}
+
+#[async_trait]
+impl UpdatesStream for tonic::Streaming<SubscribeEvent> {
+    async fn message(&mut self) -> Result<Option<SubscribeEvent>, tonic::Status> {
+        tonic::Streaming::message(self).await
+    }
+}
+
 #[async_trait]
 impl DataEntriesSource for DataEntriesSourceImpl {
     async fn stream(
         self,
         from_height: u32,
+        to_height: Option<u32>,
         batch_max_size: usize,
         batch_max_wait_time: Duration,
-    ) -> Result<Receiver<BlockchainUpdatesWithLastHeight>> {
-        let request = tonic::Request::new(SubscribeRequest {
-            from_height: from_height as i32,
-            to_height: 0,
-        });
+    ) -> Result<Receiver<Result<BlockchainUpdatesWithLastHeight>>> {
+        let grpc_client = self.grpc_client.clone();
+        self.stream_from(
+            grpc_client,
+            from_height,
+            to_height,
+            batch_max_size,
+            batch_max_wait_time,
+        )
+        .await
+    }
 
-        let stream: tonic::Streaming<SubscribeEvent> = self
-            .grpc_client
-            .clone()
-            .subscribe(request)
-            .await?
-            .into_inner();
+    async fn block(self, height: u32) -> Result<Vec<BlockchainUpdate>> {
+        receive_block(&self.grpc_client, height).await
+    }
+}
 
-        let (tx, rx) = channel::<BlockchainUpdatesWithLastHeight>(1);
+/// Updates of the block at the height, the stream is bounded to it.
+async fn receive_block<S: Subscribe>(subscriber: &S, height: u32) -> Result<Vec<BlockchainUpdate>> {
+    let mut stream = subscriber.subscribe(height, Some(height)).await?;
 
-        tokio::spawn(async move {
-            self.run(stream, tx, from_height, batch_max_size, batch_max_wait_time)
-                .await
-        });
+    let mut updates = vec![];
 
-        Ok(rx)
+    while let Some(SubscribeEvent { update }) = stream.message().await? {
+        if let Some(update) = update {
+            updates.push(parse_update(update)?);
+        }
     }
+
+    Ok(updates)
 }
 
 impl TryFrom<BlockchainUpdated> for BlockchainUpdate {
@@ -135,81 +582,65 @@ impl TryFrom<BlockchainUpdated> for BlockchainUpdate {
             Some(Update::Append(Append {
                 body,
                 transaction_ids,
+                transactions_metadata,
                 transaction_state_updates,
                 ..
             })) => {
                 let height = value.height;
 
-                let data_entries = transaction_state_updates
-                    .iter()
-                    .enumerate()
-                    .flat_map::<Vec<DataEntry>, _>(|(idx, su)| {
-                        su.data_entries
-                            .iter()
-                            .map(|de| {
-                                let deu = de.data_entry.as_ref().unwrap();
-
-                                let mut value_string: Option<String> = None;
-                                let mut value_integer: Option<i64> = None;
-                                let mut value_bool: Option<bool> = None;
-                                let mut value_binary: Option<Vec<u8>> = None;
-
-                                match deu.value.as_ref() {
-                                    Some(value) => match value {
-                                        Value::IntValue(v) => value_integer = Some(v.to_owned()),
-                                        Value::BoolValue(v) => value_bool = Some(v.to_owned()),
-                                        Value::BinaryValue(v) => value_binary = Some(v.to_owned()),
-                                        Value::StringValue(v) => {
-                                            value_string = Some(v.replace("\0", "\\0").to_owned())
-                                        }
-                                    },
-                                    None => {}
-                                }
-
-                                DataEntry {
-                                    address: bs58::encode(&de.address).into_string(),
-                                    // nul symbol is badly processed at least by PostgreSQL
-                                    // so escape this for safety
-                                    key: de
-                                        .data_entry
-                                        .as_ref()
-                                        .unwrap()
-                                        .key
-                                        .clone()
-                                        .replace("\0", "\\0"),
-                                    transaction_id: bs58::encode(
-                                        &transaction_ids.get(idx).unwrap(),
-                                    )
-                                    .into_string(),
-                                    value_binary: value_binary,
-                                    value_bool: value_bool,
-                                    value_integer: value_integer,
-                                    value_string: value_string,
-                                }
-                            })
-                            .collect()
-                    })
-                    .collect();
+                let data_entries =
+                    extract_data_entries(&transaction_ids, &transaction_state_updates);
+                let leases = extract_leases(&transaction_ids, &transaction_state_updates);
 
                 match body {
                     Some(Body::Block(BlockAppend { block, .. })) => {
+                        let time_stamp = block
+                            .as_ref()
+                            .and_then(|b| b.header.as_ref().map(|h| h.timestamp));
+                        let transactions = block.map(|b| b.transactions).unwrap_or_default();
                         Ok(BlockchainUpdate::Block(BlockMicroblockAppend {
                             id: bs58::encode(&value.id).into_string(),
-                            time_stamp: block
-                                .clone()
-                                .map(|b| b.header.map(|h| Some(h.timestamp)).unwrap_or(None))
-                                .unwrap_or(None),
+                            time_stamp,
                             height: height as u32,
-                            data_entries: data_entries,
+                            data_entries,
+                            leases,
+                            transfers: extract_transfers(
+                                &transaction_ids,
+                                &transactions_metadata,
+                                &transactions,
+                            ),
+                            contract_events: extract_contract_events(
+                                &transaction_ids,
+                                &transactions_metadata,
+                                &transactions,
+                            ),
+                            transactions: extract_transactions(&transaction_ids, &transactions),
                         }))
                     }
                     Some(Body::MicroBlock(MicroBlockAppend { micro_block, .. })) => {
+                        let id = bs58::encode(&micro_block.as_ref().unwrap().total_block_id)
+                            .into_string();
+                        let transactions = micro_block
+                            .and_then(|mb| mb.micro_block)
+                            .map(|mb| mb.transactions)
+                            .unwrap_or_default();
                         Ok(BlockchainUpdate::Microblock(BlockMicroblockAppend {
-                            id: bs58::encode(&micro_block.as_ref().unwrap().total_block_id)
-                                .into_string(),
+                            id,
                             time_stamp: None,
                             height: height as u32,
-                            data_entries: data_entries,
+                            data_entries,
+                            leases,
+                            transfers: extract_transfers(
+                                &transaction_ids,
+                                &transactions_metadata,
+                                &transactions,
+                            ),
+                            contract_events: extract_contract_events(
+                                &transaction_ids,
+                                &transactions_metadata,
+                                &transactions,
+                            ),
+                            transactions: extract_transactions(&transaction_ids, &transactions),
                         }))
                     }
                     _ => Err(AppError::InvalidMessage(
@@ -219,6 +650,7 @@ impl TryFrom<BlockchainUpdated> for BlockchainUpdate {
             }
             Some(Update::Rollback(_)) => Ok(BlockchainUpdate::Rollback(
                 bs58::encode(&value.id).into_string(),
+                value.height as u32,
             )),
             _ => Err(AppError::InvalidMessage(
                 "Unknown blockchain update.".to_string(),
@@ -226,3 +658,1184 @@ impl TryFrom<BlockchainUpdated> for BlockchainUpdate {
         }
     }
 }
+
+/// Map data entries of every transaction state update, attributed to the owning transaction.
+/// A deletion (an entry without a value) is mapped to `DataEntryValue::Deleted`.
+///
+/// State updates are not specific to a transaction type, so entries written by an invoke
+/// are covered too: every entry keeps the address of the dApp it was written to,
+/// including the ones of nested invokes, and the id of the top-level invoke transaction.
+fn extract_data_entries(
+    transaction_ids: &[Vec<u8>],
+    transaction_state_updates: &[StateUpdate],
+) -> Vec<DataEntry> {
+    transaction_state_updates
+        .iter()
+        .enumerate()
+        .flat_map::<Vec<DataEntry>, _>(|(idx, su)| {
+            su.data_entries
+                .iter()
+                .map(|de| {
+                    let deu = de.data_entry.as_ref().unwrap();
+
+                    let value = match deu.value.as_ref() {
+                        Some(Value::IntValue(v)) => DataEntryValue::Integer(*v),
+                        Some(Value::BoolValue(v)) => DataEntryValue::Bool(*v),
+                        Some(Value::BinaryValue(v)) => DataEntryValue::Binary(v.to_owned()),
+                        Some(Value::StringValue(v)) => {
+                            DataEntryValue::String(v.replace("\0", "\\0"))
+                        }
+                        None => DataEntryValue::Deleted,
+                    };
+
+                    DataEntry {
+                        address: bs58::encode(&de.address).into_string(),
+                        // nul symbol is badly processed at least by PostgreSQL
+                        // so escape this for safety
+                        key: deu.key.clone().replace("\0", "\\0"),
+                        transaction_id: bs58::encode(&transaction_ids.get(idx).unwrap())
+                            .into_string(),
+                        value,
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn extract_leases(
+    transaction_ids: &[Vec<u8>],
+    transaction_state_updates: &[StateUpdate],
+) -> Vec<Lease> {
+    transaction_state_updates
+        .iter()
+        .enumerate()
+        .flat_map::<Vec<Lease>, _>(|(idx, su)| {
+            su.individual_leases
+                .iter()
+                .map(|lease| Lease {
+                    lease_id: bs58::encode(&lease.lease_id).into_string(),
+                    transaction_id: bs58::encode(&transaction_ids.get(idx).unwrap()).into_string(),
+                    sender: bs58::encode(&lease.sender).into_string(),
+                    recipient: bs58::encode(&lease.recipient).into_string(),
+                    amount: lease.amount,
+                    status: match lease.status_after() {
+                        LeaseStatus::Active => LEASE_STATUS_ACTIVE,
+                        LeaseStatus::Inactive => LEASE_STATUS_CANCELED,
+                    }
+                    .to_string(),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Map payment, transfer and mass transfer transactions to transfers, one per recipient,
+/// and payments attached to an invoke to transfers from the invoker to the dApp.
+/// Addresses are taken from the transaction metadata, so recipients given by alias are resolved;
+/// see `recipient_string` for recipients the metadata lacks.
+/// Waves has no asset id and is stored as `None`; zero amounts and transfers to the sender
+/// itself are kept as is.
+fn extract_transfers(
+    transaction_ids: &[Vec<u8>],
+    transactions_metadata: &[TransactionMetadata],
+    transactions: &[SignedTransaction],
+) -> Vec<Transfer> {
+    transactions
+        .iter()
+        .enumerate()
+        .flat_map::<Vec<Transfer>, _>(|(idx, tx)| {
+            let (chain_id, data, meta) = match (&tx.transaction, transactions_metadata.get(idx)) {
+                (Some(Transaction::WavesTransaction(tx)), Some(meta)) => match &tx.data {
+                    Some(data) => (tx.chain_id, data, meta),
+                    None => return vec![],
+                },
+                _ => return vec![],
+            };
+
+            let transfer =
+                |recipient: String, asset_id: &[u8], amount: i64, attachment: &[u8]| Transfer {
+                    transaction_id: bs58::encode(&transaction_ids.get(idx).unwrap()).into_string(),
+                    sender: bs58::encode(&meta.sender_address).into_string(),
+                    recipient,
+                    asset_id: if asset_id.is_empty() {
+                        None
+                    } else {
+                        Some(bs58::encode(asset_id).into_string())
+                    },
+                    amount,
+                    attachment: if attachment.is_empty() {
+                        None
+                    } else {
+                        Some(bs58::encode(attachment).into_string())
+                    },
+                };
+
+            match (data, &meta.metadata) {
+                (Data::Payment(payment), _) => {
+                    vec![transfer(
+                        bs58::encode(&payment.recipient_address).into_string(),
+                        &[],
+                        payment.amount,
+                        &[],
+                    )]
+                }
+                (Data::Transfer(data), meta) => {
+                    let resolved = match meta {
+                        Some(Metadata::Transfer(meta)) => Some(&meta.recipient_address),
+                        _ => None,
+                    };
+                    let (asset_id, amount) = data
+                        .amount
+                        .as_ref()
+                        .map(|a| (a.asset_id.clone(), a.amount))
+                        .unwrap_or_default();
+                    vec![transfer(
+                        recipient_string(resolved, data.recipient.as_ref(), chain_id),
+                        &asset_id,
+                        amount,
+                        &data.attachment,
+                    )]
+                }
+                // the asset and the attachment are shared by all of the recipients
+                (Data::MassTransfer(data), meta) => {
+                    let resolved = match meta {
+                        Some(Metadata::MassTransfer(meta)) => meta.recipients_addresses.as_slice(),
+                        _ => &[],
+                    };
+                    data.transfers
+                        .iter()
+                        .enumerate()
+                        .map(|(i, t)| {
+                            transfer(
+                                recipient_string(resolved.get(i), t.recipient.as_ref(), chain_id),
+                                &data.asset_id,
+                                t.amount,
+                                &data.attachment,
+                            )
+                        })
+                        .collect()
+                }
+                (Data::InvokeScript(data), Some(Metadata::InvokeScript(meta))) => data
+                    .payments
+                    .iter()
+                    .map(|payment| {
+                        transfer(
+                            bs58::encode(&meta.d_app_address).into_string(),
+                            &payment.asset_id,
+                            payment.amount,
+                            &[],
+                        )
+                    })
+                    .collect(),
+                _ => vec![],
+            }
+        })
+        .collect()
+}
+
+/// Map set script transactions to script changes of the sender, an empty script removes it,
+/// and update asset info transactions to asset info changes.
+fn extract_contract_events(
+    transaction_ids: &[Vec<u8>],
+    transactions_metadata: &[TransactionMetadata],
+    transactions: &[SignedTransaction],
+) -> Vec<ContractEvent> {
+    transactions
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, tx)| {
+            let (data, meta) = match (&tx.transaction, transactions_metadata.get(idx)) {
+                (Some(Transaction::WavesTransaction(tx)), Some(meta)) => (tx.data.as_ref()?, meta),
+                _ => return None,
+            };
+
+            let event = |event_type: &str| ContractEvent {
+                transaction_id: bs58::encode(&transaction_ids.get(idx).unwrap()).into_string(),
+                address: bs58::encode(&meta.sender_address).into_string(),
+                event_type: event_type.to_string(),
+                has_script: None,
+                asset_id: None,
+                asset_name: None,
+                asset_description: None,
+            };
+
+            match data {
+                Data::SetScript(data) => Some(ContractEvent {
+                    has_script: Some(!data.script.is_empty()),
+                    ..event(CONTRACT_EVENT_SET_SCRIPT)
+                }),
+                Data::UpdateAssetInfo(data) => Some(ContractEvent {
+                    asset_id: Some(bs58::encode(&data.asset_id).into_string()),
+                    asset_name: Some(data.name.clone()),
+                    asset_description: Some(data.description.clone()),
+                    ..event(CONTRACT_EVENT_UPDATE_ASSET_INFO)
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Ethereum transactions have a type of their own, whatever they do.
+fn extract_transactions(
+    transaction_ids: &[Vec<u8>],
+    transactions: &[SignedTransaction],
+) -> Vec<BlockTransaction> {
+    transaction_ids
+        .iter()
+        .zip(transactions)
+        .filter_map(|(id, tx)| {
+            let tx_type = match tx.transaction.as_ref()? {
+                Transaction::WavesTransaction(tx) => transaction_type(tx.data.as_ref()?),
+                Transaction::EthereumTransaction(_) => 18,
+            };
+            Some(BlockTransaction {
+                tx_id: bs58::encode(id).into_string(),
+                tx_type,
+            })
+        })
+        .collect()
+}
+
+/// Names of the transaction types accepted by `INGEST_TX_TYPES`.
+const TX_TYPE_NAMES: &[(&str, i16)] = &[
+    ("genesis", 1),
+    ("payment", 2),
+    ("issue", 3),
+    ("transfer", 4),
+    ("reissue", 5),
+    ("burn", 6),
+    ("exchange", 7),
+    ("lease", 8),
+    ("lease_cancel", 9),
+    ("create_alias", 10),
+    ("mass_transfer", 11),
+    ("data", 12),
+    ("set_script", 13),
+    ("sponsor_fee", 14),
+    ("set_asset_script", 15),
+    ("invoke", 16),
+    ("update_asset_info", 17),
+    ("ethereum", 18),
+];
+
+pub fn tx_type_by_name(name: &str) -> Option<i16> {
+    TX_TYPE_NAMES
+        .iter()
+        .find(|(tx_type_name, _)| *tx_type_name == name)
+        .map(|(_, tx_type)| *tx_type)
+}
+
+/// Drop everything written by transactions of other types. Rows of a transaction
+/// missing from the block body are dropped too, since their type is unknown.
+fn retain_tx_types(update: BlockchainUpdate, tx_types: &HashSet<i16>) -> BlockchainUpdate {
+    let retain = |mut append: BlockMicroblockAppend| {
+        append
+            .transactions
+            .retain(|tx| tx_types.contains(&tx.tx_type));
+        let tx_ids: HashSet<String> = append
+            .transactions
+            .iter()
+            .map(|tx| tx.tx_id.clone())
+            .collect();
+
+        append
+            .data_entries
+            .retain(|de| tx_ids.contains(&de.transaction_id));
+        append
+            .leases
+            .retain(|lease| tx_ids.contains(&lease.transaction_id));
+        append
+            .transfers
+            .retain(|transfer| tx_ids.contains(&transfer.transaction_id));
+        append
+            .contract_events
+            .retain(|event| tx_ids.contains(&event.transaction_id));
+        append
+    };
+
+    match update {
+        BlockchainUpdate::Block(append) => BlockchainUpdate::Block(retain(append)),
+        BlockchainUpdate::Microblock(append) => BlockchainUpdate::Microblock(retain(append)),
+        rollback @ BlockchainUpdate::Rollback(..) => rollback,
+    }
+}
+
+fn transaction_type(data: &Data) -> i16 {
+    match data {
+        Data::Genesis(_) => 1,
+        Data::Payment(_) => 2,
+        Data::Issue(_) => 3,
+        Data::Transfer(_) => 4,
+        Data::Reissue(_) => 5,
+        Data::Burn(_) => 6,
+        Data::Exchange(_) => 7,
+        Data::Lease(_) => 8,
+        Data::LeaseCancel(_) => 9,
+        Data::CreateAlias(_) => 10,
+        Data::MassTransfer(_) => 11,
+        Data::DataTransaction(_) => 12,
+        Data::SetScript(_) => 13,
+        Data::SponsorFee(_) => 14,
+        Data::SetAssetScript(_) => 15,
+        Data::InvokeScript(_) => 16,
+        Data::UpdateAssetInfo(_) => 17,
+        Data::InvokeExpression(_) => 18,
+    }
+}
+
+/// The address resolved by the node is preferred. Without it an alias is kept
+/// in the node's `alias:<chain>:<name>` form, and a public key hash is kept base58-encoded,
+/// since the address checksum cannot be computed here.
+fn recipient_string(
+    resolved: Option<&Vec<u8>>,
+    recipient: Option<&Recipient>,
+    chain_id: i32,
+) -> String {
+    match (resolved, recipient.and_then(|r| r.recipient.as_ref())) {
+        (Some(address), _) if !address.is_empty() => bs58::encode(address).into_string(),
+        (_, Some(RecipientKind::Alias(alias))) => {
+            format!("alias:{}:{}", chain_id as u8 as char, alias)
+        }
+        (_, Some(RecipientKind::PublicKeyHash(hash))) => bs58::encode(hash).into_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use waves_protobuf_schemas::waves::events::state_update::{DataEntryUpdate, LeaseUpdate};
+    use waves_protobuf_schemas::waves::events::transaction_metadata::{
+        InvokeScriptMetadata, MassTransferMetadata,
+    };
+    use waves_protobuf_schemas::waves::mass_transfer_transaction_data::Transfer as MassTransfer;
+    use waves_protobuf_schemas::waves::{
+        Amount, InvokeScriptTransactionData, MassTransferTransactionData, SetScriptTransactionData,
+    };
+
+    type Script = VecDeque<Result<SubscribeEvent, tonic::Status>>;
+
+    /// Every subscription gets the next scripted stream, and fails once they are used up.
+    struct MockSubscriber {
+        streams: Mutex<VecDeque<Script>>,
+        subscribed_from: Arc<Mutex<Vec<u32>>>,
+    }
+
+    struct MockStream(Script);
+
+    impl MockSubscriber {
+        fn new(streams: Vec<Vec<Result<SubscribeEvent, tonic::Status>>>) -> Self {
+            Self {
+                streams: Mutex::new(streams.into_iter().map(Script::from).collect()),
+                subscribed_from: Arc::new(Mutex::new(vec![])),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Subscribe for MockSubscriber {
+        type Stream = MockStream;
+
+        async fn subscribe(&self, from_height: u32, _to_height: Option<u32>) -> Result<MockStream> {
+            self.subscribed_from.lock().unwrap().push(from_height);
+            match self.streams.lock().unwrap().pop_front() {
+                Some(script) => Ok(MockStream(script)),
+                None => Err(tonic::Status::unavailable("node is down").into()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl UpdatesStream for MockStream {
+        async fn message(&mut self) -> Result<Option<SubscribeEvent>, tonic::Status> {
+            self.0.pop_front().transpose()
+        }
+    }
+
+    fn source(max_reconnect_attempts: Option<u32>) -> DataEntriesSourceImpl {
+        DataEntriesSourceImpl {
+            grpc_client: BlockchainUpdatesApiClient::new(
+                Endpoint::from_static("http://127.0.0.1:1").connect_lazy(),
+            ),
+            max_reconnect_attempts,
+            channel_capacity: 16,
+            batch_max_bytes: usize::MAX,
+            expected_chain_id: None,
+            on_parse_error: ParseErrorAction::Fail,
+            ingest_tx_types: None,
+            last_message_at: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    fn config(blockchain_updates_url: &str) -> Config {
+        Config {
+            blockchain_updates_url: blockchain_updates_url.to_string(),
+            blockchain_updates_tls_domain_name: None,
+            blockchain_updates_ca_cert_path: None,
+            blockchain_updates_proxy_url: None,
+            updates_per_request: 100,
+            max_wait_time_in_secs: 5,
+            max_reconnect_attempts: Some(0),
+            channel_capacity: 16,
+            batch_max_bytes: usize::MAX,
+            expected_chain_id: None,
+            on_parse_error: ParseErrorAction::Fail,
+            ingest_tx_types: None,
+            grpc_connect_timeout_secs: 1,
+            grpc_tcp_keepalive_secs: 0,
+            grpc_http2_keepalive_interval_secs: 0,
+        }
+    }
+
+    #[test]
+    fn channel_settings_are_taken_from_the_config() {
+        let config = Config {
+            grpc_connect_timeout_secs: 3,
+            grpc_tcp_keepalive_secs: 60,
+            grpc_http2_keepalive_interval_secs: 20,
+            ..config("http://10.0.0.1:6881")
+        };
+
+        assert_eq!(
+            ChannelSettings::new(&config),
+            ChannelSettings {
+                connect_timeout: Duration::from_secs(3),
+                tcp_keepalive: Some(Duration::from_secs(60)),
+                http2_keep_alive_interval: Some(Duration::from_secs(20)),
+            }
+        );
+    }
+
+    #[test]
+    fn zero_disables_the_keepalives() {
+        let settings = ChannelSettings::new(&config("http://10.0.0.1:6881"));
+
+        assert_eq!(settings.connect_timeout, Duration::from_secs(1));
+        assert_eq!(settings.tcp_keepalive, None);
+        assert_eq!(settings.http2_keep_alive_interval, None);
+    }
+
+    #[test]
+    fn tls_is_only_enabled_for_https_or_a_domain_name() {
+        assert!(tls_config(&config("http://10.0.0.1:6881"))
+            .unwrap()
+            .is_none());
+        let tls = tls_config(&config("https://node:6881")).unwrap().unwrap();
+        assert!(format!("{:?}", tls).contains("domain: None"));
+    }
+
+    #[test]
+    fn tls_domain_name_overrides_the_host_of_the_url() {
+        let config = Config {
+            blockchain_updates_tls_domain_name: Some("node.example.com".to_string()),
+            ..config("http://10.0.0.1:6881")
+        };
+
+        let tls = tls_config(&config).unwrap().unwrap();
+
+        assert!(format!("{:?}", tls).contains(r#"domain: Some("node.example.com")"#));
+    }
+
+    #[test]
+    fn missing_ca_certificate_is_an_error() {
+        let config = Config {
+            blockchain_updates_ca_cert_path: Some("/nonexistent/ca.pem".to_string()),
+            ..config("https://node:6881")
+        };
+
+        let err = tls_config(&config).unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("Cannot read CA certificate from /nonexistent/ca.pem"));
+    }
+
+    fn event(height: i32, append: Append) -> SubscribeEvent {
+        SubscribeEvent {
+            update: Some(BlockchainUpdated {
+                id: vec![height as u8],
+                height,
+                update: Some(Update::Append(append)),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn block(height: i32) -> SubscribeEvent {
+        event(
+            height,
+            Append {
+                body: Some(Body::Block(BlockAppend::default())),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn microblock(height: i32, total_block_id: u8) -> SubscribeEvent {
+        event(
+            height,
+            Append {
+                body: Some(Body::MicroBlock(MicroBlockAppend {
+                    micro_block: Some(waves_protobuf_schemas::waves::SignedMicroBlock {
+                        total_block_id: vec![total_block_id],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn block_with_transaction(height: i32, chain_id: u8) -> SubscribeEvent {
+        let transaction = SignedTransaction {
+            transaction: Some(Transaction::WavesTransaction(
+                waves_protobuf_schemas::waves::Transaction {
+                    chain_id: chain_id as i32,
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        };
+        event(
+            height,
+            Append {
+                transaction_ids: vec![vec![height as u8]],
+                body: Some(Body::Block(BlockAppend {
+                    block: Some(waves_protobuf_schemas::waves::Block {
+                        transactions: vec![transaction],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+        )
+    }
+
+    async fn receive_all(
+        mut rx: Receiver<Result<BlockchainUpdatesWithLastHeight>>,
+    ) -> (Vec<BlockchainUpdate>, Option<Error>) {
+        let mut updates = vec![];
+        while let Some(batch) = rx.recv().await {
+            match batch {
+                Ok(batch) => updates.extend(batch.updates),
+                Err(err) => return (updates, Some(err)),
+            }
+        }
+        (updates, None)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_is_counted_and_the_stream_continues() {
+        let keepalive = SubscribeEvent { update: None };
+        let subscriber = MockSubscriber::new(vec![vec![Ok(block(1)), Ok(keepalive), Ok(block(2))]]);
+        let subscribed_from = subscriber.subscribed_from.clone();
+        let keepalives = GRPC_KEEPALIVES_TOTAL.get();
+
+        let rx = source(Some(0))
+            .stream_from(subscriber, 1, Some(2), 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert!(err.is_none(), "{:?}", err);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(*subscribed_from.lock().unwrap(), [1]);
+        assert_eq!(GRPC_KEEPALIVES_TOTAL.get(), keepalives + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn end_of_the_stream_is_reconnected() {
+        let subscriber = MockSubscriber::new(vec![vec![Ok(block(1))], vec![Ok(block(2))]]);
+        let subscribed_from = subscriber.subscribed_from.clone();
+
+        let rx = source(Some(1))
+            .stream_from(subscriber, 1, Some(2), 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert!(err.is_none(), "{:?}", err);
+        assert_eq!(updates.len(), 2);
+        // the stream is resumed from the height of the last block
+        assert_eq!(*subscribed_from.lock().unwrap(), [1, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn giving_up_is_sent_to_the_receiver() {
+        let subscriber = MockSubscriber::new(vec![vec![Ok(block(1)), Ok(block(2))]]);
+        let subscribed_from = subscriber.subscribed_from.clone();
+
+        let rx = source(Some(2))
+            .stream_from(subscriber, 1, None, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert_eq!(updates.len(), 2);
+        let err = err.expect("the stream has to end with an error");
+        assert!(err
+            .to_string()
+            .contains("Giving up after 2 reconnect attempts"));
+        assert_eq!(*subscribed_from.lock().unwrap(), vec![1, 2, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_after_a_failed_stream() {
+        let subscriber = MockSubscriber::new(vec![
+            vec![Ok(block(1)), Err(tonic::Status::internal("reset"))],
+            vec![Ok(block(1)), Ok(block(2))],
+        ]);
+        let subscribed_from = subscriber.subscribed_from.clone();
+
+        let rx = source(Some(1))
+            .stream_from(subscriber, 1, Some(2), 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert!(err.is_none());
+        assert_eq!(updates.len(), 3);
+        assert_eq!(*subscribed_from.lock().unwrap(), vec![1, 1]);
+    }
+
+    #[tokio::test]
+    async fn wrong_network_is_sent_to_the_receiver() {
+        let subscriber = MockSubscriber::new(vec![vec![
+            Ok(block_with_transaction(1, b'W')),
+            Ok(block_with_transaction(2, b'T')),
+        ]]);
+        let mut source = source(None);
+        source.expected_chain_id = Some(b'W');
+
+        let rx = source
+            .stream_from(subscriber, 1, None, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert_eq!(updates.len(), 1);
+        let err = err.expect("the stream has to end with an error");
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::WrongNetwork(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn matching_network_is_streamed() {
+        let subscriber = MockSubscriber::new(vec![vec![
+            Ok(block_with_transaction(1, b'W')),
+            Ok(block_with_transaction(2, b'W')),
+        ]]);
+        let mut source = source(None);
+        source.expected_chain_id = Some(b'W');
+
+        let rx = source
+            .stream_from(subscriber, 1, Some(2), 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert!(err.is_none());
+        assert_eq!(updates.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn parse_error_is_sent_to_the_receiver() {
+        let unparsable = event(2, Append::default());
+        let subscriber = MockSubscriber::new(vec![vec![Ok(block(1)), Ok(unparsable)]]);
+
+        let rx = source(None)
+            .stream_from(subscriber, 1, None, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert_eq!(updates.len(), 1);
+        let err = err.expect("the stream has to end with an error");
+        assert!(format!("{:#}", err).contains("at height 2 cannot be parsed"));
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::InvalidMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unparsable_update_is_skipped_when_skipping() {
+        let unparsable = event(2, Append::default());
+        let subscriber =
+            MockSubscriber::new(vec![vec![Ok(block(1)), Ok(unparsable), Ok(block(3))]]);
+        let mut source = source(None);
+        source.on_parse_error = ParseErrorAction::Skip;
+        let parse_errors = PARSE_ERRORS_TOTAL.get();
+
+        let rx = source
+            .stream_from(subscriber, 1, Some(3), 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, err) = receive_all(rx).await;
+
+        assert!(err.is_none());
+        let heights = updates
+            .iter()
+            .map(|update| match update {
+                BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => {
+                    append.height
+                }
+                BlockchainUpdate::Rollback(_, height) => *height,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![1, 3]);
+        // the counter is shared with the tests running in parallel
+        assert!(PARSE_ERRORS_TOTAL.get() > parse_errors);
+    }
+
+    #[tokio::test]
+    async fn single_block_is_received_with_its_microblocks() {
+        let subscriber = MockSubscriber::new(vec![vec![
+            Ok(block(5)),
+            Ok(SubscribeEvent { update: None }),
+            Ok(microblock(5, 5)),
+        ]]);
+        let subscribed_from = subscriber.subscribed_from.clone();
+
+        let updates = receive_block(&subscriber, 5).await.unwrap();
+
+        assert!(matches!(
+            updates.as_slice(),
+            [BlockchainUpdate::Block(block), BlockchainUpdate::Microblock(microblock)]
+                if block.height == 5 && microblock.height == 5
+        ));
+        assert_eq!(*subscribed_from.lock().unwrap(), vec![5]);
+    }
+
+    #[tokio::test]
+    async fn unparsable_update_of_a_single_block_is_an_error() {
+        let subscriber = MockSubscriber::new(vec![vec![Ok(event(5, Append::default()))]]);
+
+        let err = receive_block(&subscriber, 5).await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<AppError>(),
+            Some(AppError::InvalidMessage(_))
+        ));
+    }
+
+    fn data_entry_update(address: &[u8], key: &str, value: Option<Value>) -> DataEntryUpdate {
+        DataEntryUpdate {
+            address: address.to_vec(),
+            data_entry: Some(waves_protobuf_schemas::waves::DataEntry {
+                key: key.to_string(),
+                value,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn block_with_state_updates(
+        transaction_ids: Vec<Vec<u8>>,
+        transaction_state_updates: Vec<StateUpdate>,
+    ) -> BlockchainUpdated {
+        BlockchainUpdated {
+            id: vec![1],
+            height: 1,
+            update: Some(Update::Append(Append {
+                transaction_ids,
+                transaction_state_updates,
+                body: Some(Body::Block(BlockAppend::default())),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn appended(update: BlockchainUpdated) -> BlockMicroblockAppend {
+        match BlockchainUpdate::try_from(update).unwrap() {
+            BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => append,
+            BlockchainUpdate::Rollback(..) => panic!("an append is expected"),
+        }
+    }
+
+    #[test]
+    fn every_data_entry_of_a_state_update_is_extracted() {
+        let update = block_with_state_updates(
+            vec![vec![7]],
+            vec![StateUpdate {
+                data_entries: vec![
+                    data_entry_update(&[1], "a", Some(Value::IntValue(1))),
+                    data_entry_update(&[1], "b", Some(Value::StringValue("x".to_string()))),
+                ],
+                ..Default::default()
+            }],
+        );
+
+        let data_entries = appended(update).data_entries;
+
+        let tx_id = bs58::encode([7]).into_string();
+        let address = bs58::encode([1]).into_string();
+        assert_eq!(data_entries.len(), 2);
+        assert_eq!(data_entries[0].key, "a");
+        assert_eq!(data_entries[0].value, DataEntryValue::Integer(1));
+        assert_eq!(data_entries[1].key, "b");
+        assert_eq!(
+            data_entries[1].value,
+            DataEntryValue::String("x".to_string())
+        );
+        assert!(data_entries
+            .iter()
+            .all(|de| de.address == address && de.transaction_id == tx_id));
+    }
+
+    #[test]
+    fn lease_updates_are_extracted_with_their_status() {
+        let lease_update = |lease_id: u8, status: LeaseStatus| LeaseUpdate {
+            lease_id: vec![lease_id],
+            status_after: status as i32,
+            amount: 100,
+            sender: vec![1],
+            recipient: vec![2],
+            ..Default::default()
+        };
+        let update = block_with_state_updates(
+            vec![vec![7], vec![8]],
+            vec![
+                StateUpdate {
+                    individual_leases: vec![lease_update(3, LeaseStatus::Active)],
+                    ..Default::default()
+                },
+                StateUpdate {
+                    individual_leases: vec![lease_update(4, LeaseStatus::Inactive)],
+                    ..Default::default()
+                },
+            ],
+        );
+
+        let leases = appended(update).leases;
+
+        let encoded = |bytes: &[u8]| bs58::encode(bytes).into_string();
+        assert_eq!(
+            leases
+                .iter()
+                .map(|lease| (
+                    lease.lease_id.clone(),
+                    lease.transaction_id.clone(),
+                    lease.status.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (encoded(&[3]), encoded(&[7]), LEASE_STATUS_ACTIVE),
+                (encoded(&[4]), encoded(&[8]), LEASE_STATUS_CANCELED),
+            ]
+        );
+        assert!(leases.iter().all(|lease| lease.amount == 100
+            && lease.sender == encoded(&[1])
+            && lease.recipient == encoded(&[2])));
+    }
+
+    #[test]
+    fn invoke_data_entries_keep_their_own_address() {
+        let update = block_with_state_updates(
+            vec![vec![7]],
+            vec![StateUpdate {
+                data_entries: vec![
+                    data_entry_update(&[1], "a", Some(Value::IntValue(1))),
+                    // written by a nested invoke of another dApp
+                    data_entry_update(&[2], "a", Some(Value::IntValue(2))),
+                ],
+                ..Default::default()
+            }],
+        );
+
+        let data_entries = appended(update).data_entries;
+
+        let addresses = data_entries
+            .iter()
+            .map(|de| de.address.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addresses,
+            vec![
+                bs58::encode([1]).into_string(),
+                bs58::encode([2]).into_string()
+            ]
+        );
+        let tx_id = bs58::encode([7]).into_string();
+        assert!(data_entries.iter().all(|de| de.transaction_id == tx_id));
+    }
+
+    /// A block at height 1 with the transactions, their ids are 10, 11 and so on.
+    fn block_with_transactions(
+        transactions: Vec<(Data, TransactionMetadata)>,
+    ) -> BlockchainUpdated {
+        let transaction_ids = (0..transactions.len())
+            .map(|i| vec![10 + i as u8])
+            .collect();
+        let (transactions, transactions_metadata): (Vec<_>, Vec<_>) = transactions
+            .into_iter()
+            .map(|(data, meta)| {
+                let transaction = SignedTransaction {
+                    transaction: Some(Transaction::WavesTransaction(
+                        waves_protobuf_schemas::waves::Transaction {
+                            chain_id: b'W' as i32,
+                            data: Some(data),
+                            ..Default::default()
+                        },
+                    )),
+                    ..Default::default()
+                };
+                (transaction, meta)
+            })
+            .unzip();
+        BlockchainUpdated {
+            id: vec![1],
+            height: 1,
+            update: Some(Update::Append(Append {
+                transaction_ids,
+                transactions_metadata,
+                body: Some(Body::Block(BlockAppend {
+                    block: Some(waves_protobuf_schemas::waves::Block {
+                        transactions,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn metadata(sender: &[u8], metadata: Option<Metadata>) -> TransactionMetadata {
+        TransactionMetadata {
+            sender_address: sender.to_vec(),
+            metadata,
+        }
+    }
+
+    fn invoke(payments: Vec<(&[u8], i64)>) -> (Data, TransactionMetadata) {
+        let data = Data::InvokeScript(InvokeScriptTransactionData {
+            payments: payments
+                .into_iter()
+                .map(|(asset_id, amount)| Amount {
+                    asset_id: asset_id.to_vec(),
+                    amount,
+                })
+                .collect(),
+            ..Default::default()
+        });
+        let meta = Metadata::InvokeScript(InvokeScriptMetadata {
+            d_app_address: vec![2],
+            ..Default::default()
+        });
+        (data, metadata(&[1], Some(meta)))
+    }
+
+    #[test]
+    fn every_payment_of_an_invoke_is_a_transfer_to_the_dapp() {
+        let update = block_with_transactions(vec![
+            invoke(vec![(&[5], 100)]),
+            invoke(vec![(&[], 200), (&[6], 300)]),
+        ]);
+
+        let transfers = appended(update).transfers;
+
+        let encoded = |bytes: &[u8]| bs58::encode(bytes).into_string();
+        let transfers = transfers
+            .iter()
+            .map(|t| {
+                assert_eq!(t.sender, encoded(&[1]));
+                assert_eq!(t.recipient, encoded(&[2]));
+                assert_eq!(t.attachment, None);
+                (t.transaction_id.clone(), t.asset_id.clone(), t.amount)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            transfers,
+            vec![
+                (encoded(&[10]), Some(encoded(&[5])), 100),
+                (encoded(&[11]), None, 200),
+                (encoded(&[11]), Some(encoded(&[6])), 300),
+            ]
+        );
+    }
+
+    #[test]
+    fn mass_transfer_recipients_are_addresses_or_aliases() {
+        let recipient = |recipient| Recipient {
+            recipient: Some(recipient),
+        };
+        let data = Data::MassTransfer(MassTransferTransactionData {
+            asset_id: vec![5],
+            transfers: vec![
+                MassTransfer {
+                    recipient: Some(recipient(RecipientKind::PublicKeyHash(vec![3]))),
+                    amount: 10,
+                },
+                MassTransfer {
+                    recipient: Some(recipient(RecipientKind::Alias("bob".to_string()))),
+                    amount: 20,
+                },
+                MassTransfer {
+                    recipient: Some(recipient(RecipientKind::PublicKeyHash(vec![4]))),
+                    amount: 30,
+                },
+            ],
+            attachment: vec![9],
+        });
+        // only the first recipient is resolved by the node
+        let meta = Metadata::MassTransfer(MassTransferMetadata {
+            recipients_addresses: vec![vec![30], vec![]],
+        });
+        let update = block_with_transactions(vec![(data, metadata(&[1], Some(meta)))]);
+
+        let transfers = appended(update).transfers;
+
+        let encoded = |bytes: &[u8]| bs58::encode(bytes).into_string();
+        let transfers = transfers
+            .iter()
+            .map(|t| {
+                assert_eq!(t.transaction_id, encoded(&[10]));
+                assert_eq!(t.sender, encoded(&[1]));
+                assert_eq!(t.asset_id, Some(encoded(&[5])));
+                assert_eq!(t.attachment, Some(encoded(&[9])));
+                (t.recipient.clone(), t.amount)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            transfers,
+            vec![
+                (encoded(&[30]), 10),
+                ("alias:W:bob".to_string(), 20),
+                (encoded(&[4]), 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn invoke_without_payments_has_no_transfers() {
+        let update = block_with_transactions(vec![invoke(vec![])]);
+
+        assert!(appended(update).transfers.is_empty());
+    }
+
+    #[test]
+    fn filtered_out_transaction_type_has_no_rows() {
+        let mass_transfer = Data::MassTransfer(MassTransferTransactionData {
+            transfers: vec![MassTransfer {
+                recipient: Some(Recipient {
+                    recipient: Some(RecipientKind::PublicKeyHash(vec![3])),
+                }),
+                amount: 10,
+            }],
+            ..Default::default()
+        });
+        let mut update = block_with_transactions(vec![
+            invoke(vec![(&[5], 100)]),
+            (mass_transfer, metadata(&[1], None)),
+        ]);
+        // the invoke writes a data entry too
+        if let Some(Update::Append(append)) = &mut update.update {
+            append.transaction_state_updates = vec![StateUpdate {
+                data_entries: vec![data_entry_update(&[2], "k", Some(Value::IntValue(1)))],
+                ..Default::default()
+            }];
+        }
+        let tx_types = HashSet::from([tx_type_by_name("mass_transfer").unwrap()]);
+
+        let append = appended(update);
+        assert_eq!(append.data_entries.len(), 1);
+        assert_eq!(append.transfers.len(), 2);
+
+        let append = match retain_tx_types(BlockchainUpdate::Block(append), &tx_types) {
+            BlockchainUpdate::Block(append) => append,
+            _ => panic!("a block is expected"),
+        };
+
+        let mass_transfer_id = bs58::encode([11]).into_string();
+        assert!(append.data_entries.is_empty());
+        assert_eq!(
+            append
+                .transfers
+                .iter()
+                .map(|t| &t.transaction_id)
+                .collect::<Vec<_>>(),
+            [&mass_transfer_id]
+        );
+        assert_eq!(
+            append
+                .transactions
+                .iter()
+                .map(|tx| &tx.tx_id)
+                .collect::<Vec<_>>(),
+            [&mass_transfer_id]
+        );
+    }
+
+    #[test]
+    fn set_script_records_whether_the_script_was_set_or_removed() {
+        let set_script = |script: Vec<u8>| {
+            (
+                Data::SetScript(SetScriptTransactionData { script }),
+                metadata(&[1], None),
+            )
+        };
+        let update = block_with_transactions(vec![set_script(vec![0, 6, 1]), set_script(vec![])]);
+
+        let events = appended(update).contract_events;
+
+        let encoded = |bytes: &[u8]| bs58::encode(bytes).into_string();
+        let events = events
+            .iter()
+            .map(|e| {
+                assert_eq!(e.address, encoded(&[1]));
+                assert_eq!(e.event_type, CONTRACT_EVENT_SET_SCRIPT);
+                assert_eq!(e.asset_id, None);
+                (e.transaction_id.clone(), e.has_script)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![(encoded(&[10]), Some(true)), (encoded(&[11]), Some(false))]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_receives_the_liquid_block_again() {
+        let subscriber = MockSubscriber::new(vec![
+            vec![
+                Ok(block(1)),
+                Ok(block(2)),
+                Ok(microblock(2, 21)),
+                Err(tonic::Status::internal("reset")),
+            ],
+            // the node sends the liquid block as it is now, then its next microblocks
+            vec![Ok(block(2)), Ok(microblock(2, 22))],
+        ]);
+        let subscribed_from = subscriber.subscribed_from.clone();
+
+        let rx = source(Some(1))
+            .stream_from(subscriber, 1, None, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let (updates, _) = receive_all(rx).await;
+
+        assert_eq!(subscribed_from.lock().unwrap()[..2], [1, 2]);
+        let ids = updates
+            .iter()
+            .map(|update| match update {
+                BlockchainUpdate::Block(append) | BlockchainUpdate::Microblock(append) => {
+                    append.id.clone()
+                }
+                BlockchainUpdate::Rollback(id, _) => id.clone(),
+            })
+            .collect::<Vec<_>>();
+        let id = |byte: u8| bs58::encode([byte]).into_string();
+        assert_eq!(ids, vec![id(1), id(2), id(21), id(2), id(22)]);
+    }
+}
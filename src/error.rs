@@ -1,22 +1,39 @@
 use crate::data_entries::BlockchainUpdatesWithLastHeight;
+use std::any::Any;
 use waves_protobuf_schemas::tonic;
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("LoadConfigFailed: {0}")]
     LoadConfigFailed(#[from] envy::Error),
+    #[error("InvalidConfig: {0}")]
+    InvalidConfig(String),
     #[error("GrpcTransportError: {0}")]
     GrpcTransportError(#[from] tonic::transport::Error),
     #[error("GrpcError: {0}")]
-    GrpcError(#[from] tonic::Status),
+    GrpcError(Box<tonic::Status>),
     #[error("InvalidMessage: {0}")]
     InvalidMessage(String),
+    #[error("WrongNetwork: {0}")]
+    WrongNetwork(String),
     #[error("InvalidBase58String: {0}")]
     InvalidBase58String(#[from] bs58::decode::Error),
     #[error("DbError: {0}")]
-    DbError(#[from] diesel::result::Error),
+    DbError(diesel::result::Error),
+    #[error("StatementTimeout: {0}")]
+    StatementTimeout(diesel::result::Error),
+    #[error("SchemaNotFound: {0}")]
+    SchemaNotFound(String),
     #[error("ConnectionError: {0}")]
     ConnectionError(#[from] diesel::ConnectionError),
+    #[error("ConnectionPoolTimeout: {0}")]
+    ConnectionPoolTimeout(#[from] r2d2::Error),
+    #[cfg(feature = "async-pg")]
+    #[error("AsyncDbError: {0}")]
+    AsyncDbError(#[from] tokio_postgres::Error),
+    #[cfg(feature = "async-pg")]
+    #[error("AsyncConnectionPoolError: {0}")]
+    AsyncConnectionPoolError(#[from] deadpool_postgres::PoolError),
     #[error("SendError: {0}")]
     SendError(#[from] tokio::sync::mpsc::error::SendError<BlockchainUpdatesWithLastHeight>),
     #[error("JoinError: {0}")]
@@ -25,10 +42,44 @@ pub enum AppError {
     StreamClosed(String),
     #[error("LivenessCheckFailed: {0}")]
     LivenessCheckFailed(String),
+    #[error("GenesisRollback: {0}")]
+    GenesisRollback(String),
+    #[error("DeepRollback: {0}")]
+    DeepRollback(String),
 }
 
-impl Into<String> for AppError {
-    fn into(self) -> String {
-        self.to_string()
+/// The status is boxed, it would make every `Result<_, AppError>` several times larger.
+impl From<tonic::Status> for AppError {
+    fn from(status: tonic::Status) -> Self {
+        AppError::GrpcError(Box::new(status))
+    }
+}
+
+/// A query cancelled by `statement_timeout` is told apart from other database errors.
+impl From<diesel::result::Error> for AppError {
+    fn from(err: diesel::result::Error) -> Self {
+        match &err {
+            diesel::result::Error::DatabaseError(_, info)
+                if info.message().contains("statement timeout") =>
+            {
+                AppError::StatementTimeout(err)
+            }
+            _ => AppError::DbError(err),
+        }
+    }
+}
+
+/// Message of a caught panic, it is only known when the panic was raised with a string.
+pub fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+impl From<AppError> for String {
+    fn from(err: AppError) -> Self {
+        err.to_string()
     }
 }
@@ -0,0 +1,119 @@
+//! Log macros of the service, which write either through `wavesexchange_log`
+//! or as JSON lines, depending on `LOG_FORMAT`.
+//!
+//! Key-value fields after a `;`, e.g. `info!("Batch was written"; "height" => height)`,
+//! are separate keys of a JSON line, and are left out of the plain text,
+//! so a plain line stays the same as before.
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use slog::{o, Drain, Logger};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines of `wavesexchange_log`.
+    #[default]
+    Plain,
+    /// A JSON object per line with `ts`, `level`, `msg` and the fields of the line.
+    Json,
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+static JSON_LOGGER: Lazy<Logger> = Lazy::new(|| json_logger_to(std::io::stdout()));
+
+fn json_logger_to<W: Write + Send + 'static>(out: W) -> Logger {
+    let drain = slog_json::Json::new(out).add_default_keys().build();
+    let drain = slog::LevelFilter::new(Mutex::new(drain).fuse(), slog::Level::Info).fuse();
+    Logger::root(drain, o!())
+}
+
+/// Set the format once on start, before anything is logged.
+/// Lines logged before that are plain.
+pub fn init(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&LogFormat::Json)
+}
+
+pub fn json_logger() -> &'static Logger {
+    &JSON_LOGGER
+}
+
+macro_rules! log {
+    ($level:ident, $fmt:literal $(, $arg:expr)* ; $($key:literal => $value:expr),+ $(,)?) => {
+        if $crate::logging::is_json() {
+            slog::$level!($crate::logging::json_logger(), $fmt $(, $arg)* ; $($key => $value),+)
+        } else {
+            wavesexchange_log::$level!($fmt $(, $arg)*)
+        }
+    };
+    ($level:ident, $($arg:tt)+) => {
+        if $crate::logging::is_json() {
+            slog::$level!($crate::logging::json_logger(), $($arg)+)
+        } else {
+            wavesexchange_log::$level!($($arg)+)
+        }
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::logging::log!(info, $($arg)+) };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::logging::log!(warn, $($arg)+) };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::logging::log!(error, $($arg)+) };
+}
+
+// the builtin `warn` attribute makes a plain `use warn` ambiguous
+pub(crate) use {log, log_error as error, log_info as info, log_warn as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fields_are_keys_of_the_json_line() {
+        let buffer = Buffer::default();
+        let logger = json_logger_to(buffer.clone());
+        slog::info!(logger, "Updates were processed"; "height" => 100, "batch_size" => 3);
+        slog::debug!(logger, "filtered out");
+
+        let out = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(r#""msg":"Updates were processed""#));
+        assert!(lines[0].contains(r#""level":"INFO""#));
+        assert!(lines[0].contains(r#""height":100"#));
+        assert!(lines[0].contains(r#""batch_size":3"#));
+    }
+
+    #[test]
+    fn format_is_plain_by_default() {
+        assert_eq!(LogFormat::default(), LogFormat::Plain);
+        assert!(!is_json());
+    }
+}
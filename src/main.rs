@@ -1,66 +1,346 @@
 #[macro_use]
 extern crate diesel;
 
+pub mod api;
 pub mod config;
 pub mod data_entries;
 pub mod db;
 pub mod error;
+pub mod logging;
+pub mod metrics;
+pub mod readiness;
+pub mod reporting;
 pub mod schema;
 
 use anyhow::Result;
 use data_entries::{repo::PgDataEntriesRepo, updates::DataEntriesSourceImpl};
-use std::sync::Arc;
-use std::time::Duration;
+use logging::{error, info};
+use std::future;
+use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::select;
-use wavesexchange_liveness::channel;
-use wavesexchange_log::{error, info};
-use wavesexchange_warp::MetricsWarpBuilder;
-
-const POLL_INTERVAL_SECS: u64 = 60;
-const MAX_BLOCK_AGE: Duration = Duration::from_secs(300);
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use wavesexchange_warp::{endpoints::Readiness, MetricsWarpBuilder};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = config::load()?;
+    let config = config::load(std::env::vars().collect())?;
+    logging::init(config.log_format);
+    let _reporting = reporting::init(config.sentry_dsn.as_deref());
+
+    if let Some(height) = debug_block_height()? {
+        let updates_src = DataEntriesSourceImpl::new(&config.data_entries)?;
+        return data_entries::debug::dump_block(updates_src, height).await;
+    }
 
+    data_entries::repo::set_table_prefix(&config.postgres.table_prefix);
     let pool = db::pool(&config.postgres)?;
+    if config.postgres.run_migrations_on_startup {
+        db::run_migrations(&pool, config.postgres.schema.as_deref())?;
+    }
+    db::check_schema(&pool)?;
+    let pool_state = tokio::spawn(db::report_pool_state(pool.clone()));
     let data_entries_repo = Arc::new(PgDataEntriesRepo::new(pool));
 
-    let updates_repo =
-        DataEntriesSourceImpl::new(&config.data_entries.blockchain_updates_url).await?;
+    let secondary_repo = match &config.secondary_database_url {
+        Some(url) => {
+            info!("Batches are also written to the secondary database");
+            let pool = db::pool_with_url(&config.postgres, url.clone())?;
+            if config.postgres.run_migrations_on_startup {
+                // the schema of the secondary is set in its url, if any
+                db::run_migrations(&pool, None)?;
+            }
+            db::check_schema(&pool)?;
+            Some(Arc::new(PgDataEntriesRepo::new(pool)))
+        }
+        None => None,
+    };
+
+    // entries written before `value_type` existed get it in the background,
+    // an interrupted backfill is done again on the next start
+    if !config.dry_run {
+        let repos = std::iter::once(data_entries_repo.clone()).chain(secondary_repo.clone());
+        for repo in repos {
+            tokio::spawn(async move {
+                if let Err(err) = data_entries::backfill::backfill_value_types(repo).await {
+                    error!("Value type backfill failed: {:#}", err);
+                }
+            });
+        }
+    }
+
+    let updates_repo = DataEntriesSourceImpl::new(&config.data_entries)?;
+    let last_message_at = updates_repo.last_message_at();
+
+    let shutdown = CancellationToken::new();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutdown signal was received");
+            shutdown.cancel();
+        }
+    });
+
+    let heartbeat = Arc::new(AtomicI64::new(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64),
+    ));
+
+    let syncing = Arc::new(AtomicBool::new(false));
+
+    // written entries are published for the api, a subscriber which falls behind
+    // by this many batches is disconnected
+    let (changes, _) = broadcast::channel(256);
+
+    let consuming = Arc::new(AtomicBool::new(true));
+
+    let settings = data_entries::daemon::Settings {
+        updates_per_request: config.data_entries.updates_per_request,
+        max_wait_time_in_secs: config.data_entries.max_wait_time_in_secs,
+        start_rollback_depth: config.start_rollback_depth,
+        min_rollback_height: config.min_rollback_height,
+        track_last_writes: config.track_last_writes,
+        implicit_rollbacks: config.implicit_rollbacks,
+        use_copy_insert: config.use_copy_insert,
+        insert_chunk_size: data_entries::repo::insert_chunk_size(config.insert_chunk_size),
+        close_superseded_by_chunk_size: config.close_superseded_by_chunk_size,
+        commit_every_n_batches: config.commit_every_n_batches,
+        commit_max_wait_secs: config.commit_max_wait_secs,
+        live_max_blocks_per_minute: config.live_max_blocks_per_minute,
+        collapse_identical_writes: config.collapse_identical_writes,
+        collapse_within_transaction: config.collapse_within_transaction,
+        decode_binary_value_fragments: config.decode_binary_value_fragments,
+        skip_empty_string_values: config.skip_empty_string_values,
+        address_filter: config.address_filter.iter().cloned().collect(),
+        key_prefix_filter: config.key_prefix_filter.clone(),
+        metrics_address_allowlist: config.metrics_address_allowlist.iter().cloned().collect(),
+        sync_to_height: config.sync_to_height,
+        reset_to_height: config.reset_to_height,
+        on_deep_rollback: config.on_deep_rollback,
+        on_parse_error: config.data_entries.on_parse_error,
+        dry_run: config.dry_run,
+        reconcile_uid_seq_on_startup: config.reconcile_uid_seq_on_startup,
+        secondary_failures_are_fatal: config.secondary_failures_are_fatal,
+    };
+
+    let signals = data_entries::daemon::Signals {
+        heartbeat: heartbeat.clone(),
+        consuming: consuming.clone(),
+        syncing: syncing.clone(),
+        changes: changes.clone(),
+        shutdown: shutdown.clone(),
+    };
 
     info!("Starting state-consumer");
-    let consumer = data_entries::daemon::start(
-        updates_repo,
+    // in degraded mode a failed consumer only marks itself as down and is restarted,
+    // so the api keeps serving reads from the database
+    let degraded_mode = config.degraded_mode;
+    let consumer = {
+        let data_entries_repo = data_entries_repo.clone();
+        async move {
+            if degraded_mode {
+                data_entries::daemon::start_degraded(
+                    updates_repo,
+                    data_entries_repo,
+                    secondary_repo,
+                    settings,
+                    signals,
+                )
+                .await
+            } else {
+                data_entries::daemon::start(
+                    updates_repo,
+                    data_entries_repo,
+                    secondary_repo,
+                    settings,
+                    signals,
+                )
+                .await
+            }
+        }
+    };
+
+    let mut readiness_channel = readiness::channel(
         data_entries_repo.clone(),
-        config.data_entries.updates_per_request,
-        config.data_entries.max_wait_time_in_secs,
-        config.start_rollback_depth,
+        config.readiness_poll_interval_secs,
+        config.readiness_poll_jitter,
+        Duration::from_secs(config.readiness_max_block_age_secs),
+        last_message_at.clone(),
+        Duration::from_secs(config.readiness_max_stream_idle_secs),
     );
 
-    let db_url = config.postgres.database_url();
-    let readiness_channel = channel(db_url, POLL_INTERVAL_SECS, MAX_BLOCK_AGE, None);
+    // readiness is tapped on its way to the metrics server, so the api can report it too
+    let ready = Arc::new(Mutex::new(None));
+    let (readiness_tx, readiness_rx) = mpsc::unbounded_channel();
+    tokio::spawn({
+        let ready = ready.clone();
+        async move {
+            while let Some(readiness) = readiness_channel.recv().await {
+                *ready.lock().unwrap() = Some(matches!(readiness, Readiness::Ready));
+                if readiness_tx.send(readiness).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let api = tokio::spawn(api::start(
+        config.port,
+        api::Probes {
+            consuming,
+            ready,
+            syncing,
+            heartbeat,
+            liveness_timeout: Duration::from_secs(config.liveness_timeout_secs),
+            last_message_at,
+            max_stream_idle: Duration::from_secs(config.readiness_max_stream_idle_secs),
+        },
+        changes,
+        data_entries_repo.clone(),
+    ));
+
+    let pruning = {
+        let data_entries_repo = data_entries_repo.clone();
+        // pruning deletes blocks, so it is not started in dry run mode
+        let blocks_retention_depth = config.blocks_retention_depth.filter(|_| !config.dry_run);
+        tokio::spawn(async move {
+            match blocks_retention_depth {
+                Some(depth) => data_entries::pruning::start(data_entries_repo, depth).await,
+                None => future::pending().await,
+            }
+        })
+    };
+
+    let history_compaction = {
+        let data_entries_repo = data_entries_repo.clone();
+        let history_retention_blocks = config.history_retention_blocks.filter(|_| !config.dry_run);
+        tokio::spawn(async move {
+            match history_retention_blocks {
+                Some(retention_blocks) => {
+                    data_entries::pruning::start_history_compaction(
+                        data_entries_repo,
+                        retention_blocks,
+                    )
+                    .await
+                }
+                None => future::pending().await,
+            }
+        })
+    };
 
     let metrics = tokio::spawn(async move {
-        MetricsWarpBuilder::new()
+        let builder = MetricsWarpBuilder::new()
             .with_metrics_port(config.metrics_port)
-            .with_readiness_channel(readiness_channel)
-            .run_async()
-            .await
+            .with_readiness_channel(readiness_rx)
+            .with_metric(&*metrics::LAST_HEIGHT)
+            .with_metric(&*metrics::SYNC_PROGRESS_RATIO)
+            .with_metric(&*metrics::CONSUMER_MODE)
+            .with_metric(&*metrics::NEXT_UPDATE_UID)
+            .with_metric(&*metrics::DATA_ENTRIES_TOTAL)
+            .with_metric(&*metrics::BLOCKS_TOTAL)
+            .with_metric(&*metrics::MICROBLOCKS_TOTAL)
+            .with_metric(&*metrics::ROLLBACKS_TOTAL)
+            .with_metric(&*metrics::PARSE_ERRORS_TOTAL)
+            .with_metric(&*metrics::GRPC_KEEPALIVES_TOTAL)
+            .with_metric(&*metrics::BATCH_RECEIVE_SECONDS)
+            .with_metric(&*metrics::BATCH_PROCESS_SECONDS)
+            .with_metric(&*metrics::PG_POOL_CONNECTIONS_IN_USE)
+            .with_metric(&*metrics::PG_POOL_CONNECTIONS_IDLE);
+
+        // without an allowlist the per-address counter would stay empty
+        let builder = if config.metrics_address_allowlist.is_empty() {
+            builder
+        } else {
+            builder.with_metric(&*metrics::DATA_ENTRIES_BY_ADDRESS_TOTAL)
+        };
+
+        builder.run_async().await
     });
 
     select! {
-        Err(err) = consumer => {
-            error!("{}", err);
-            panic!("{}", err);
-        },
-        result = metrics => {
+        result = consumer => {
             if let Err(err) = result {
-                error!("Metrics failed: {:?}", err);
+                // the alternate form includes the causes, e.g. why an update cannot be parsed
+                error!("{:#}", err);
+                reporting::capture_fatal(&err);
+                return Err(err);
             } else {
-                error!("Metrics stopped");
+                info!("Consumer stopped");
             }
+        },
+        result = metrics => {
+            let err = anyhow::anyhow!("Metrics stopped: {:?}", result);
+            error!("{}", err);
+            reporting::capture_fatal(&err);
+            return Err(err);
+        },
+        // failed runs are retried, so pruning only ends by a panic, and the service
+        // is not left running without it. The error is returned rather than panicked
+        // with, the panic integration would report it a second time
+        result = pruning => {
+            let err = anyhow::anyhow!("Pruning stopped: {:?}", result);
+            error!("{}", err);
+            reporting::capture_fatal(&err);
+            return Err(err);
+        },
+        result = history_compaction => {
+            let err = anyhow::anyhow!("History compaction stopped: {:?}", result);
+            error!("{}", err);
+            reporting::capture_fatal(&err);
+            return Err(err);
+        },
+        // like pruning, these never end on their own, and the service is not
+        // left running without them
+        result = pool_state => {
+            let err = anyhow::anyhow!("Pool state reporting stopped: {:?}", result);
+            error!("{}", err);
+            reporting::capture_fatal(&err);
+            return Err(err);
+        },
+        result = api => {
+            let err = anyhow::anyhow!("API stopped: {:?}", result);
+            error!("{}", err);
+            reporting::capture_fatal(&err);
+            return Err(err);
         }
     };
     Ok(())
 }
+
+/// Parse `debug-block --height N` from the command line arguments.
+fn debug_block_height() -> Result<Option<u32>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["debug-block", "--height", height] => {
+            Ok(Some(height.parse().map_err(|_| {
+                anyhow::anyhow!("Invalid height for debug-block: {}", height)
+            })?))
+        }
+        [] => Ok(None),
+        _ => Err(anyhow::anyhow!(
+            "Unknown arguments: {}. Usage: service [debug-block --height N]",
+            args.join(" ")
+        )),
+    }
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Cannot listen for SIGTERM");
+
+    select! {
+        _ = sigterm.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
@@ -0,0 +1,139 @@
+use once_cell::sync::Lazy;
+use prometheus::{Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts};
+
+/// From 10ms to a minute.
+const BATCH_SECONDS_BUCKETS: &[f64] = &[
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+pub static LAST_HEIGHT: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new(
+        "state_consumer_last_height",
+        "Height of the last blockchain update handled by the consumer",
+    )
+    .unwrap()
+});
+
+/// Only set while syncing up to `sync_to_height`.
+pub static SYNC_PROGRESS_RATIO: Lazy<Gauge> = Lazy::new(|| {
+    Gauge::new(
+        "state_consumer_sync_progress_ratio",
+        "Part of the blocks up to the target height which is handled, from 0 to 1",
+    )
+    .unwrap()
+});
+
+/// 1 while the consumer catches up with the chain, 0 while it tails it.
+pub static CONSUMER_MODE: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new(
+        "state_consumer_mode",
+        "Whether the consumer is syncing (1) or live (0)",
+    )
+    .unwrap()
+});
+
+pub static NEXT_UPDATE_UID: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new(
+        "state_consumer_next_update_uid",
+        "Uid of the next data entry to be inserted",
+    )
+    .unwrap()
+});
+
+pub static DATA_ENTRIES_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new(
+        "state_consumer_data_entries_total",
+        "Number of inserted data entries",
+    )
+    .unwrap()
+});
+
+/// Only the addresses of the allowlist are labeled, so the cardinality stays bounded.
+pub static DATA_ENTRIES_BY_ADDRESS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "state_consumer_data_entries_by_address_total",
+            "Number of inserted data entries of the allowlisted addresses",
+        ),
+        &["address"],
+    )
+    .unwrap()
+});
+
+pub static BLOCKS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new("state_consumer_blocks_total", "Number of appended blocks").unwrap()
+});
+
+pub static MICROBLOCKS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new(
+        "state_consumer_microblocks_total",
+        "Number of appended microblocks",
+    )
+    .unwrap()
+});
+
+/// Labeled by `kind`, `microblock` for rollbacks within the current key block, `block` otherwise.
+pub static ROLLBACKS_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "state_consumer_rollbacks_total",
+            "Number of handled rollbacks",
+        ),
+        &["kind"],
+    )
+    .unwrap()
+});
+
+pub static PARSE_ERRORS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new(
+        "state_consumer_parse_errors_total",
+        "Number of skipped updates which could not be parsed",
+    )
+    .unwrap()
+});
+
+pub static GRPC_KEEPALIVES_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+    IntCounter::new(
+        "state_consumer_grpc_keepalives_total",
+        "Number of received events without an update",
+    )
+    .unwrap()
+});
+
+pub static BATCH_RECEIVE_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::with_opts(
+        HistogramOpts::new(
+            "state_consumer_batch_receive_seconds",
+            "Time spent waiting for a batch of blockchain updates",
+        )
+        .buckets(BATCH_SECONDS_BUCKETS.to_vec()),
+    )
+    .unwrap()
+});
+
+pub static BATCH_PROCESS_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::with_opts(
+        HistogramOpts::new(
+            "state_consumer_batch_process_seconds",
+            "Time spent writing a batch of blockchain updates",
+        )
+        .buckets(BATCH_SECONDS_BUCKETS.to_vec()),
+    )
+    .unwrap()
+});
+
+pub static PG_POOL_CONNECTIONS_IN_USE: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new(
+        "state_consumer_pg_pool_connections_in_use",
+        "Number of checked out connections of the database pool",
+    )
+    .unwrap()
+});
+
+pub static PG_POOL_CONNECTIONS_IDLE: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new(
+        "state_consumer_pg_pool_connections_idle",
+        "Number of idle connections of the database pool",
+    )
+    .unwrap()
+});
@@ -0,0 +1,115 @@
+use crate::logging::warn;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use wavesexchange_warp::endpoints::Readiness;
+
+use crate::data_entries::{DataEntriesRepo, DataEntriesRepoOperations};
+
+/// Poll the last block timestamp through the shared pool and report the service as dead
+/// when it has not changed for `max_block_age` or cannot be read.
+///
+/// A pooled connection is only held for the duration of a single poll.
+///
+/// The service is also dead when no message has arrived from the updates stream
+/// for `max_stream_idle`, since a stalled stream keeps its socket open.
+///
+/// The first poll happens right away, the next ones are spread by `poll_jitter`
+/// of the interval, so replicas started together do not poll in lockstep.
+pub fn channel<U>(
+    repo: Arc<U>,
+    poll_interval_secs: u64,
+    poll_jitter: f64,
+    max_block_age: Duration,
+    last_message_at: Arc<AtomicI64>,
+    max_stream_idle: Duration,
+) -> UnboundedReceiver<Readiness>
+where
+    U: DataEntriesRepo + Send + Sync + 'static,
+{
+    let (tx, rx) = unbounded_channel();
+
+    tokio::spawn(async move {
+        let poll_interval = Duration::from_secs(poll_interval_secs);
+        let mut last_change: Option<(Option<i64>, Instant)> = None;
+
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64);
+            let stream_idle = Duration::from_millis(
+                (now - last_message_at.load(Ordering::Relaxed)).max(0) as u64,
+            );
+
+            let readiness = match repo.execute(|mut ops| ops.get_last_block_timestamp()) {
+                Ok(timestamp) => {
+                    let changed_at = match last_change {
+                        Some((last_timestamp, changed_at)) if last_timestamp == timestamp => {
+                            changed_at
+                        }
+                        _ => Instant::now(),
+                    };
+                    last_change = Some((timestamp, changed_at));
+
+                    if changed_at.elapsed() > max_block_age {
+                        Readiness::Dead
+                    } else if stream_idle > max_stream_idle {
+                        warn!(
+                            "No messages were received from the stream for {:?}",
+                            stream_idle
+                        );
+                        Readiness::Dead
+                    } else {
+                        Readiness::Ready
+                    }
+                }
+                Err(err) => {
+                    warn!("Readiness check failed: {:?}", err);
+                    Readiness::Dead
+                }
+            };
+
+            if tx.send(readiness).is_err() {
+                break;
+            }
+
+            tokio::time::sleep(jittered(poll_interval, poll_jitter)).await;
+        }
+    });
+
+    rx
+}
+
+/// A random duration within `interval` ± `jitter` of it.
+fn jittered(interval: Duration, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    interval.mul_f64(1.0 + jitter * (fastrand::f64() * 2.0 - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_interval_stays_within_the_bounds() {
+        let interval = Duration::from_secs(10);
+
+        for _ in 0..1000 {
+            let delay = jittered(interval, 0.2);
+            assert!(delay >= Duration::from_secs(8), "{:?}", delay);
+            assert!(delay <= Duration::from_secs(12), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn jitter_is_clamped() {
+        let interval = Duration::from_secs(10);
+
+        assert_eq!(jittered(interval, 0.0), interval);
+        assert_eq!(jittered(interval, -1.0), interval);
+        for _ in 0..1000 {
+            assert!(jittered(interval, 5.0) <= Duration::from_secs(20));
+        }
+    }
+}
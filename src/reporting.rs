@@ -0,0 +1,81 @@
+#[cfg(not(feature = "sentry"))]
+use crate::logging::warn;
+use anyhow::Error;
+
+/// Keeps the error reporting client alive, it is flushed when dropped.
+#[cfg(feature = "sentry")]
+pub struct Guard(#[allow(dead_code)] Option<sentry::ClientInitGuard>);
+
+#[cfg(not(feature = "sentry"))]
+pub struct Guard;
+
+/// Start reporting fatal errors to Sentry, nothing is initialized without a dsn.
+#[cfg(feature = "sentry")]
+pub fn init(dsn: Option<&str>) -> Guard {
+    Guard(dsn.map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                ..Default::default()
+            },
+        ))
+    }))
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init(dsn: Option<&str>) -> Guard {
+    if dsn.is_some() {
+        warn!("SENTRY_DSN is ignored, the service is built without the sentry feature");
+    }
+    Guard
+}
+
+/// Report an error the service is about to stop on, with its whole context chain.
+/// Without an initialized client this does nothing.
+#[cfg(feature = "sentry")]
+pub fn capture_fatal(err: &Error) {
+    sentry_anyhow::capture_anyhow(err);
+    // the service stops right after, so the event is sent before that
+    if let Some(client) = sentry::Hub::current().client() {
+        client.flush(Some(std::time::Duration::from_secs(2)));
+    }
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn capture_fatal(_err: &Error) {}
+
+#[cfg(all(test, feature = "sentry"))]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn fatal_error_is_captured_with_its_context() {
+        let err = anyhow!("connection refused").context("Cannot subscribe to updates");
+
+        let events = sentry::test::with_captured_events(|| capture_fatal(&err));
+
+        assert_eq!(events.len(), 1);
+        let values: Vec<_> = events[0]
+            .exception
+            .values
+            .iter()
+            .filter_map(|exception| exception.value.clone())
+            .collect();
+        assert!(values.iter().any(|v| v == "Cannot subscribe to updates"));
+        assert!(values.iter().any(|v| v == "connection refused"));
+    }
+
+    #[test]
+    fn nothing_is_captured_without_a_dsn() {
+        let hub = std::sync::Arc::new(sentry::Hub::new_from_top(sentry::Hub::current()));
+        sentry::Hub::run(hub.clone(), || {
+            let _guard = init(None);
+            capture_fatal(&anyhow!("connection refused"));
+        });
+
+        assert!(hub.client().is_none());
+        assert!(hub.last_event_id().is_none());
+    }
+}
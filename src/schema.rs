@@ -25,6 +25,7 @@ table! {
         value_bool -> Nullable<Bool>,
         value_integer -> Nullable<BigInt>,
         value_string -> Nullable<Varchar>,
+        value_type -> Nullable<Varchar>,
         fragment_0_integer -> Nullable<BigInt>,
         fragment_0_string -> Nullable<Varchar>,
         fragment_1_integer -> Nullable<BigInt>,
@@ -82,5 +83,75 @@ table! {
         key -> Text,
         block_timestamp -> Nullable<Timestamp>,
     }
-}  
+}
+
+table! {
+    leases (uid) {
+        uid -> BigInt,
+        block_uid -> BigInt,
+        transaction_id -> Varchar,
+        lease_id -> Varchar,
+        sender -> Varchar,
+        recipient -> Varchar,
+        amount -> BigInt,
+        status -> Varchar,
+    }
+}
+
+table! {
+    data_entries_last_writes (address, key) {
+        address -> Varchar,
+        key -> Varchar,
+        height -> Int4,
+        block_timestamp -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    transfers (uid) {
+        uid -> BigInt,
+        block_uid -> BigInt,
+        height -> Int4,
+        transaction_id -> Varchar,
+        sender -> Varchar,
+        recipient -> Varchar,
+        asset_id -> Nullable<Varchar>,
+        amount -> BigInt,
+        attachment -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    contract_events (uid) {
+        uid -> BigInt,
+        block_uid -> BigInt,
+        height -> Int4,
+        transaction_id -> Varchar,
+        address -> Varchar,
+        event_type -> Varchar,
+        has_script -> Nullable<Bool>,
+        asset_id -> Nullable<Varchar>,
+        asset_name -> Nullable<Varchar>,
+        asset_description -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    transactions (tx_id) {
+        tx_id -> Varchar,
+        block_uid -> BigInt,
+        height -> Int4,
+        tx_type -> Int2,
+    }
+}
+
+table! {
+    consumer_state (id) {
+        id -> Int2,
+        last_height -> Int4,
+        last_uid -> Nullable<BigInt>,
+        applied_reset_to_height -> Nullable<Int4>,
+    }
+}
 
+allow_tables_to_appear_in_same_query!(data_entries, data_entries_history_keys);